@@ -1,16 +1,28 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 pub mod render_markdown;
 
-use render_markdown::render_md;
+use render_markdown::{md_to_html, render_md};
 use unchained::{
+    cache::CachePolicy,
+    compression::Compression,
     error::Error,
-    router::{HTTPVerb::*, Request, Response, ResponseContent, Route},
+    export::{export_site, ExportOptions},
+    feed::{Feed, FeedItem},
+    router::{HTTPVerb::*, Request, Response, ResponseContent, Route, Router, Urls},
     server::Server,
+    sitemap::Sitemap,
+    static_files::StaticFiles,
     templates::{
         context::ContextTree,
+        i18n::Translations,
         render::{load_template, RenderOptions},
     },
+    tls::{CertificateFiles, TlsOptions},
 };
 
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -50,27 +62,27 @@ fn handle_error(e: &Error) -> String {
         Error::LoadFile(s) => s.to_string(),
         Error::ParseTemplate(s) => s.to_string(),
         Error::Connection(s) => s.to_string(),
+        Error::RenderLimit(s) => s.to_string(),
     }
 }
 
-fn load_tmpl_and_handle_error(path: &str, context: Option<HashMap<String, ContextTree>>) -> String {
-    match load_template(path, context, &RenderOptions::empty()) {
+fn load_tmpl_and_handle_error(
+    path: &str,
+    context: Option<HashMap<String, ContextTree>>,
+    options: &RenderOptions,
+) -> String {
+    match load_template(path, context, options) {
         Ok(template) => template.to_string(),
         Err(e) => handle_error(&e),
     }
 }
 
-fn create_skill(
-    id: &str,
-    name: &str,
-    description: &str,
-    score: isize,
-    image_path: &str,
-) -> ContextTree {
+/// Skill with its description translated from `skills.<id>.description`
+fn create_skill(id: &str, name: &str, score: isize, image_path: &str) -> ContextTree {
     [
         ("id", ContextTree::from(id)),
         ("name", name.into()),
-        ("description", description.into()),
+        ("description", format!("skills.{id}.description").into()),
         ("score", score.into()),
         ("image", image_path.into()),
         ("percentage", (score * 100 / 5).into()),
@@ -78,10 +90,10 @@ fn create_skill(
     .into()
 }
 
+/// Experience with its title and description translated from
+/// `experience.<id>.title` and `experience.<id>.description`
 fn create_experience(
     id: &str,
-    title: &str,
-    description: &str,
     image_path: &str,
     date_start: &str,
     date_end: &str,
@@ -91,8 +103,8 @@ fn create_experience(
 ) -> ContextTree {
     [
         ("id", ContextTree::from(id)),
-        ("title", title.into()),
-        ("description", description.into()),
+        ("title", format!("experience.{id}.title").into()),
+        ("description", format!("experience.{id}.description").into()),
         ("image", image_path.into()),
         ("date_start", date_start.into()),
         ("date_end", date_end.into()),
@@ -103,20 +115,216 @@ fn create_experience(
     .into()
 }
 
-fn create_course(course_id: &str, title: &str, image_path: &str) -> ContextTree {
+fn create_course(course_id: &str, title: &str, image_path: &str, href: &str) -> ContextTree {
     [
         ("course_id", course_id),
         ("title", title),
         ("image", image_path),
+        ("href", href),
     ]
     .into()
 }
 
+/// Write the site to `out_dir` for hosting it statically.
+/// Run with `cargo run -- export <out_dir>`
+fn export(routes: &[Route], urls: &Urls, out_dir: &Path) {
+    let course_paths = LOCALES
+        .iter()
+        .flat_map(|(locale, _)| {
+            course_ids()
+                .into_iter()
+                .map(|course_id| course_url(urls, locale, &course_id))
+        })
+        .collect();
+    let mut options = ExportOptions::new(out_dir);
+    options.paths = course_paths;
+    options.not_found = Some("/404".to_string());
+    match export_site(routes, &options) {
+        Ok(written) => println!("Exported {} files to {}", written.len(), out_dir.display()),
+        Err(e) => {
+            eprintln!("Could not export site: {}", handle_error(&e));
+            std::process::exit(1);
+        }
+    }
+}
+
+const SITE_URL: &str = "https://gutzkow.com";
+
+/// Ids of the courses with a markdown page
+fn course_ids() -> Vec<String> {
+    let mut ids = std::fs::read_dir("templates/markdown/courses")
+        .expect("Could not read courses")
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.strip_suffix(".md").map(String::from)
+        })
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+fn sitemap_route(routes: &[Route]) -> Route {
+    let mut sitemap = Sitemap::new(SITE_URL);
+    sitemap.exclude("/feed.xml");
+    for (_, prefix) in LOCALES {
+        sitemap.expand(&format!("{prefix}{COURSE_DETAIL}"), || {
+            course_ids()
+                .into_iter()
+                .map(|id| HashMap::from([("courseid".to_string(), id)]))
+                .collect()
+        });
+    }
+    sitemap.route(routes).expect("Could not create sitemap")
+}
+
+/// Feed with a course page for each course in `course_pages`
+fn course_feed(course_pages: &ContextTree, urls: &Urls) -> Feed {
+    let mut feed = Feed::new(
+        "Carl Gützkow | Courses",
+        SITE_URL,
+        "Information about some of the courses I have taken.",
+    );
+    feed.feed_url = Some(format!("{}/feed.xml", SITE_URL));
+    let ContextTree::Array(pages) = course_pages else {
+        return feed;
+    };
+    for page in pages.iter() {
+        let get = |key: &str| page.get_from_branch(key).map(|c| c.to_string());
+        let (Some(course_id), Some(title)) = (get("course_id"), get("title")) else {
+            continue;
+        };
+        let path = format!("templates/markdown/courses/{}.md", course_id);
+        feed.items.push(FeedItem {
+            title: format!("{} {}", course_id, title),
+            link: format!("{}{}", SITE_URL, course_url(urls, "en", &course_id)),
+            content: md_to_html(&path).ok(),
+            ..FeedItem::default()
+        });
+    }
+    feed
+}
+
+/// Locales with the path prefix of their pages
+const LOCALES: [(&str, &str); 2] = [("en", ""), ("nl", "/nl")];
+
+/// Route names and paths of the pages rendered for each locale
+const PAGES: [(&str, &str); 4] = [
+    ("landing", "/"),
+    ("skills", "/skills"),
+    ("experience", "/experience"),
+    ("courses", "/courses"),
+];
+
+const COURSE_DETAIL: &str = "/courses/:courseid<[A-Za-z0-9-]+>";
+
+/// Course ids and titles of the course list
+const COURSES: [(&str, &str); 4] = [
+    ("CS4515", "3D Computer Graphics and Animation"),
+    ("CS4505", "Software Architecture"),
+    ("DSAIT4005", "Machine and Deep Learning"),
+    ("CS4510", "Formal Reasoning about Software"),
+];
+
+const HTML: &str = "text/html; charset=utf-8";
+
+/// Route name of a page in a locale, e.g. `nl.skills`
+fn page_name(locale: &str, page: &str) -> String {
+    match locale {
+        "en" => page.to_string(),
+        _ => format!("{locale}.{page}"),
+    }
+}
+
+/// Paths of the named routes.
+/// Created before the routes, because the pages are rendered with links to each other.
+fn site_urls() -> Urls {
+    let mut urls = Urls::new();
+    for (locale, prefix) in LOCALES {
+        for (page, path) in PAGES {
+            urls.add(&page_name(locale, page), &format!("{prefix}{path}"))
+                .expect("Duplicate route name");
+        }
+        let course_detail = page_name(locale, "course_detail");
+        urls.add(&course_detail, &format!("{prefix}{COURSE_DETAIL}"))
+            .expect("Duplicate route name");
+    }
+    urls
+}
+
+fn url(urls: &Urls, name: &str) -> String {
+    urls.url_for(name, &HashMap::new())
+        .expect("Could not create link")
+}
+
+fn course_url(urls: &Urls, locale: &str, course_id: &str) -> String {
+    let params = HashMap::from([("courseid".to_string(), course_id.to_string())]);
+    urls.url_for(&page_name(locale, "course_detail"), &params)
+        .expect("Could not create link")
+}
+
+fn course_pages(locale: &str, urls: &Urls) -> ContextTree {
+    COURSES
+        .map(|(course_id, title)| {
+            create_course(course_id, title, "", &course_url(urls, locale, course_id))
+        })
+        .into()
+}
+
+fn page_links(locale: &str, urls: &Urls) -> ContextTree {
+    [
+        ("landing", "#about", "nav.about"),
+        ("experience", "", "nav.experience"),
+        ("skills", "", "nav.skills"),
+        ("courses", "", "nav.courses"),
+    ]
+    .map(|(page, anchor, label)| {
+        let href = url(urls, &page_name(locale, page));
+        [
+            ("href", format!("{href}{anchor}")),
+            ("label", label.to_string()),
+        ]
+    })
+    .into()
+}
+
 fn folder_access(path: &str) -> Route {
     Route::new(GET, path, ResponseContent::FolderAccess)
 }
 
+fn static_files(path: &str, root: &str) -> Route {
+    Route::new(GET, path, ResponseContent::Static(StaticFiles::new(root)))
+}
+
+/// Directory with the templates, locales and assets of the site.
+/// Uses `SITE_DIR` if it is set, otherwise the current directory.
+fn site_dir() -> PathBuf {
+    let dir = match std::env::var_os("SITE_DIR") {
+        Some(dir) => std::path::absolute(dir).expect("Invalid SITE_DIR"),
+        None => std::env::current_dir().expect("Could not read the current directory"),
+    };
+    if !dir.join("templates").is_dir() {
+        eprintln!(
+            "No site found in {}. Run from the eportfolio directory or set SITE_DIR.",
+            dir.display()
+        );
+        std::process::exit(1);
+    }
+    dir
+}
+
 fn main() {
+    // Resolved before the working directory changes to the site directory
+    let export_dir = match std::env::args().collect::<Vec<_>>().as_slice() {
+        [_, command, out_dir] if command == "export" => {
+            Some(std::path::absolute(out_dir).expect("Invalid export directory"))
+        }
+        _ => None,
+    };
+    let site_dir = site_dir();
+    // Assets, locales and markdown are read relative to the site
+    std::env::set_current_dir(&site_dir).expect("Could not open the site directory");
+
     let mut context_base = HashMap::new();
 
     let current_year: isize = current_year().try_into().unwrap();
@@ -125,99 +333,164 @@ fn main() {
         "brainmade".to_string(),
         [
             ("link", "https://brainmade.org"),
-            (
-                "tooltip",
-                "Yes, I did watch the youtube video from No Boilerplate...",
-            ),
+            ("tooltip", "footer.brainmade"),
         ]
         .into(),
     );
 
-    let page_links = [
-        [("href", "/#about"), ("label", "About me")],
-        [("href", "/experience"), ("label", "Experience")],
-        [("href", "/skills"), ("label", "Skills")],
-        [("href", "/courses"), ("label", "Courses")],
-    ];
-    context_base.insert("page_links".to_string(), page_links.into());
-
     let mut context_landing = context_base.clone();
     let mut context_skills = context_base.clone();
     let mut context_experience = context_base.clone();
-    let mut context_courses = context_base.clone();
+    let context_courses = context_base.clone();
 
     context_landing.insert(
         "carl_images".to_string(),
         [
             [
                 ("path", "cafe-midi.webp"),
-                ("alt", "Me and goat at Cafe du Midi"),
+                ("alt", "landing.images.cafe_midi"),
+            ],
+            [
+                ("path", "abtswoudse-bos.webp"),
+                ("alt", "landing.images.delft"),
+            ],
+            [
+                ("path", "zeeland-beach.webp"),
+                ("alt", "landing.images.zeeland"),
             ],
-            [("path", "abtswoudse-bos.webp"), ("alt", "Park in Delft")],
-            [("path", "zeeland-beach.webp"), ("alt", "Beach in Zeeland")],
         ]
         .into(),
     );
     context_landing.insert("age".into(), (current_age() as isize).into());
 
-    context_skills.insert("skills".to_string(), [
-        create_skill("django", "Django", "I have used Django in various projects in Index, Hackerspace, and Ei Solutions. It has been my Go To framework for backend developement because of its simplicity, scalability, and effeciency.", 5, "django.webp"),
-        create_skill("java", "Java", "Java was used extensively at NTNU and was often required for school projects with Spring Boot, Maven and more.", 5, "java.webp"),
-        create_skill("docker", "Docker", "I have used docker in several projects with Index, Hackerspace, and Ei Solutions. It has been very useful in both development and deployment. Yet, it is incredibly complex to master. My skill with Docker centers around using Compose and creating Dockerfiles.", 4, "docker.webp"),
-        create_skill("python", "Python", "Python was my my first introduction to programming with a clear goal in mind. It has been used in my projects with Django. It was also used in the CS50-AI course with Tensorflow.", 4, "python.webp"),
-        create_skill("typescript", "Type Script", "TypeScript has been used in all Front end projects. In high school, I was introduced to JavaScript, but after learning TypeScript, I have understood that I can never go back", 4, "typescript.webp"),
-        create_skill("next", "Next", "This SSR framework was used to build my bachelor thesis product in addition to the landing page for Ei Solutions.", 3, "next.webp"),
-        create_skill("postgis", "PostGIS", "This Postgres extension has been used to store and query spatial data in Ei Solutions. Postgres with PostGIS is by far the best relational geospatial database.", 3, "postgis.webp"),
-        create_skill("qgis", "QGIS", "In Ei Solutions, I used QGIS to pre-process datasets before storing them in a PostGIS database.", 3, "qgis.webp"),
-        create_skill("rust", "Rust", "I enjoy writing in this language and have created some fun projects with it, including this website.", 2, "rust.webp"),
-    ].into());
-
-    context_experience.insert("experience_list".to_string(), [
-        create_experience("unchained", "Unchained router and templater", "Wanted to remove as much JavaScript from the website as possible so created a router and html template library that this website is created with.", "unchained.webp", "Mar 2024", "", "https://gutzkow.com", "https://github.com/cjgutz/unchained", vec!["Rust", "Docker"]),
-        create_experience("hackerspace-deputy", "Deputy Commander - Hackerspace NTNU", "The deputy commander, together with the lead and the financial manager, had the responsibility to administer the organization. We made equipment available for students, organized events like the general assembly, and created an environment for students to learn. The last few months, I took the lead role as the previous leader stepped down.", "hackerspace.webp", "Mar 2023", "Mar 2024", "https://hackerspace-ntnu.no", "https://github.com/hackerspace-ntnu", vec![]),
-        create_experience("telescope", "Telescope", "We started as two developers and a project manager who created the first prototype for an application. The application helps property developers manage a risk and vulnerability assessment of physical climate risk and biodiversity. I had responsibility for the Back-end and managed analysis using large amounts of geodata in a postGIS database. In the summer of 2023, with more teamates, we rewrote the entire application with a higher priority on user experience. This tought me a great deal about creating applications that scale and easily adapts to changing circumstances and customers.", "telescope.webp", "Jun 22", "", "https://telescope.eco", "", vec!["Django", "PostGIS", "QGIS", "Docker"]),
-        create_experience("hackerspace-devops", "DevOps Member and Team Leader - Hackerspace NTNU", "For a year I managed the DevOps team at Hackerspace NTNU. I got into the role after one semester. I had responsibility for the development lifecycle, server infrastructure and the team's well-being. When I became deputy leader of the organization, I continued working with DevOps.", "hackerspace.webp", "Aug 2021", "Mar 2024", "https://hackerspace-ntnu.no", "https://github.com/hackerspace-ntnu", vec!["Django", "Docker"]),
-        create_experience("tihlde-index", "Programmer with TIHLDE Index", "Worked as a Back-end developer for index.", "tihlde.webp", "Aug 2021", "Jun 2022", "https://tihlde.org", "https://github.com/tihlde/lepton", vec!["Django", "Docker"]),
-    ].into());
-
-    context_courses.insert(
-        "course_pages".to_string(),
+    context_skills.insert(
+        "skills".to_string(),
+        [
+            create_skill("django", "Django", 5, "django.webp"),
+            create_skill("java", "Java", 5, "java.webp"),
+            create_skill("docker", "Docker", 4, "docker.webp"),
+            create_skill("python", "Python", 4, "python.webp"),
+            create_skill("typescript", "Type Script", 4, "typescript.webp"),
+            create_skill("next", "Next", 3, "next.webp"),
+            create_skill("postgis", "PostGIS", 3, "postgis.webp"),
+            create_skill("qgis", "QGIS", 3, "qgis.webp"),
+            create_skill("rust", "Rust", 2, "rust.webp"),
+        ]
+        .into(),
+    );
+
+    context_experience.insert(
+        "experience_list".to_string(),
         [
-            create_course("CS4515", "3D Computer Graphics and Animation", ""),
-            create_course("CS4505", "Software Architecture", ""),
-            create_course("DSAIT4005", "Machine and Deep Learning", ""),
-            create_course("CS4510", "Formal Reasoning about Software", ""),
+            create_experience(
+                "unchained",
+                "unchained.webp",
+                "Mar 2024",
+                "",
+                "https://gutzkow.com",
+                "https://github.com/cjgutz/unchained",
+                vec!["Rust", "Docker"],
+            ),
+            create_experience(
+                "hackerspace-deputy",
+                "hackerspace.webp",
+                "Mar 2023",
+                "Mar 2024",
+                "https://hackerspace-ntnu.no",
+                "https://github.com/hackerspace-ntnu",
+                vec![],
+            ),
+            create_experience(
+                "telescope",
+                "telescope.webp",
+                "Jun 22",
+                "",
+                "https://telescope.eco",
+                "",
+                vec!["Django", "PostGIS", "QGIS", "Docker"],
+            ),
+            create_experience(
+                "hackerspace-devops",
+                "hackerspace.webp",
+                "Aug 2021",
+                "Mar 2024",
+                "https://hackerspace-ntnu.no",
+                "https://github.com/hackerspace-ntnu",
+                vec!["Django", "Docker"],
+            ),
+            create_experience(
+                "tihlde-index",
+                "tihlde.webp",
+                "Aug 2021",
+                "Jun 2022",
+                "https://tihlde.org",
+                "https://github.com/tihlde/lepton",
+                vec!["Django", "Docker"],
+            ),
         ]
         .into(),
     );
 
+    let translations = Translations::load_dir("locales", "en").expect("Could not load locales");
+    let translations = Arc::new(translations);
+    let urls = Arc::new(site_urls());
+    let options_for = |locale: &str| {
+        let mut options = RenderOptions::with_root(&site_dir);
+        options.translations = Some(translations.clone());
+        options.locale = Some(locale.to_string());
+        options.urls = Some(urls.clone());
+        options
+    };
+    let with_locale = |context: &HashMap<String, ContextTree>, locale: &str| {
+        let mut context = context.clone();
+        context.insert("locale".to_string(), locale.into());
+        context.insert("page_links".to_string(), page_links(locale, &urls));
+        context.insert("course_pages".to_string(), course_pages(locale, &urls));
+        context.insert(
+            "landing_href".to_string(),
+            url(&urls, &page_name(locale, "landing")).into(),
+        );
+        context.insert(
+            "courses_href".to_string(),
+            url(&urls, &page_name(locale, "courses")).into(),
+        );
+        context
+    };
+
     let start = std::time::Instant::now();
-    let landing =
-        load_tmpl_and_handle_error("templates/landing.html", Some(context_landing.clone()));
-    let skills = load_tmpl_and_handle_error("templates/skills.html", Some(context_skills));
-    let experience =
-        load_tmpl_and_handle_error("templates/experience.html", Some(context_experience));
-    let courses = load_tmpl_and_handle_error("templates/course-list.html", Some(context_courses));
-    let page_404 = load_tmpl_and_handle_error("templates/404.html", Some(context_landing));
-    let duration = start.elapsed();
-    println!("Finished rendering after {} s", duration.as_secs_f64());
+    let mut site = Router::new();
+    let mut pages_404 = HashMap::new();
+    for (locale, prefix) in LOCALES {
+        let options = options_for(locale);
+        let render = |path: &str, context: &HashMap<String, ContextTree>| {
+            load_tmpl_and_handle_error(path, Some(with_locale(context, locale)), &options)
+        };
+        let mut pages = Router::new();
+        for (page, path) in PAGES {
+            let (template, context) = match page {
+                "landing" => ("templates/landing.html", &context_landing),
+                "skills" => ("templates/skills.html", &context_skills),
+                "experience" => ("templates/experience.html", &context_experience),
+                _ => ("templates/course-list.html", &context_courses),
+            };
+            let content = ResponseContent::Str(render(template, context));
+            pages.named_route(&page_name(locale, page), Route::new(GET, path, content));
+        }
+        let page_404 = render("templates/404.html", &context_base);
+        pages_404.insert(locale, page_404.clone());
 
-    let routes = vec![
-        Route::new(GET, "/", ResponseContent::Str(landing)),
-        Route::new(GET, "/skills", ResponseContent::Str(skills)),
-        Route::new(GET, "/experience", ResponseContent::Str(experience)),
-        Route::new(GET, "/courses", ResponseContent::Str(courses)),
-        Route::new(
-            GET,
-            "courses/:courseid",
-            ResponseContent::FromRequest({
-                let page_404 = page_404.clone();
-                Box::new(move |req: Request| {
+        let context = with_locale(&context_base, locale);
+        pages.named_route(
+            &page_name(locale, "course_detail"),
+            Route::new(
+                GET,
+                COURSE_DETAIL,
+                ResponseContent::FromRequest(Box::new(move |req: Request| {
                     let md = if let Some(courseid) = req.path_params.get("courseid") {
-                        let mut ctx = context_base.clone();
+                        let mut ctx = context.clone();
                         let path = format!("templates/markdown/courses/{}.md", courseid);
                         ctx.insert("course_md_path".to_string(), path.into());
-                        render_md("templates/course-detail.html", Some(ctx)).ok()
+                        render_md("templates/course-detail.html", Some(ctx), &options).ok()
                     } else {
                         None
                     };
@@ -226,25 +499,65 @@ fn main() {
                         Some(md.unwrap_or(page_404.clone())),
                         if is_some { 200 } else { 404 },
                     )
-                })
-            }),
-        ),
-        folder_access("/images/*"),
-        folder_access("/Poppins/Poppins-Regular.ttf"),
-        folder_access("favicon.ico"),
-        folder_access("cv.pdf"),
-        folder_access("robots.txt"),
-        folder_access("templates/css/*"),
-        Route::new(
-            GET,
-            "/*",
-            ResponseContent::FromRequest(Box::new(move |_req: Request| {
-                Response::new(Some(page_404.clone()), 404)
-            })),
-        ),
-    ];
+                })),
+            ),
+        );
+        pages.add_default_header("Content-Type", HTML);
+        site.nest(prefix, pages);
+    }
+    let duration = start.elapsed();
+    println!("Finished rendering after {} s", duration.as_secs_f64());
+
+    site.route(course_feed(&course_pages("en", &urls), &urls).rss_route("/feed.xml"));
+
+    let mut css = Router::new();
+    css.route(static_files("/css/*", "templates/css"));
+    let mut assets = Router::new();
+    assets
+        .route(static_files("/images/*", "images"))
+        .route(folder_access("/Poppins/Poppins-Regular.ttf"))
+        .route(folder_access("favicon.ico"))
+        .route(folder_access("cv.pdf"))
+        .route(folder_access("robots.txt"))
+        .nest("/templates", css)
+        .set_cache_policy(CachePolicy::MaxAge(3600));
+    // Pages are validated with their ETag, so changes show up right away
+    site.merge(assets).set_cache_policy(CachePolicy::Revalidate);
+    let compression = Compression::new();
+    compression.precompress(&site.routes);
+    site.add_middleware(compression);
+
+    let mut routes = site.into_routes();
+    routes.push(sitemap_route(&routes));
+    routes.push(Route::new(
+        GET,
+        "/*",
+        ResponseContent::FromRequest(Box::new(move |req: Request| {
+            // Missing pages are in the locale of the path or the one the browser prefers
+            let available = LOCALES.map(|(locale, _)| locale);
+            let locale = req.locale(&available).unwrap_or_else(|| "en".to_string());
+            let mut response = Response::new(Some(pages_404[locale.as_str()].clone()), 404);
+            response
+                .add_header("Content-Type", HTML)
+                .add_header("Vary", "Accept-Language");
+            response
+        })),
+    ));
+    if let Some(out_dir) = export_dir {
+        export(&routes, &urls, &out_dir);
+        return;
+    }
+
     let mut server = Server::new(routes);
-    server.add_default_header("Cache-Control", "max-age=300");
-    server.add_default_header("Accept-Encoding", "gzip, compress, br");
-    server.listen();
+    // Serve HTTPS directly when certificates are given, instead of behind a proxy
+    if let (Ok(certificate), Ok(key)) = (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        server
+            .set_address("0.0.0.0:443")
+            .set_tls(TlsOptions::new(CertificateFiles::new(certificate, key)))
+            .redirect_http_from("0.0.0.0:80");
+    }
+    if let Err(e) = server.listen() {
+        eprintln!("Could not start the server. {:?}", e);
+        std::process::exit(1);
+    }
 }
@@ -1,8 +1,6 @@
-use std::collections::HashMap;
-
 use markdown::Options;
 use unchained::{
-    error::WebResult,
+    error::{Error, WebResult},
     templates::{
         context::{ContextMap, ContextTree::*, Primitive::*},
         operations::{unwrap_n_params, TemplateOperation},
@@ -10,7 +8,11 @@ use unchained::{
     },
 };
 
-pub fn render_md(path: &str, context: Option<ContextMap>) -> WebResult<String> {
+pub fn render_md(
+    path: &str,
+    context: Option<ContextMap>,
+    options: &RenderOptions,
+) -> WebResult<String> {
     let closure = {
         |call, ctx, opts| {
             let file_path = unwrap_n_params::<1>(&call.parameters)?[0];
@@ -26,11 +28,15 @@ pub fn render_md(path: &str, context: Option<ContextMap>) -> WebResult<String> {
         }
     } as TemplateOperation;
 
-    load_template(
-        path,
-        context.clone(),
-        &RenderOptions {
-            custom_operations: HashMap::from([("md", closure)]),
-        },
-    )
+    let mut options = options.clone();
+    options.custom_operations.insert("md", closure);
+    load_template(path, context.clone(), &options)
+}
+
+/// Convert a markdown file to html
+pub fn md_to_html(path: &str) -> WebResult<String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|_| Error::LoadFile(format!("Could not read file {}", path)))?;
+    markdown::to_html_with_options(&content, &Options::gfm())
+        .map_err(|e| Error::ParseTemplate(e.to_string()))
 }
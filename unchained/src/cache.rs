@@ -0,0 +1,384 @@
+//! Cache validation with `ETag` and `Last-Modified`, and `Cache-Control` policies.
+//!
+//! Responses of [RouteTree::respond](crate::route_tree::RouteTree::respond) with an `ETag`
+//! or `Last-Modified` header are checked against the conditional headers of the request,
+//! and answered with `304 Not Modified` or `412 Precondition Failed`.
+//! `Str` and `Bytes` routes get a strong `ETag` from their content
+//! and static files a weak `ETag` and `Last-Modified` from their metadata.
+//!
+//! The conditions are only applied to `GET` and `HEAD` requests, after the route responded.
+//! Handlers of other methods change state before their response is known,
+//! so they have to check the conditions first with [Preconditions::status].
+//!
+//! ```
+//! use unchained_web::{
+//!     cache::CachePolicy,
+//!     router::{check_routes, HTTPVerb::*, Request, ResponseContent, Route},
+//! };
+//!
+//! let mut route = Route::new(GET, "/", ResponseContent::Str("Home".to_string()));
+//! route.set_cache_policy(CachePolicy::Revalidate);
+//! let routes = vec![route];
+//!
+//! let response = check_routes(&routes, Request::new("GET", "/"));
+//! let etag = response.headers["ETag"].clone();
+//! assert_eq!(response.headers["Cache-Control"], "no-cache");
+//!
+//! let mut request = Request::new("GET", "/");
+//! request.headers.insert("if-none-match".to_string(), etag);
+//! assert_eq!(check_routes(&routes, request).status_code, 304);
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    router::{HTTPVerb, Request, Response},
+    templates::i18n::Date,
+};
+
+/// How long clients and proxies can use a response without asking the server again
+#[derive(Clone, Debug, PartialEq)]
+pub enum CachePolicy {
+    /// `no-store`: never stored
+    NoStore,
+    /// `no-cache`: stored, but validated with the server before it is used
+    Revalidate,
+    /// `max-age=N`: used for N seconds without validation
+    MaxAge(u64),
+    /// `max-age=N, immutable` for files that never change, e.g. with a hash in their name
+    Immutable(u64),
+    /// Any `Cache-Control` value
+    Header(String),
+}
+
+impl CachePolicy {
+    /// Value of the `Cache-Control` header
+    pub fn header(&self) -> String {
+        match self {
+            CachePolicy::NoStore => "no-store".to_string(),
+            CachePolicy::Revalidate => "no-cache".to_string(),
+            CachePolicy::MaxAge(seconds) => format!("max-age={}", seconds),
+            CachePolicy::Immutable(seconds) => format!("max-age={}, immutable", seconds),
+            CachePolicy::Header(value) => value.clone(),
+        }
+    }
+}
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+/// Month abbreviations of HTTP dates, also used in access logs
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Seconds since the unix epoch
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Hours, minutes and seconds of the day of a unix timestamp
+pub(crate) fn time_of_day(seconds: u64) -> (u64, u64, u64) {
+    let of_day = seconds % (60 * 60 * 24);
+    (of_day / 3600, of_day / 60 % 60, of_day % 60)
+}
+
+/// Date in the format of HTTP headers, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(seconds: u64) -> String {
+    let date = Date::from_timestamp(seconds as i64);
+    let (hours, minutes, seconds) = time_of_day(seconds);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[date.weekday() as usize],
+        date.day,
+        MONTHS[date.month as usize - 1],
+        date.year,
+        hours,
+        minutes,
+        seconds
+    )
+}
+
+/// Seconds since the unix epoch of a date in the format of [http_date]
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let (_, rest) = value.trim().split_once(", ")?;
+    let parts = rest.split(' ').collect::<Vec<_>>();
+    let [day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    let date = Date {
+        year: year.parse().ok()?,
+        month,
+        day: day.parse().ok()?,
+    };
+    let mut time = time.split(':').map(|p| p.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if date.day == 0 || date.day > 31 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let days = u64::try_from(date.days_since_epoch()).ok()?;
+    Some(days * 60 * 60 * 24 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Strong `ETag` from the content, using the FNV-1a hash
+pub fn content_etag(content: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in content {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{:016x}\"", hash)
+}
+
+/// Whether two entity tags are equal.
+/// The weak comparison ignores the `W/` prefix.
+fn etags_match(a: &str, b: &str, weak: bool) -> bool {
+    match (a.strip_prefix("W/"), b.strip_prefix("W/")) {
+        (None, None) => a == b,
+        (a_weak, b_weak) => weak && a_weak.unwrap_or(a) == b_weak.unwrap_or(b),
+    }
+}
+
+/// Whether an `If-Match` or `If-None-Match` header matches the `ETag`
+fn list_matches(header: &str, etag: Option<&str>, weak: bool) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    let Some(etag) = etag else {
+        return false;
+    };
+    header
+        .split(',')
+        .any(|tag| etags_match(tag.trim(), etag, weak))
+}
+
+/// Conditional headers of a request
+#[derive(Clone, Debug, Default)]
+pub struct Preconditions {
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<u64>,
+    pub if_unmodified_since: Option<u64>,
+    /// Whether the method is `GET` or `HEAD`, which are answered with `304`
+    pub get_or_head: bool,
+}
+
+impl Preconditions {
+    pub fn from_request(request: &Request) -> Preconditions {
+        let header = |name: &str| request.header(name).map(String::from);
+        let date = |name: &str| request.header(name).and_then(parse_http_date);
+        Preconditions {
+            if_match: header("If-Match"),
+            if_none_match: header("If-None-Match"),
+            if_modified_since: date("If-Modified-Since"),
+            if_unmodified_since: date("If-Unmodified-Since"),
+            get_or_head: matches!(request.method(), HTTPVerb::GET | HTTPVerb::HEAD),
+        }
+    }
+
+    /// `304` or `412` if the conditions fail for a resource with the `ETag` and modification time.
+    /// Evaluated in the order of RFC 9110, section 13.2.2.
+    pub fn status(&self, etag: Option<&str>, last_modified: Option<u64>) -> Option<u32> {
+        if let Some(if_match) = &self.if_match {
+            if !list_matches(if_match, etag, false) {
+                return Some(412);
+            }
+        } else if let (Some(since), Some(modified)) = (self.if_unmodified_since, last_modified) {
+            if modified > since {
+                return Some(412);
+            }
+        }
+        if let Some(if_none_match) = &self.if_none_match {
+            if list_matches(if_none_match, etag, true) {
+                return Some(if self.get_or_head { 304 } else { 412 });
+            }
+        } else if let (true, Some(since), Some(modified)) =
+            (self.get_or_head, self.if_modified_since, last_modified)
+        {
+            if modified <= since {
+                return Some(304);
+            }
+        }
+        None
+    }
+
+    /// Replace a successful response to `GET` or `HEAD` with `304` or `412`
+    /// if the conditions fail for its `ETag` and `Last-Modified` headers.
+    /// Responses to other methods are left as they are, because their handler already ran.
+    pub fn apply(&self, response: &mut Response) {
+        if !self.get_or_head || !(200..300).contains(&response.status_code) {
+            return;
+        }
+        let header = |name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.clone())
+        };
+        let etag = header("ETag");
+        let last_modified = header("Last-Modified").and_then(|d| parse_http_date(&d));
+        let Some(status) = self.status(etag.as_deref(), last_modified) else {
+            return;
+        };
+        response.status_code = status;
+        response.bytes = None;
+        response.stream = None;
+        // A 304 keeps the validators and caching headers, but describes no content
+        response.headers.retain(|k, _| {
+            ![
+                "Content-Length",
+                "Content-Type",
+                "Transfer-Encoding",
+                "Content-Range",
+            ]
+            .iter()
+            .any(|h| k.eq_ignore_ascii_case(h))
+        });
+        if status == 412 {
+            response.headers.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditions(headers: &[(&str, &str)]) -> Preconditions {
+        let mut request = Request::new("GET", "/");
+        for (name, value) in headers {
+            request
+                .headers
+                .insert(name.to_ascii_lowercase(), value.to_string());
+        }
+        Preconditions::from_request(&request)
+    }
+
+    #[test]
+    fn test_http_dates() {
+        assert_eq!(http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(parse_http_date(&http_date(0)), Some(0));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let etag = Some("\"abc\"");
+        let weak = Some("W/\"abc\"");
+        let status =
+            |headers: &[(&str, &str)], etag, modified| conditions(headers).status(etag, modified);
+        assert_eq!(status(&[], etag, Some(10)), None);
+        assert_eq!(
+            status(&[("If-None-Match", "\"x\", \"abc\"")], etag, None),
+            Some(304)
+        );
+        assert_eq!(
+            status(&[("If-None-Match", "\"abc\"")], weak, None),
+            Some(304)
+        );
+        assert_eq!(status(&[("If-None-Match", "\"x\"")], etag, None), None);
+        assert_eq!(status(&[("If-Match", "\"abc\"")], etag, None), None);
+        // If-Match uses the strong comparison
+        assert_eq!(status(&[("If-Match", "\"abc\"")], weak, None), Some(412));
+        assert_eq!(status(&[("If-Match", "*")], None, None), None);
+
+        let date = http_date(100);
+        assert_eq!(
+            status(&[("If-Modified-Since", &date)], None, Some(100)),
+            Some(304)
+        );
+        assert_eq!(
+            status(&[("If-Modified-Since", &date)], None, Some(101)),
+            None
+        );
+        assert_eq!(
+            status(&[("If-Unmodified-Since", &date)], None, Some(101)),
+            Some(412)
+        );
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            status(
+                &[("If-None-Match", "\"x\""), ("If-Modified-Since", &date)],
+                etag,
+                Some(50)
+            ),
+            None
+        );
+        let mut put = conditions(&[("If-None-Match", "*")]);
+        put.get_or_head = false;
+        assert_eq!(put.status(etag, None), Some(412));
+    }
+
+    #[test]
+    fn test_unsafe_methods_check_first() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        use crate::router::{check_routes, HTTPVerb, ResponseContent, Route};
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let counter = writes.clone();
+        let routes = vec![Route::new(
+            HTTPVerb::PUT,
+            "/doc",
+            ResponseContent::FromRequest(Box::new(move |request| {
+                let etag = format!("\"{}\"", counter.load(Ordering::SeqCst));
+                if let Some(status) =
+                    Preconditions::from_request(&request).status(Some(&etag), None)
+                {
+                    return Response::new(None, status);
+                }
+                let version = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut response = Response::new_200("saved".to_string());
+                response.add_header("ETag", &format!("\"{}\"", version));
+                response
+            })),
+        )];
+        let put = |if_match: &str| {
+            let mut request = Request::new("PUT", "/doc");
+            request
+                .headers
+                .insert("if-match".to_string(), if_match.to_string());
+            check_routes(&routes, request).status_code
+        };
+        assert_eq!(put("\"1\""), 412);
+        assert_eq!(writes.load(Ordering::SeqCst), 0);
+        // The response of a write that happened is not replaced, although its ETag differs
+        assert_eq!(put("\"0\""), 200);
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_apply_to_response() {
+        let mut response = Response::new_200("content".to_string());
+        response
+            .add_header("ETag", "\"abc\"")
+            .add_header("Content-Type", "text/plain")
+            .add_header("Cache-Control", "no-cache");
+        conditions(&[("If-None-Match", "\"abc\"")]).apply(&mut response);
+        assert_eq!(response.status_code, 304);
+        assert!(response.bytes.is_none());
+        assert!(response.headers.contains_key("ETag"));
+        assert!(response.headers.contains_key("Cache-Control"));
+        assert!(!response.headers.contains_key("Content-Type"));
+
+        let mut put = conditions(&[("If-Match", "\"other\"")]);
+        put.get_or_head = false;
+        let mut response = Response::new_200("content".to_string());
+        response.add_header("ETag", "\"abc\"");
+        put.apply(&mut response);
+        assert_eq!(response.status_code, 200);
+
+        assert_ne!(content_etag(b"a"), content_etag(b"b"));
+        assert_eq!(content_etag(b""), "\"cbf29ce484222325\"");
+    }
+}
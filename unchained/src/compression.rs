@@ -0,0 +1,510 @@
+//! Compression of responses with `br`, `gzip` and `deflate`, negotiated with `Accept-Encoding`.
+//!
+//! The encoders need the `brotli` and `gzip` cargo features, the latter also enabling `deflate`.
+//! Without them no dependencies are added and [Compression] leaves responses as they are,
+//! while [StaticFiles](crate::static_files::StaticFiles) still serves precompressed
+//! `.br` and `.gz` files next to the requested file.
+//!
+//! Add [Compression] to routes or a [Router](crate::router::Router) rather than the server,
+//! so conditional requests are checked against the `ETag` of the compressed response.
+//!
+//! ```
+//! use unchained_web::{
+//!     compression::Compression,
+//!     router::{HTTPVerb::*, ResponseContent, Route, Router},
+//! };
+//!
+//! let mut site = Router::new();
+//! site.route(Route::new(GET, "/", ResponseContent::Str("<h1>Home</h1>".to_string())))
+//!     .add_default_header("Content-Type", "text/html; charset=utf-8");
+//! let compression = Compression::new();
+//! // Compress the pages once instead of on every request
+//! compression.precompress(&site.routes);
+//! site.add_middleware(compression);
+//! ```
+
+use std::{collections::HashMap, io::Write, sync::Mutex};
+
+use crate::{
+    error::{Error, WebResult},
+    middleware::Middleware,
+    router::{Request, Response, ResponseContent, Route},
+};
+
+/// Compressed bodies kept by [Compression] before new ones are no longer cached
+const MAX_CACHED: usize = 512;
+
+/// Quality of [Compression::precompress], the best of brotli
+const BEST_QUALITY: u32 = 11;
+
+/// Content codings of `Accept-Encoding` and `Content-Encoding`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// All encodings, in order of preference when a client accepts several
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    /// Name in `Accept-Encoding` and `Content-Encoding` headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Extension of precompressed files, e.g. `site.css.gz`
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    /// Encodings enabled with cargo features, in order of preference
+    pub fn available() -> Vec<Encoding> {
+        Encoding::ALL
+            .into_iter()
+            .filter(|encoding| match encoding {
+                Encoding::Brotli => cfg!(feature = "brotli"),
+                Encoding::Gzip | Encoding::Deflate => cfg!(feature = "gzip"),
+            })
+            .collect()
+    }
+}
+
+/// The encoding of `available` the `Accept-Encoding` header prefers.
+/// Ties are broken by the order of `available`. `None` means the body is sent as it is.
+/// ```
+/// use unchained_web::compression::{negotiate, Encoding::*};
+///
+/// assert_eq!(negotiate("gzip, deflate, br", &[Brotli, Gzip]), Some(Brotli));
+/// assert_eq!(negotiate("br;q=0.5, gzip", &[Brotli, Gzip]), Some(Gzip));
+/// assert_eq!(negotiate("*;q=0", &[Brotli, Gzip]), None);
+/// ```
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut weights = HashMap::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(|p| p.trim());
+        let coding = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let weight = parts
+            .find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        if !coding.is_empty() {
+            weights.insert(coding, weight);
+        }
+    }
+    let weight = |encoding: &Encoding| {
+        let alias = (*encoding == Encoding::Gzip).then_some("x-gzip");
+        [Some(encoding.name()), alias, Some("*")]
+            .into_iter()
+            .flatten()
+            .find_map(|name| weights.get(name))
+            .copied()
+            .unwrap_or(0.0)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in available {
+        let weight = weight(encoding);
+        if weight > 0.0 && best.is_none_or(|(_, best)| weight > best) {
+            best = Some((*encoding, weight));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether responses of the media type get smaller when compressed.
+/// Images, audio, video and archives are compressed already.
+pub fn compressible(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "image/bmp"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Write the body written by `write_body` to `writer`, compressed with the encoding.
+/// The quality goes from 0 to 11, of which gzip and deflate use at most 9.
+/// Fails if the cargo feature of the encoding is not enabled.
+pub fn encode_to<F>(
+    encoding: Encoding,
+    quality: u32,
+    writer: &mut dyn Write,
+    write_body: F,
+) -> WebResult<()>
+where
+    F: FnOnce(&mut dyn Write) -> WebResult<()>,
+{
+    match encoding {
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(writer, 4096, quality.min(11), 22);
+            write_body(&mut encoder)?;
+            encoder.flush()?;
+            encoder.into_inner();
+            Ok(())
+        }
+        #[cfg(feature = "gzip")]
+        Encoding::Gzip => {
+            let level = flate2::Compression::new(quality.min(9));
+            let mut encoder = flate2::write::GzEncoder::new(writer, level);
+            write_body(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        #[cfg(feature = "gzip")]
+        Encoding::Deflate => {
+            let level = flate2::Compression::new(quality.min(9));
+            let mut encoder = flate2::write::ZlibEncoder::new(writer, level);
+            write_body(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            let _ = (quality, writer, write_body);
+            Err(Error::InvalidParams(format!(
+                "Encoding {} is not enabled with a cargo feature",
+                encoding.name()
+            )))
+        }
+    }
+}
+
+/// Compress the bytes with the encoding, see [encode_to]
+pub fn compress(encoding: Encoding, quality: u32, bytes: &[u8]) -> WebResult<Vec<u8>> {
+    let mut compressed = Vec::new();
+    encode_to(encoding, quality, &mut compressed, |writer| {
+        Ok(writer.write_all(bytes)?)
+    })?;
+    Ok(compressed)
+}
+
+/// `ETag` of the compressed representation, e.g. `"abc-gzip"` for `"abc"`
+fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    match etag.strip_suffix('"') {
+        Some(tag) => format!("{}-{}\"", tag, encoding.name()),
+        None => etag.to_string(),
+    }
+}
+
+/// Add `Accept-Encoding` to the `Vary` header of a response
+pub(crate) fn vary_on_encoding(response: &mut Response) {
+    let key = response
+        .headers
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case("Vary"))
+        .cloned();
+    match key {
+        Some(key) => {
+            let vary = &response.headers[&key];
+            let listed = vary
+                .split(',')
+                .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("Accept-Encoding"));
+            if !listed {
+                let vary = format!("{}, Accept-Encoding", vary);
+                response.headers.insert(key, vary);
+            }
+        }
+        None => {
+            response.add_header("Vary", "Accept-Encoding");
+        }
+    }
+}
+
+/// Middleware compressing successful responses with a compressible `Content-Type`
+/// in the encoding the client prefers.
+/// Bodies of responses with a strong `ETag`, like `Str` and `Bytes` routes, are compressed once.
+pub struct Compression {
+    /// Encodings used, in order of preference
+    pub encodings: Vec<Encoding>,
+    /// Bodies smaller than this are not compressed
+    pub min_size: usize,
+    /// Quality from 0 to 11 of responses compressed while responding.
+    /// Gzip and deflate use at most 9.
+    pub quality: u32,
+    /// Compressed bodies by strong `ETag` and encoding
+    cache: Mutex<HashMap<(String, Encoding), Vec<u8>>>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Compress with the encodings of [Encoding::available]
+    pub fn new() -> Compression {
+        Compression {
+            encodings: Encoding::available(),
+            min_size: 1024,
+            quality: 5,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Compress the content of `Str` and `Bytes` routes with a compressible `Content-Type`
+    /// with the best quality, so it is not compressed while responding
+    pub fn precompress(&self, routes: &[Route]) {
+        for route in routes {
+            let (content, etag) = match (&route.response, route.etag()) {
+                (ResponseContent::Str(s), Some(etag)) => (s.as_bytes(), etag),
+                (ResponseContent::Bytes(b), Some(etag)) => (b.as_slice(), etag),
+                _ => continue,
+            };
+            let compressible_type = route
+                .returned_headers
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case("Content-Type") && compressible(v));
+            if !compressible_type || content.len() < self.min_size {
+                continue;
+            }
+            for encoding in self.encodings.iter() {
+                let _ = self.cached(etag, *encoding, || {
+                    compress(*encoding, BEST_QUALITY, content)
+                });
+            }
+        }
+    }
+
+    /// Compressed body from the cache, or compressed and added to the cache
+    fn cached<F>(&self, etag: &str, encoding: Encoding, compress: F) -> WebResult<Vec<u8>>
+    where
+        F: FnOnce() -> WebResult<Vec<u8>>,
+    {
+        let key = (etag.to_string(), encoding);
+        if let Some(bytes) = self.cache.lock().unwrap().get(&key) {
+            return Ok(bytes.clone());
+        }
+        let bytes = compress()?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() < MAX_CACHED {
+            cache.insert(key, bytes.clone());
+        }
+        Ok(bytes)
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: &mut Response) {
+        let header = |response: &Response, name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.clone())
+        };
+        let compressible_type = header(response, "Content-Type").is_some_and(|t| compressible(&t));
+        if !compressible_type
+            || header(response, "Content-Encoding").is_some()
+            || self.encodings.is_empty()
+        {
+            return;
+        }
+        vary_on_encoding(response);
+        let no_transform =
+            header(response, "Cache-Control").is_some_and(|c| c.contains("no-transform"));
+        let small = response
+            .bytes
+            .as_ref()
+            .is_some_and(|b| b.len() < self.min_size);
+        if response.status_code != 200 || no_transform || small {
+            return;
+        }
+        let accept_encoding = request.header("Accept-Encoding").unwrap_or_default();
+        let Some(encoding) = negotiate(accept_encoding, &self.encodings) else {
+            return;
+        };
+
+        let etag = header(response, "ETag");
+        if let Some(bytes) = response.bytes.take() {
+            let compressed = match &etag {
+                Some(etag) if !etag.starts_with("W/") => {
+                    self.cached(etag, encoding, || compress(encoding, self.quality, &bytes))
+                }
+                _ => compress(encoding, self.quality, &bytes),
+            };
+            match compressed {
+                Ok(compressed) => response.bytes = Some(compressed),
+                Err(_) => {
+                    response.bytes = Some(bytes);
+                    return;
+                }
+            }
+        } else if let Some(write_body) = response.stream.take() {
+            let quality = self.quality;
+            response.stream = Some(Box::new(move |writer| {
+                encode_to(encoding, quality, writer, write_body)
+            }));
+        } else {
+            return;
+        }
+        response.headers.retain(|k, _| {
+            !k.eq_ignore_ascii_case("Content-Length")
+                && !k.eq_ignore_ascii_case("Accept-Ranges")
+                && !k.eq_ignore_ascii_case("ETag")
+        });
+        response.add_header("Content-Encoding", encoding.name());
+        if let Some(etag) = etag {
+            response.add_header("ETag", &encoded_etag(&etag, encoding));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{check_routes, HTTPVerb};
+
+    #[test]
+    fn test_negotiate() {
+        let all = Encoding::ALL;
+        assert_eq!(negotiate("", &all), None);
+        assert_eq!(negotiate("gzip, br", &all), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate("gzip, br", &[Encoding::Gzip]),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("x-gzip", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *", &all), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate("deflate;q=0.9, gzip;q=0.5", &all),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate("identity, compress", &all), None);
+        assert_eq!(negotiate("gzip;q=bad", &all), None);
+        assert_eq!(negotiate("gzip", &[]), None);
+    }
+
+    #[test]
+    fn test_compressible() {
+        assert!(compressible("text/html; charset=utf-8"));
+        assert!(compressible("application/ld+json"));
+        assert!(compressible("image/svg+xml"));
+        assert!(!compressible("image/png"));
+        assert!(!compressible("font/woff2"));
+        assert!(!compressible("application/gzip"));
+    }
+
+    #[test]
+    fn test_vary_and_etag() {
+        let mut response = Response::new(None, 200);
+        vary_on_encoding(&mut response);
+        vary_on_encoding(&mut response);
+        assert_eq!(response.headers["Vary"], "Accept-Encoding");
+        let mut response = Response::new(None, 200);
+        response.add_header("vary", "Origin");
+        vary_on_encoding(&mut response);
+        assert_eq!(response.headers["vary"], "Origin, Accept-Encoding");
+
+        assert_eq!(encoded_etag("\"abc\"", Encoding::Gzip), "\"abc-gzip\"");
+        assert_eq!(encoded_etag("W/\"abc\"", Encoding::Brotli), "W/\"abc-br\"");
+    }
+
+    #[test]
+    fn test_compression_middleware() {
+        let page = "<p>compress me</p>".repeat(100);
+        let mut route = Route::new(HTTPVerb::GET, "/", ResponseContent::Str(page.clone()));
+        route
+            .returned_headers
+            .insert("Content-Type".to_string(), "text/html".to_string());
+        let mut image = Route::new(HTTPVerb::GET, "/image", ResponseContent::Str(page.clone()));
+        image
+            .returned_headers
+            .insert("Content-Type".to_string(), "image/png".to_string());
+        let mut compression = Compression::new();
+        compression.encodings = vec![Encoding::Gzip];
+        compression.precompress(std::slice::from_ref(&route));
+        route.add_middleware(compression);
+        image.add_middleware(Compression::new());
+        let routes = vec![route, image];
+        let get = |path: &str, accept_encoding: &str| {
+            let mut request = Request::new("GET", path);
+            request
+                .headers
+                .insert("accept-encoding".to_string(), accept_encoding.to_string());
+            check_routes(&routes, request)
+        };
+
+        let image = get("/image", "gzip");
+        assert!(!image.headers.contains_key("Content-Encoding"));
+        assert!(!image.headers.contains_key("Vary"));
+        let identity = get("/", "identity");
+        assert_eq!(identity.bytes.as_deref(), Some(page.as_bytes()));
+        let response = get("/", "gzip, br");
+        assert_eq!(response.headers["Vary"], "Accept-Encoding");
+        if cfg!(not(feature = "gzip")) {
+            assert!(!response.headers.contains_key("Content-Encoding"));
+            return;
+        }
+        assert_eq!(response.headers["Content-Encoding"], "gzip");
+        let etag = response.headers["ETag"].clone();
+        assert_ne!(etag, identity.headers["ETag"]);
+        assert!(response.bytes.as_ref().unwrap().len() < page.len());
+
+        let mut request = Request::new("GET", "/");
+        request
+            .headers
+            .insert("accept-encoding".to_string(), "gzip".to_string());
+        request.headers.insert("if-none-match".to_string(), etag);
+        assert_eq!(check_routes(&routes, request).status_code, 304);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_and_deflate() {
+        use std::io::Read;
+
+        let content = b"hello hello hello hello".repeat(20);
+        let mut decoded = Vec::new();
+        let gzip = compress(Encoding::Gzip, 5, &content).unwrap();
+        flate2::read::GzDecoder::new(gzip.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, content);
+        decoded.clear();
+        let deflate = compress(Encoding::Deflate, 5, &content).unwrap();
+        flate2::read::ZlibDecoder::new(deflate.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn test_brotli() {
+        use std::io::Read;
+
+        let content = b"hello hello hello hello".repeat(20);
+        let compressed = compress(Encoding::Brotli, 11, &content).unwrap();
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(compressed.as_slice(), 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, content);
+    }
+}
@@ -4,6 +4,8 @@ pub enum Error {
     LoadFile(String),
     InvalidParams(String),
     Connection(String),
+    /// A limit in [RenderLimits](crate::templates::render::RenderLimits) was exceeded
+    RenderLimit(String),
 }
 
 pub type WebResult<T> = Result<T, Error>;
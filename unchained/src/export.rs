@@ -0,0 +1,295 @@
+//! Render routes to a directory that can be deployed to any static host.
+//!
+//! ```no_run
+//! use unchained_web::{export::{export_site, ExportOptions}, router::{HTTPVerb::*, ResponseContent, Route}};
+//!
+//! let routes = vec![
+//!     Route::new(GET, "/", ResponseContent::Str("<h1>Home</h1>".to_string())),
+//!     Route::new(GET, "/posts/:id", ResponseContent::FromRequest(Box::new(|req| {
+//!         unchained_web::router::Response::new_200(format!("Post {}", req.path_params["id"]))
+//!     }))),
+//!     Route::new(GET, "/images/*", ResponseContent::FolderAccess),
+//! ];
+//! let mut options = ExportOptions::new("dist");
+//! options.paths = vec!["/posts/first".to_string()];
+//! export_site(&routes, &options).unwrap();
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, WebResult},
+    router::{check_routes, HTTPVerb, Request, Response, ResponseContent, Route},
+};
+
+pub struct ExportOptions {
+    /// Directory the site is written to
+    pub out_dir: PathBuf,
+    /// Paths to render in addition to routes without parameters,
+    /// e.g. `/courses/CS4505` for the route `/courses/:courseid`.
+    pub paths: Vec<String>,
+    /// Path that is rendered to `404.html`, e.g. a path caught by a `/*` route.
+    pub not_found: Option<String>,
+    /// Directory `FolderAccess` routes are served from
+    pub asset_root: PathBuf,
+}
+
+impl ExportOptions {
+    pub fn new(out_dir: impl Into<PathBuf>) -> Self {
+        ExportOptions {
+            out_dir: out_dir.into(),
+            paths: Vec::new(),
+            not_found: None,
+            asset_root: PathBuf::from("."),
+        }
+    }
+}
+
+/// Path with parameters or wildcards that can not be rendered without a concrete path
+fn is_static_path(path: &str) -> bool {
+    !path
+        .split('/')
+        .any(|s| s.starts_with(':') || s.contains('*'))
+}
+
+/// File a request path is written to.
+/// Paths with a file extension are kept and other paths get an `index.html`.
+fn output_file(out_dir: &Path, path: &str) -> PathBuf {
+    let trimmed = path.trim_matches('/');
+    let last_segment = trimmed.rsplit('/').next().unwrap_or_default();
+    if last_segment.contains('.') {
+        out_dir.join(trimmed)
+    } else {
+        out_dir.join(trimmed).join("index.html")
+    }
+}
+
+fn write_response(file: &Path, response: Response) -> WebResult<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut writer = BufWriter::new(File::create(file)?);
+    writer.write_all(&response.bytes.unwrap_or_default())?;
+    if let Some(write_body) = response.stream {
+        write_body(&mut writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Copy a file or folder like it is served.
+/// Hidden entries are skipped unless `serve_hidden` is set, and so is the output directory
+/// if it is inside the folder.
+fn copy_recursively(
+    from: &Path,
+    to: &Path,
+    serve_hidden: bool,
+    out_dir: &Path,
+    written: &mut Vec<PathBuf>,
+) -> WebResult<()> {
+    if from.is_dir() {
+        if fs::canonicalize(from)? == out_dir {
+            return Ok(());
+        }
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            if !serve_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            copy_recursively(
+                &entry.path(),
+                &to.join(entry.file_name()),
+                serve_hidden,
+                out_dir,
+                written,
+            )?;
+        }
+    } else {
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::copy(from, to)?;
+        written.push(to.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Render every static `GET` route and the given paths to files in the output directory.
+/// Files and folders of `FolderAccess` and `Static` routes are copied,
+/// without hidden files unless the route serves them.
+/// Returns the files that were written.
+///
+/// Fails if one of the given paths does not respond with a success status.
+/// Static routes without a success response, like a `/*` route, are skipped.
+pub fn export_site(routes: &[Route], options: &ExportOptions) -> WebResult<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    fs::create_dir_all(&options.out_dir)?;
+    let out_dir = fs::canonicalize(&options.out_dir)?;
+    let get_routes = routes
+        .iter()
+        .filter(|r| r.accepts(&HTTPVerb::GET))
+        .collect::<Vec<_>>();
+
+    for route in get_routes.iter() {
+        let prefix = route
+            .path
+            .split('/')
+            .take_while(|s| is_static_path(s))
+            .collect::<Vec<_>>()
+            .join("/");
+        let prefix = prefix.trim_matches('/');
+        let source = match &route.response {
+            ResponseContent::FolderAccess => Some((options.asset_root.join(prefix), false)),
+            ResponseContent::Static(files) => Some((files.root.clone(), files.serve_hidden)),
+            _ => None,
+        };
+        if let Some((source, serve_hidden)) = source {
+            if source.exists() {
+                copy_recursively(
+                    &source,
+                    &options.out_dir.join(prefix),
+                    serve_hidden,
+                    &out_dir,
+                    &mut written,
+                )?;
+            }
+            continue;
+        }
+        if !is_static_path(&route.path) {
+            continue;
+        }
+        let response = check_routes(routes, Request::new("GET", &route.path));
+        if (200..300).contains(&response.status_code) {
+            let file = output_file(&options.out_dir, &route.path);
+            write_response(&file, response)?;
+            written.push(file);
+        }
+    }
+
+    for path in options.paths.iter() {
+        let response = check_routes(routes, Request::new("GET", path));
+        if !(200..300).contains(&response.status_code) {
+            return Err(Error::InvalidParams(format!(
+                "Could not export {}. Got status {}",
+                path, response.status_code
+            )));
+        }
+        let file = output_file(&options.out_dir, path);
+        write_response(&file, response)?;
+        written.push(file);
+    }
+
+    if let Some(path) = &options.not_found {
+        let response = check_routes(routes, Request::new("GET", path));
+        let file = options.out_dir.join("404.html");
+        write_response(&file, response)?;
+        written.push(file);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_files() {
+        let out = Path::new("dist");
+        assert_eq!(output_file(out, "/"), out.join("index.html"));
+        assert_eq!(output_file(out, "skills"), out.join("skills/index.html"));
+        assert_eq!(
+            output_file(out, "/courses/CS4505/"),
+            out.join("courses/CS4505/index.html")
+        );
+        assert_eq!(output_file(out, "/robots.txt"), out.join("robots.txt"));
+    }
+
+    #[test]
+    fn test_export_routes_and_assets() {
+        let root = std::env::temp_dir().join(format!("unchained-export-{}", std::process::id()));
+        let assets = root.join("assets");
+        fs::create_dir_all(assets.join("images/icons")).unwrap();
+        fs::write(assets.join("images/icons/a.svg"), "<svg></svg>").unwrap();
+        fs::write(assets.join("favicon.ico"), "icon").unwrap();
+
+        let routes = vec![
+            Route::new(HTTPVerb::GET, "/", ResponseContent::Str("home".to_string())),
+            Route::new(
+                HTTPVerb::POST,
+                "/form",
+                ResponseContent::Str("post".to_string()),
+            ),
+            Route::new(
+                HTTPVerb::GET,
+                "/posts/:id",
+                ResponseContent::FromRequest(Box::new(|req| {
+                    Response::new_200(format!("post {}", req.path_params["id"]))
+                })),
+            ),
+            Route::new(HTTPVerb::GET, "/images/*", ResponseContent::FolderAccess),
+            Route::new(HTTPVerb::GET, "favicon.ico", ResponseContent::FolderAccess),
+            Route::new(
+                HTTPVerb::GET,
+                "/*",
+                ResponseContent::FromRequest(Box::new(|_| Response::new(Some("404".into()), 404))),
+            ),
+        ];
+        let out = root.join("dist");
+        let mut options = ExportOptions::new(&out);
+        options.asset_root = assets;
+        options.paths = vec!["/posts/first".to_string()];
+        options.not_found = Some("/not-found".to_string());
+
+        let written = export_site(&routes, &options).unwrap();
+        assert_eq!(written.len(), 5);
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), "home");
+        assert_eq!(
+            fs::read_to_string(out.join("posts/first/index.html")).unwrap(),
+            "post first"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("images/icons/a.svg")).unwrap(),
+            "<svg></svg>"
+        );
+        assert_eq!(fs::read_to_string(out.join("favicon.ico")).unwrap(), "icon");
+        assert_eq!(fs::read_to_string(out.join("404.html")).unwrap(), "404");
+        assert!(!out.join("form").exists());
+
+        options.paths = vec!["/missing/path".to_string()];
+        options.not_found = None;
+        assert!(export_site(&routes, &options).is_err());
+    }
+
+    #[test]
+    fn test_export_skips_hidden_files_and_output() {
+        let root =
+            std::env::temp_dir().join(format!("unchained-export-hidden-{}", std::process::id()));
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".git/config"), "secret").unwrap();
+        fs::write(root.join(".env"), "secret").unwrap();
+        fs::write(root.join("style.css"), "body {}").unwrap();
+
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/*",
+            ResponseContent::FolderAccess,
+        )];
+        let out = root.join("dist");
+        let mut options = ExportOptions::new(&out);
+        options.asset_root = root.clone();
+
+        let written = export_site(&routes, &options).unwrap();
+        assert_eq!(written, vec![out.join("style.css")]);
+        assert!(!out.join(".env").exists() && !out.join(".git").exists());
+        assert!(!out.join("dist").exists());
+
+        let written = export_site(&routes, &options).unwrap();
+        assert_eq!(written, vec![out.join("style.css")]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
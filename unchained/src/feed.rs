@@ -0,0 +1,376 @@
+//! Build RSS 2.0 and Atom feeds.
+//!
+//! ```
+//! use unchained_web::feed::{Feed, FeedItem};
+//!
+//! let mut feed = Feed::new("Blog", "https://example.com", "Posts about Rust");
+//! feed.items.push(FeedItem {
+//!     title: "Hello".to_string(),
+//!     link: "https://example.com/posts/hello".to_string(),
+//!     published: Some("2024-03-01".to_string()),
+//!     ..FeedItem::default()
+//! });
+//! assert!(feed.to_rss().contains("<pubDate>Fri, 01 Mar 2024 00:00:00 +0000</pubDate>"));
+//! assert!(feed.to_atom().contains("<updated>2024-03-01T00:00:00Z</updated>"));
+//! ```
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, WebResult},
+    router::{HTTPVerb, ResponseContent, Route},
+    templates::{context::ContextTree, i18n::Date},
+};
+
+/// Escape text to include it in XML
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Route serving an XML document with the given content type
+pub fn xml_route(path: &str, xml: String, content_type: &str) -> Route {
+    Route::new_with_headers(
+        HTTPVerb::GET,
+        path,
+        ResponseContent::Str(xml),
+        HashMap::from([("Content-Type".to_string(), content_type.to_string())]),
+    )
+}
+
+/// Point in time of a feed item in UTC
+struct Timestamp {
+    date: Date,
+    time: (u32, u32, u32),
+}
+
+impl Timestamp {
+    /// Parse an ISO 8601 date with an optional UTC time, e.g. `2024-03-01T12:30:00Z`
+    fn parse(value: &str) -> Option<Self> {
+        let date = Date::parse_iso(value)?;
+        let time = match value.get(11..19) {
+            Some(time) => {
+                let mut parts = time.split(':').map(|p| p.parse::<u32>().ok());
+                (parts.next()??, parts.next()??, parts.next()??)
+            }
+            None => (0, 0, 0),
+        };
+        Some(Timestamp { date, time })
+    }
+
+    fn now() -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let of_day = seconds.rem_euclid(60 * 60 * 24) as u32;
+        Timestamp {
+            date: Date::from_timestamp(seconds),
+            time: (of_day / 3600, of_day / 60 % 60, of_day % 60),
+        }
+    }
+
+    /// RFC 822 date used by RSS
+    fn rfc822(&self) -> String {
+        const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let Timestamp { date, time } = self;
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+            DAYS[date.weekday() as usize],
+            date.day,
+            MONTHS[date.month as usize - 1],
+            date.year,
+            time.0,
+            time.1,
+            time.2
+        )
+    }
+
+    /// RFC 3339 date used by Atom
+    fn rfc3339(&self) -> String {
+        let Timestamp { date, time } = self;
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            date.year, date.month, date.day, time.0, time.1, time.2
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FeedItem {
+    pub title: String,
+    /// Absolute URL of the item
+    pub link: String,
+    /// Unique id. Uses the link if `None`.
+    pub id: Option<String>,
+    pub summary: Option<String>,
+    /// Full content of the item as html, e.g. rendered markdown
+    pub content: Option<String>,
+    /// ISO 8601 date, e.g. `2024-03-01` or `2024-03-01T12:30:00Z`
+    pub published: Option<String>,
+    pub author: Option<String>,
+}
+
+impl FeedItem {
+    fn published(&self) -> Option<Timestamp> {
+        self.published.as_deref().and_then(Timestamp::parse)
+    }
+}
+
+impl TryFrom<&ContextTree> for FeedItem {
+    type Error = Error;
+
+    /// Create an item from a context branch with the attributes
+    /// `title`, `link`, `id`, `summary`, `content`, `published`, and `author`.
+    /// `title` and `link` are required.
+    fn try_from(value: &ContextTree) -> Result<Self, Self::Error> {
+        let get = |key: &str| match value.get_from_branch(key) {
+            Some(ContextTree::Leaf(p)) => Some(p.to_string()),
+            _ => None,
+        };
+        let required = |key: &str| {
+            get(key).ok_or_else(|| {
+                Error::InvalidParams(format!("Feed item is missing the attribute {}", key))
+            })
+        };
+        Ok(FeedItem {
+            title: required("title")?,
+            link: required("link")?,
+            id: get("id"),
+            summary: get("summary"),
+            content: get("content"),
+            published: get("published"),
+            author: get("author"),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub title: String,
+    /// Absolute URL of the website
+    pub link: String,
+    pub description: String,
+    /// Absolute URL the feed is served from
+    pub feed_url: Option<String>,
+    pub items: Vec<FeedItem>,
+}
+
+impl Feed {
+    pub fn new(title: &str, link: &str, description: &str) -> Self {
+        Feed {
+            title: title.to_string(),
+            link: link.to_string(),
+            description: description.to_string(),
+            feed_url: None,
+            items: Vec::new(),
+        }
+    }
+
+    /// Create a feed with items from a context array.
+    /// See [FeedItem::try_from].
+    pub fn with_items(mut self, items: &ContextTree) -> WebResult<Self> {
+        let ContextTree::Array(items) = items else {
+            return Err(Error::InvalidParams(
+                "Feed items have to be a context array".to_string(),
+            ));
+        };
+        for item in items.iter() {
+            self.items.push(FeedItem::try_from(item)?);
+        }
+        Ok(self)
+    }
+
+    /// Most recent date of the items, or now if no item has a date
+    fn updated(&self) -> Timestamp {
+        self.items
+            .iter()
+            .filter_map(FeedItem::published)
+            .max_by_key(|t| (t.date.days_since_epoch(), t.time))
+            .unwrap_or_else(Timestamp::now)
+    }
+
+    /// Write the feed as RSS 2.0.
+    /// Items with an invalid `published` date are written without a date.
+    pub fn to_rss(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(
+            "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n",
+        );
+        xml.push_str(&format!("<title>{}</title>\n", escape_xml(&self.title)));
+        xml.push_str(&format!("<link>{}</link>\n", escape_xml(&self.link)));
+        xml.push_str(&format!(
+            "<description>{}</description>\n",
+            escape_xml(&self.description)
+        ));
+        if let Some(feed_url) = &self.feed_url {
+            xml.push_str(&format!(
+                "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+                escape_xml(feed_url)
+            ));
+        }
+        xml.push_str(&format!(
+            "<lastBuildDate>{}</lastBuildDate>\n",
+            self.updated().rfc822()
+        ));
+        for item in self.items.iter() {
+            xml.push_str("<item>\n");
+            xml.push_str(&format!("<title>{}</title>\n", escape_xml(&item.title)));
+            xml.push_str(&format!("<link>{}</link>\n", escape_xml(&item.link)));
+            let id = item.id.as_ref().unwrap_or(&item.link);
+            xml.push_str(&format!(
+                "<guid isPermaLink=\"{}\">{}</guid>\n",
+                item.id.is_none(),
+                escape_xml(id)
+            ));
+            if let Some(description) = item.content.as_ref().or(item.summary.as_ref()) {
+                xml.push_str(&format!(
+                    "<description>{}</description>\n",
+                    escape_xml(description)
+                ));
+            }
+            if let Some(author) = &item.author {
+                xml.push_str(&format!("<author>{}</author>\n", escape_xml(author)));
+            }
+            if let Some(published) = item.published() {
+                xml.push_str(&format!("<pubDate>{}</pubDate>\n", published.rfc822()));
+            }
+            xml.push_str("</item>\n");
+        }
+        xml.push_str("</channel>\n</rss>\n");
+        xml
+    }
+
+    /// Write the feed as Atom.
+    /// Items without a valid `published` date use the date of the feed.
+    pub fn to_atom(&self) -> String {
+        let updated = self.updated().rfc3339();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_xml(&self.title)));
+        xml.push_str(&format!(
+            "<subtitle>{}</subtitle>\n",
+            escape_xml(&self.description)
+        ));
+        xml.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&self.link)));
+        if let Some(feed_url) = &self.feed_url {
+            xml.push_str(&format!(
+                "<link href=\"{}\" rel=\"self\"/>\n",
+                escape_xml(feed_url)
+            ));
+        }
+        let id = self.feed_url.as_ref().unwrap_or(&self.link);
+        xml.push_str(&format!("<id>{}</id>\n", escape_xml(id)));
+        xml.push_str(&format!("<updated>{}</updated>\n", updated));
+        for item in self.items.iter() {
+            xml.push_str("<entry>\n");
+            xml.push_str(&format!("<title>{}</title>\n", escape_xml(&item.title)));
+            xml.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&item.link)));
+            let id = item.id.as_ref().unwrap_or(&item.link);
+            xml.push_str(&format!("<id>{}</id>\n", escape_xml(id)));
+            let item_updated = item
+                .published()
+                .map(|p| p.rfc3339())
+                .unwrap_or(updated.clone());
+            xml.push_str(&format!("<updated>{}</updated>\n", item_updated));
+            if let Some(author) = &item.author {
+                xml.push_str(&format!(
+                    "<author><name>{}</name></author>\n",
+                    escape_xml(author)
+                ));
+            }
+            if let Some(summary) = &item.summary {
+                xml.push_str(&format!("<summary>{}</summary>\n", escape_xml(summary)));
+            }
+            if let Some(content) = &item.content {
+                xml.push_str(&format!(
+                    "<content type=\"html\">{}</content>\n",
+                    escape_xml(content)
+                ));
+            }
+            xml.push_str("</entry>\n");
+        }
+        xml.push_str("</feed>\n");
+        xml
+    }
+
+    /// Route serving the feed as RSS 2.0
+    pub fn rss_route(&self, path: &str) -> Route {
+        xml_route(path, self.to_rss(), "application/rss+xml; charset=utf-8")
+    }
+
+    /// Route serving the feed as Atom
+    pub fn atom_route(&self, path: &str) -> Route {
+        xml_route(path, self.to_atom(), "application/atom+xml; charset=utf-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed() -> Feed {
+        let items: ContextTree = [
+            [
+                ("title", "First & best"),
+                ("link", "https://example.com/1"),
+                ("published", "2024-03-01T12:30:00Z"),
+                ("content", "<p>Hi</p>"),
+            ],
+            [
+                ("title", "Second"),
+                ("link", "https://example.com/2"),
+                ("published", "2024-04-02"),
+                ("summary", "Short"),
+            ],
+        ]
+        .into();
+        Feed::new("Blog", "https://example.com", "Posts")
+            .with_items(&items)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_rss() {
+        let rss = feed().to_rss();
+        assert!(rss.contains("<title>First &amp; best</title>"));
+        assert!(rss.contains("<pubDate>Fri, 01 Mar 2024 12:30:00 +0000</pubDate>"));
+        assert!(rss.contains("<description>&lt;p&gt;Hi&lt;/p&gt;</description>"));
+        assert!(rss.contains("<lastBuildDate>Tue, 02 Apr 2024 00:00:00 +0000</lastBuildDate>"));
+        assert!(rss.contains("<guid isPermaLink=\"true\">https://example.com/2</guid>"));
+    }
+
+    #[test]
+    fn test_atom() {
+        let atom = feed().to_atom();
+        assert!(atom.starts_with("<?xml"));
+        assert!(atom.contains("<updated>2024-03-01T12:30:00Z</updated>"));
+        assert!(atom.contains("<id>https://example.com</id>"));
+        assert!(atom.contains("<summary>Short</summary>"));
+        assert_eq!(atom.matches("<entry>").count(), 2);
+    }
+
+    #[test]
+    fn test_items_require_title_and_link() {
+        let items: ContextTree = [[("title", "No link")]].into();
+        assert!(Feed::new("Blog", "https://example.com", "")
+            .with_items(&items)
+            .is_err());
+    }
+}
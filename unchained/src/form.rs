@@ -0,0 +1,278 @@
+//! Query strings and form bodies.
+//!
+//! ```
+//! use unchained_web::form::{parse_query, percent_decode};
+//!
+//! let params = parse_query("tag=ml&tag=web&q=deep+learning");
+//! assert_eq!(params["tag"], vec!["ml", "web"]);
+//! assert_eq!(params["q"], vec!["deep learning"]);
+//! assert_eq!(percent_decode("caf%C3%A9"), "café");
+//! ```
+
+use std::collections::HashMap;
+
+use crate::error::{Error, WebResult};
+
+/// Values of parameters that can be given several times
+pub type MultiMap = HashMap<String, Vec<String>>;
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some([high, low]) if bytes[i] == b'%' => hex_value(*high).zip(hex_value(*low)),
+            _ => None,
+        };
+        match (bytes[i], escaped) {
+            (_, Some((high, low))) => {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+            (b'+', None) if plus_as_space => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decode `%XX` escapes, e.g. in path segments.
+/// Invalid escapes are kept as they are.
+pub fn percent_decode(value: &str) -> String {
+    decode(value, false)
+}
+
+/// Encode characters other than letters, digits and `-._~` as `%XX`,
+/// e.g. to use a file name in a link
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Parse `application/x-www-form-urlencoded` pairs like `a=1&b=2&a=3`.
+/// A `+` is decoded as a space.
+pub fn parse_query(query: &str) -> MultiMap {
+    let mut params = MultiMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params
+            .entry(decode(name, true))
+            .or_default()
+            .push(decode(value, true));
+    }
+    params
+}
+
+/// File uploaded in a `multipart/form-data` body
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormFile {
+    /// Name of the form field
+    pub name: String,
+    /// File name given by the client
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Fields and files of a submitted form
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Form {
+    pub fields: MultiMap,
+    pub files: Vec<FormFile>,
+}
+
+/// Value of a parameter in a header like `form-data; name="file"; filename="a.txt"`
+fn header_param<'a>(header: &'a str, param: &str) -> Option<&'a str> {
+    header.split(';').skip(1).find_map(|part| {
+        let (key, value) = part.trim().split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(param)
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+impl Form {
+    /// First value of a field
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .get(name)
+            .and_then(|v| v.first())
+            .map(|v| v.as_str())
+    }
+
+    /// First file uploaded with the field name
+    pub fn file(&self, name: &str) -> Option<&FormFile> {
+        self.files.iter().find(|f| f.name == name)
+    }
+
+    /// Parse a form from a body with the given `Content-Type`.
+    /// Supports `application/x-www-form-urlencoded` and `multipart/form-data`.
+    pub fn parse(content_type: &str, body: &[u8]) -> WebResult<Form> {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/x-www-form-urlencoded" => Ok(Form {
+                fields: parse_query(&String::from_utf8_lossy(body)),
+                files: Vec::new(),
+            }),
+            "multipart/form-data" => {
+                let boundary = header_param(content_type, "boundary").ok_or_else(|| {
+                    Error::InvalidParams("Multipart form without boundary".to_string())
+                })?;
+                Form::from_multipart(body, boundary)
+            }
+            _ => Err(Error::InvalidParams(format!(
+                "Unsupported form content type '{}'",
+                content_type
+            ))),
+        }
+    }
+
+    /// Parse a `multipart/form-data` body.
+    /// Parts with a file name are added to `files` and other parts to `fields`.
+    /// See https://www.rfc-editor.org/rfc/rfc7578
+    pub fn from_multipart(body: &[u8], boundary: &str) -> WebResult<Form> {
+        let invalid =
+            |reason: &str| Error::InvalidParams(format!("Invalid multipart form. {}", reason));
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut form = Form::default();
+
+        let start = find(body, &delimiter).ok_or_else(|| invalid("Missing boundary"))?;
+        let mut rest = &body[start + delimiter.len()..];
+        loop {
+            if rest.starts_with(b"--") {
+                return Ok(form);
+            }
+            rest = rest
+                .strip_prefix(b"\r\n")
+                .ok_or_else(|| invalid("Expected line break after boundary"))?;
+            let head_end =
+                find(rest, b"\r\n\r\n").ok_or_else(|| invalid("Missing part headers"))?;
+            let head = String::from_utf8_lossy(&rest[..head_end]);
+            rest = &rest[head_end + 4..];
+
+            let mut next_delimiter = b"\r\n".to_vec();
+            next_delimiter.extend_from_slice(&delimiter);
+            let end =
+                find(rest, &next_delimiter).ok_or_else(|| invalid("Missing closing boundary"))?;
+            let data = &rest[..end];
+            rest = &rest[end + next_delimiter.len()..];
+
+            let mut disposition = None;
+            let mut content_type = "text/plain";
+            for line in head.split("\r\n") {
+                let Some((name, value)) = line.split_once(':') else {
+                    continue;
+                };
+                if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                    disposition = Some(value.trim());
+                } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                    content_type = value.trim();
+                }
+            }
+            let disposition = disposition.ok_or_else(|| invalid("Missing Content-Disposition"))?;
+            let name = header_param(disposition, "name")
+                .ok_or_else(|| invalid("Missing field name"))?
+                .to_string();
+            match header_param(disposition, "filename") {
+                Some(filename) => form.files.push(FormFile {
+                    name,
+                    filename: filename.to_string(),
+                    content_type: content_type.to_string(),
+                    data: data.to_vec(),
+                }),
+                None => form
+                    .fields
+                    .entry(name)
+                    .or_default()
+                    .push(String::from_utf8_lossy(data).into_owned()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c"), "a b+c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%E2%9C%93"), "✓");
+        assert_eq!(percent_encode("a b/✓"), "a%20b%2F%E2%9C%93");
+        assert_eq!(percent_decode(&percent_encode("100% ~ok")), "100% ~ok");
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("a=1&b&a=2&&c=x%26y&d+e=f+g");
+        assert_eq!(params["a"], vec!["1", "2"]);
+        assert_eq!(params["b"], vec![""]);
+        assert_eq!(params["c"], vec!["x&y"]);
+        assert_eq!(params["d e"], vec!["f g"]);
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn test_multipart_form() {
+        let body = "preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Hello\r\nworld\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"notes.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            file --XyZ content\r\n--XyZ--\r\n";
+        let form = Form::parse("multipart/form-data; boundary=\"XyZ\"", body.as_bytes()).unwrap();
+        assert_eq!(form.field("title"), Some("Hello\r\nworld"));
+        let file = form.file("upload").unwrap();
+        assert_eq!(file.filename, "notes.txt");
+        assert_eq!(file.content_type, "text/plain");
+        assert_eq!(file.data, b"file --XyZ content");
+
+        assert!(Form::from_multipart(b"--XyZ\r\nno headers", "XyZ").is_err());
+        assert!(Form::parse("multipart/form-data", b"").is_err());
+    }
+
+    #[test]
+    fn test_urlencoded_form() {
+        let form = Form::parse(
+            "application/x-www-form-urlencoded; charset=utf-8",
+            b"name=Ada+Lovelace&lang=en&lang=nl",
+        )
+        .unwrap();
+        assert_eq!(form.field("name"), Some("Ada Lovelace"));
+        assert_eq!(form.fields["lang"], vec!["en", "nl"]);
+        assert!(Form::parse("application/json", b"{}").is_err());
+    }
+}
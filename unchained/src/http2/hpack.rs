@@ -0,0 +1,523 @@
+//! HPACK header compression of HTTP/2, see https://www.rfc-editor.org/rfc/rfc7541
+//!
+//! ```
+//! use unchained_web::http2::hpack::{encode, Decoder};
+//!
+//! let block = encode(&[(":status", "200"), ("content-type", "text/html")]);
+//! let headers = Decoder::new(4096).decode(&block, 16 * 1024).unwrap().unwrap();
+//! assert_eq!(headers[0], (":status".to_string(), "200".to_string()));
+//! assert_eq!(headers[1], ("content-type".to_string(), "text/html".to_string()));
+//! ```
+
+use std::{collections::VecDeque, sync::OnceLock};
+
+use crate::error::{Error, WebResult};
+
+/// Entries of the static table, indexed from 1
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Bit lengths of the canonical Huffman codes of the bytes and EOS (256).
+/// See https://www.rfc-editor.org/rfc/rfc7541#appendix-B
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, //
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28, //
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, //
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, //
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, //
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, //
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, //
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, //
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, //
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, //
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, //
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23, //
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, //
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, //
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, //
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, //
+    30,
+];
+
+const MAX_CODE_LENGTH: usize = 30;
+const EOS: u16 = 256;
+
+/// Tables for decoding canonical Huffman codes one bit at a time
+struct Huffman {
+    /// Symbols ordered by code length, then by value
+    symbols: Vec<u16>,
+    /// Number of codes of each length
+    counts: [u32; MAX_CODE_LENGTH + 1],
+    /// First code of each length
+    first_codes: [u32; MAX_CODE_LENGTH + 1],
+    /// Index in `symbols` of the first code of each length
+    offsets: [u32; MAX_CODE_LENGTH + 1],
+}
+
+fn huffman() -> &'static Huffman {
+    static HUFFMAN: OnceLock<Huffman> = OnceLock::new();
+    HUFFMAN.get_or_init(|| {
+        let mut symbols = (0..=EOS).collect::<Vec<_>>();
+        symbols.sort_by_key(|&s| (HUFFMAN_LENGTHS[s as usize], s));
+        let mut counts = [0; MAX_CODE_LENGTH + 1];
+        for length in HUFFMAN_LENGTHS {
+            counts[length as usize] += 1;
+        }
+        let mut first_codes = [0; MAX_CODE_LENGTH + 1];
+        let mut offsets = [0; MAX_CODE_LENGTH + 1];
+        for length in 1..=MAX_CODE_LENGTH {
+            first_codes[length] = (first_codes[length - 1] + counts[length - 1]) << 1;
+            offsets[length] = offsets[length - 1] + counts[length - 1];
+        }
+        Huffman {
+            symbols,
+            counts,
+            first_codes,
+            offsets,
+        }
+    })
+}
+
+fn compression_error(message: &str) -> Error {
+    Error::InvalidParams(format!("HPACK: {}", message))
+}
+
+/// Decode a Huffman encoded string
+pub fn decode_huffman(bytes: &[u8]) -> WebResult<Vec<u8>> {
+    let huffman = huffman();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut length) = (0u32, 0usize);
+    for byte in bytes {
+        for shift in (0..8).rev() {
+            code = (code << 1) | (byte >> shift & 1) as u32;
+            length += 1;
+            let index = code.wrapping_sub(huffman.first_codes[length]);
+            if index < huffman.counts[length] {
+                match huffman.symbols[(huffman.offsets[length] + index) as usize] {
+                    EOS => return Err(compression_error("EOS in Huffman string")),
+                    symbol => decoded.push(symbol as u8),
+                }
+                (code, length) = (0, 0);
+            } else if length == MAX_CODE_LENGTH {
+                return Err(compression_error("Invalid Huffman code"));
+            }
+        }
+    }
+    // The padding is the start of EOS, up to 7 bits that are all 1
+    if length > 7 || code != (1 << length) - 1 {
+        return Err(compression_error("Invalid Huffman padding"));
+    }
+    Ok(decoded)
+}
+
+/// Decode an integer with a prefix of `prefix_bits` in the first byte.
+/// Returns the integer and the bytes it used.
+fn decode_integer(bytes: &[u8], prefix_bits: u8) -> WebResult<(usize, usize)> {
+    let max_prefix = (1 << prefix_bits) - 1;
+    let Some(first) = bytes.first() else {
+        return Err(compression_error("Missing integer"));
+    };
+    let mut value = (*first as usize) & max_prefix;
+    if value < max_prefix {
+        return Ok((value, 1));
+    }
+    for (i, byte) in bytes.iter().enumerate().skip(1) {
+        let shift = 7 * (i - 1);
+        if shift > 28 {
+            return Err(compression_error("Integer too large"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(compression_error("Truncated integer"))
+}
+
+/// Append an integer with a prefix of `prefix_bits`, setting `flags` in the first byte
+fn encode_integer(output: &mut Vec<u8>, flags: u8, prefix_bits: u8, mut value: usize) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        output.push(flags | value as u8);
+        return;
+    }
+    output.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        output.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+/// Decode a string literal. Returns the string and the bytes it used.
+fn decode_string(bytes: &[u8]) -> WebResult<(String, usize)> {
+    let (length, used) = decode_integer(bytes, 7)?;
+    let Some(content) = bytes.get(used..used + length) else {
+        return Err(compression_error("Truncated string"));
+    };
+    let content = match bytes[0] & 0x80 != 0 {
+        true => decode_huffman(content)?,
+        false => content.to_vec(),
+    };
+    let string = String::from_utf8(content).map_err(|_| compression_error("Invalid UTF-8"))?;
+    Ok((string, used + length))
+}
+
+/// Size of a table entry, counting 32 bytes of overhead
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Decodes the header blocks of one connection, keeping its dynamic table
+pub struct Decoder {
+    /// Newest entries first
+    dynamic: VecDeque<(String, String)>,
+    size: usize,
+    /// Size of the table set by the encoder
+    max_size: usize,
+    /// Size the encoder may use, from the `SETTINGS_HEADER_TABLE_SIZE` we sent
+    allowed_size: usize,
+}
+
+impl Decoder {
+    pub fn new(allowed_size: usize) -> Decoder {
+        Decoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: allowed_size,
+            allowed_size,
+        }
+    }
+
+    fn entry(&self, index: usize) -> WebResult<(&str, &str)> {
+        let entry = match index {
+            0 => None,
+            1..=61 => Some(STATIC_TABLE[index - 1]),
+            _ => self
+                .dynamic
+                .get(index - 62)
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        };
+        entry.ok_or_else(|| compression_error(&format!("Invalid table index {}", index)))
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        self.size += entry_size(&name, &value);
+        self.dynamic.push_front((name, value));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some((name, value)) = self.dynamic.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&name, &value);
+        }
+    }
+
+    /// Decode a header block into names and values in their order.
+    /// Returns None if the headers are larger than `max_list_size`, counted like
+    /// `SETTINGS_MAX_HEADER_LIST_SIZE`. The rest of the block is still decoded
+    /// to keep the table in sync, but no more headers are kept.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> WebResult<Option<Vec<(String, String)>>> {
+        let mut headers = HeaderList {
+            headers: Vec::new(),
+            size: 0,
+            max_size: max_list_size,
+        };
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let (index, used) = decode_integer(block, 7)?;
+                let (name, value) = self.entry(index)?;
+                headers.push(name, value);
+                block = &block[used..];
+                continue;
+            }
+            if first & 0xe0 == 0x20 {
+                if headers.size > 0 {
+                    return Err(compression_error("Table size update after a header"));
+                }
+                let (size, used) = decode_integer(block, 5)?;
+                if size > self.allowed_size {
+                    return Err(compression_error("Table size above the setting"));
+                }
+                self.max_size = size;
+                self.evict();
+                block = &block[used..];
+                continue;
+            }
+            // Literals with incremental indexing have a 6 bit prefix,
+            // the ones without indexing or never indexed a 4 bit prefix
+            let indexing = first & 0x40 != 0;
+            let (index, mut used) = decode_integer(block, if indexing { 6 } else { 4 })?;
+            let name = match index {
+                0 => {
+                    let (name, name_used) = decode_string(&block[used..])?;
+                    used += name_used;
+                    name
+                }
+                index => self.entry(index)?.0.to_string(),
+            };
+            let (value, value_used) = decode_string(&block[used..])?;
+            block = &block[used + value_used..];
+            headers.push(&name, &value);
+            if indexing {
+                self.insert(name, value);
+            }
+        }
+        Ok((headers.size <= max_list_size).then_some(headers.headers))
+    }
+}
+
+/// Decoded headers, kept until their size exceeds the maximum
+struct HeaderList {
+    headers: Vec<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl HeaderList {
+    fn push(&mut self, name: &str, value: &str) {
+        self.size += entry_size(name, value);
+        if self.size <= self.max_size {
+            self.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+}
+
+/// Encode headers without the dynamic table or Huffman coding.
+/// Names have to be lowercase.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in headers {
+        if let Some(index) = STATIC_TABLE.iter().position(|&e| e == (name, value)) {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_integer(&mut block, 0x00, 7, name.len());
+                block.extend_from_slice(name.as_bytes());
+            }
+        }
+        encode_integer(&mut block, 0x00, 7, value.len());
+        block.extend_from_slice(value.as_bytes());
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = usize::MAX;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        let hex = hex.replace(' ', "");
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut output = Vec::new();
+        encode_integer(&mut output, 0, 5, 10);
+        encode_integer(&mut output, 0, 5, 1337);
+        assert_eq!(output, [0x0a, 0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&output, 5).unwrap(), (10, 1));
+        assert_eq!(decode_integer(&output[1..], 5).unwrap(), (1337, 3));
+        assert!(decode_integer(&[0x1f, 0x9a], 5).is_err());
+        assert!(decode_integer(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], 5).is_err());
+    }
+
+    /// Requests of https://www.rfc-editor.org/rfc/rfc7541#appendix-C.3 and C.4
+    #[test]
+    fn test_decode_requests() {
+        let expected = [
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ]),
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ]),
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ]),
+        ];
+        let plain = [
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ];
+        let huffman = [
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ];
+        for blocks in [plain, huffman] {
+            let mut decoder = Decoder::new(4096);
+            for (block, expected) in blocks.iter().zip(&expected) {
+                assert_eq!(
+                    &decoder.decode(&hex(block), MAX).unwrap().unwrap(),
+                    expected
+                );
+            }
+            assert_eq!(decoder.size, 164);
+        }
+    }
+
+    #[test]
+    fn test_table_size() {
+        let mut decoder = Decoder::new(100);
+        // custom-key: custom-value is 54 bytes in the table
+        let entry = hex("400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65");
+        decoder.decode(&entry, MAX).unwrap();
+        decoder.decode(&entry, MAX).unwrap();
+        assert_eq!(decoder.dynamic.len(), 1);
+        assert_eq!(
+            decoder.decode(&[0xbe], MAX).unwrap().unwrap()[0].1,
+            "custom-value"
+        );
+        assert!(decoder.decode(&[0xbf], MAX).is_err());
+        // Evict everything with a size update
+        decoder.decode(&[0x20], MAX).unwrap();
+        assert!(decoder.decode(&[0xbe], MAX).is_err());
+        assert!(decoder.decode(&[0x3f, 0x60], MAX).is_err());
+        assert!(decoder.decode(&[0x82, 0x20], MAX).is_err());
+    }
+
+    #[test]
+    fn test_header_list_size() {
+        let mut decoder = Decoder::new(4096);
+        // custom-key: custom-value is 54 bytes in the list
+        let entry = hex("400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65");
+        assert_eq!(decoder.decode(&entry, 54).unwrap().unwrap().len(), 1);
+        assert!(decoder.decode(&entry, 53).unwrap().is_none());
+        // Each repeated index would copy the entry, but no headers are kept past the limit
+        let bomb = [0xbe; 16 * 1024];
+        assert!(decoder.decode(&bomb, 16 * 1024).unwrap().is_none());
+        // The entries added by a block over the limit are still in the table
+        assert_eq!(decoder.dynamic.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_huffman() {
+        assert_eq!(
+            decode_huffman(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff")).unwrap(),
+            b"www.example.com"
+        );
+        // Padding longer than 7 bits
+        assert!(decode_huffman(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff ff")).is_err());
+        // Padding with a 0 bit
+        assert!(decode_huffman(&[0x1e]).is_err());
+        // EOS
+        assert!(decode_huffman(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let headers = [
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("location", "https://www.example.com"),
+            ("x-custom", "a"),
+            (":status", "200"),
+        ];
+        let block = encode(&headers);
+        assert_eq!(&block[..5], [0x08, 0x03, b'3', b'0', b'2']);
+        assert_eq!(block.last(), Some(&0x88));
+        assert_eq!(
+            Decoder::new(0).decode(&block, MAX).unwrap().unwrap(),
+            pairs(&headers)
+        );
+    }
+}
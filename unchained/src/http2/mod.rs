@@ -0,0 +1,989 @@
+//! HTTP/2, enabled with the `http2` cargo feature. See https://www.rfc-editor.org/rfc/rfc9113
+//!
+//! The [Server](crate::server::Server) speaks HTTP/2 on plain connections starting with the
+//! connection preface (h2c with prior knowledge) and on TLS connections negotiating `h2`
+//! with ALPN. Other connections keep using HTTP/1.1.
+//! Requests are answered by the same routes and middleware, with `HTTP/2` as [Request::version].
+//!
+//! Requests of a connection are received in parallel, and each response is sent
+//! once its request is complete. Request bodies are read before the route responds,
+//! also for [ResponseContent::Upload](crate::router::ResponseContent::Upload) routes.
+//! Server push and upgrading HTTP/1.1 connections with `Upgrade: h2c` are not supported.
+//!
+//! ```no_run
+//! use unchained_web::server::Server;
+//!
+//! // Try it with `curl --http2-prior-knowledge localhost:8080`
+//! Server::new(vec![]).listen().unwrap();
+//! ```
+
+pub mod hpack;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufWriter, ErrorKind, Read, Write},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use crate::{
+    error::{Error, WebResult},
+    logging::{AccessLog, Level},
+    router::{Request, Response},
+    server::ServerOptions,
+};
+
+/// Sent by clients before their first frame
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Initial flow control window of connections and streams
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Largest frame payload before a peer raises it with `SETTINGS_MAX_FRAME_SIZE`
+const DEFAULT_FRAME_SIZE: usize = 16_384;
+const HEADER_TABLE_SIZE: usize = 4096;
+/// Streams a client may have open at once
+const MAX_STREAMS: usize = 100;
+
+/// Header fields that only apply to HTTP/1.1 connections
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Whether the first bytes of a connection are the HTTP/2 preface.
+/// At least `PR` is needed so a partially received `POST` or `PUT` is not mistaken for it.
+pub(crate) fn starts_with_preface(buffered: &[u8]) -> bool {
+    let length = buffered.len().min(PREFACE.len());
+    length >= 2 && PREFACE.starts_with(&buffered[..length])
+}
+
+/// Reasons a connection or stream is aborted
+#[derive(Debug)]
+enum H2Error {
+    Io(std::io::Error),
+    /// Closes the connection with `GOAWAY`
+    Connection(u32, String),
+    /// Closes the stream with `RST_STREAM`
+    Stream(u32, u32),
+}
+
+impl From<std::io::Error> for H2Error {
+    fn from(value: std::io::Error) -> Self {
+        H2Error::Io(value)
+    }
+}
+
+fn protocol_error(message: &str) -> H2Error {
+    H2Error::Connection(PROTOCOL_ERROR, message.to_string())
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Payload without the padding of `PADDED` frames
+    fn unpadded(&self) -> Result<&[u8], H2Error> {
+        if self.flags & PADDED == 0 {
+            return Ok(&self.payload);
+        }
+        match self.payload.split_first() {
+            Some((&padding, rest)) if (padding as usize) <= rest.len() => {
+                Ok(&rest[..rest.len() - padding as usize])
+            }
+            _ => Err(protocol_error("Padding longer than the frame")),
+        }
+    }
+}
+
+/// Read a frame, or `None` if the connection was closed before it
+fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> Result<Option<Frame>, H2Error> {
+    let mut header = [0; 9];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if length > max_size {
+        return Err(H2Error::Connection(
+            FRAME_SIZE_ERROR,
+            format!("Frame of {} bytes", length),
+        ));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+fn write_frame<W: Write>(
+    writer: &mut W,
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let length = (payload.len() as u32).to_be_bytes();
+    writer.write_all(&length[1..])?;
+    writer.write_all(&[kind, flags])?;
+    writer.write_all(&stream.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// A request being received
+struct Incoming {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// Status of an error response sent instead of calling the routes
+    error_status: Option<u32>,
+    /// Whether the client is still sending when the response is sent
+    open: bool,
+}
+
+struct Connection<'a, R: Read, W: Write> {
+    reader: R,
+    writer: BufWriter<W>,
+    options: &'a ServerOptions,
+    /// Address of the client, for access logs
+    client: Option<SocketAddr>,
+    decoder: hpack::Decoder,
+    /// Streams still receiving their request
+    streams: HashMap<u32, Incoming>,
+    /// Complete requests in the order they were received
+    ready: VecDeque<(u32, Incoming)>,
+    /// Highest stream opened by the client
+    last_stream: u32,
+    /// Stream, flags and start of a header block continued by `CONTINUATION` frames
+    continuation: Option<(u32, u8, Vec<u8>)>,
+    /// Bytes the client accepts on the connection
+    send_window: i64,
+    /// Bytes the client accepts on each stream that is not answered yet
+    stream_windows: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    going_away: bool,
+}
+
+impl<'a, R: Read, W: Write> Connection<'a, R, W> {
+    fn new(reader: R, writer: W, options: &'a ServerOptions, client: Option<SocketAddr>) -> Self {
+        Connection {
+            reader,
+            writer: BufWriter::new(writer),
+            options,
+            client,
+            decoder: hpack::Decoder::new(HEADER_TABLE_SIZE),
+            streams: HashMap::new(),
+            ready: VecDeque::new(),
+            last_stream: 0,
+            continuation: None,
+            send_window: DEFAULT_WINDOW,
+            stream_windows: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_FRAME_SIZE,
+            going_away: false,
+        }
+    }
+
+    fn run(&mut self, respond: &impl Fn(Request) -> Response) -> Result<(), H2Error> {
+        let mut preface = [0; PREFACE.len()];
+        self.reader.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(protocol_error("Invalid connection preface"));
+        }
+        let settings = [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                self.options.limits.max_header_size as u32,
+            ),
+        ]
+        .iter()
+        .flat_map(|(id, value)| [&id.to_be_bytes()[..], &value.to_be_bytes()].concat())
+        .collect::<Vec<_>>();
+        write_frame(&mut self.writer, SETTINGS, 0, 0, &settings)?;
+        self.writer.flush()?;
+
+        loop {
+            while let Some((id, incoming)) = self.ready.pop_front() {
+                self.respond(id, incoming, respond)?;
+            }
+            if (self.going_away && self.streams.is_empty()) || !self.receive()? {
+                break;
+            }
+        }
+        self.go_away(NO_ERROR)
+    }
+
+    /// Read and handle the next frame. Returns false when the client closed the connection.
+    fn receive(&mut self) -> Result<bool, H2Error> {
+        let Some(frame) = read_frame(&mut self.reader, DEFAULT_FRAME_SIZE)? else {
+            return Ok(false);
+        };
+        match self.handle_frame(frame) {
+            Err(H2Error::Stream(id, code)) => self.reset(id, code)?,
+            result => result?,
+        }
+        Ok(true)
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if let Some((id, ..)) = self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != id {
+                return Err(protocol_error("Expected CONTINUATION"));
+            }
+        }
+        match frame.kind {
+            DATA => self.on_data(frame),
+            HEADERS => self.on_headers(frame),
+            CONTINUATION => {
+                let Some((id, flags, mut block)) = self.continuation.take() else {
+                    return Err(protocol_error("Unexpected CONTINUATION"));
+                };
+                block.extend_from_slice(&frame.payload);
+                if block.len() > 2 * self.options.limits.max_header_size {
+                    return Err(H2Error::Connection(
+                        ENHANCE_YOUR_CALM,
+                        "Header block too large".to_string(),
+                    ));
+                }
+                match frame.flags & END_HEADERS != 0 {
+                    true => self.on_header_block(id, flags, block),
+                    false => {
+                        self.continuation = Some((id, flags, block));
+                        Ok(())
+                    }
+                }
+            }
+            RST_STREAM => {
+                if frame.stream == 0 || frame.payload.len() != 4 {
+                    return Err(protocol_error("Invalid RST_STREAM"));
+                }
+                self.close(frame.stream);
+                Ok(())
+            }
+            SETTINGS => self.on_settings(frame),
+            PING => {
+                if frame.stream != 0 || frame.payload.len() != 8 {
+                    return Err(protocol_error("Invalid PING"));
+                }
+                if frame.flags & ACK == 0 {
+                    write_frame(&mut self.writer, PING, ACK, 0, &frame.payload)?;
+                    self.writer.flush()?;
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(frame),
+            PUSH_PROMISE => Err(protocol_error("Clients cannot push")),
+            // Priorities are not used
+            PRIORITY => Ok(()),
+            // Unknown frames are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream == 0 || frame.stream.is_multiple_of(2) {
+            return Err(protocol_error("HEADERS on a stream the client cannot open"));
+        }
+        let mut block = frame.unpadded()?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            block = block
+                .get(5..)
+                .ok_or_else(|| protocol_error("Invalid HEADERS"))?;
+        }
+        let block = block.to_vec();
+        match frame.flags & END_HEADERS != 0 {
+            true => self.on_header_block(frame.stream, frame.flags, block),
+            false => {
+                self.continuation = Some((frame.stream, frame.flags, block));
+                Ok(())
+            }
+        }
+    }
+
+    fn on_header_block(&mut self, id: u32, flags: u8, block: Vec<u8>) -> Result<(), H2Error> {
+        // Every block changes the decoder's table, even for streams that are refused
+        let limits = &self.options.limits;
+        let headers = self
+            .decoder
+            .decode(&block, limits.max_header_size)
+            .map_err(|e| {
+                H2Error::Connection(
+                    COMPRESSION_ERROR,
+                    format!("Could not decode headers {:?}", e),
+                )
+            })?;
+        let end_stream = flags & END_STREAM != 0;
+        if let Some(mut incoming) = self.streams.remove(&id) {
+            // Trailers are accepted but not passed to the routes
+            if !end_stream {
+                return Err(H2Error::Stream(id, PROTOCOL_ERROR));
+            }
+            incoming.open = false;
+            self.ready.push_back((id, incoming));
+            return Ok(());
+        }
+        if id <= self.last_stream {
+            return Err(H2Error::Connection(
+                STREAM_CLOSED,
+                format!("HEADERS on closed stream {}", id),
+            ));
+        }
+        self.last_stream = id;
+        if self.streams.len() + self.ready.len() >= MAX_STREAMS {
+            return Err(H2Error::Stream(id, REFUSED_STREAM));
+        }
+        let too_large = headers
+            .as_ref()
+            .is_none_or(|headers| headers.len() > limits.max_headers);
+        let incoming = Incoming {
+            headers: headers.unwrap_or_default(),
+            body: Vec::new(),
+            error_status: too_large.then_some(431),
+            open: !end_stream,
+        };
+        self.stream_windows.insert(id, self.initial_window);
+        match end_stream || too_large {
+            true => self.ready.push_back((id, incoming)),
+            false => {
+                self.streams.insert(id, incoming);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), H2Error> {
+        let id = frame.stream;
+        if id == 0 || id > self.last_stream {
+            return Err(protocol_error("DATA on an idle stream"));
+        }
+        // Flow control counts the whole payload, so the windows are restored right away
+        let length = frame.payload.len() as u32;
+        if length > 0 {
+            write_frame(&mut self.writer, WINDOW_UPDATE, 0, 0, &length.to_be_bytes())?;
+        }
+        let data = frame.unpadded()?;
+        // Data of streams that were answered early or reset is dropped
+        let Some(incoming) = self.streams.get_mut(&id) else {
+            return Ok(());
+        };
+        if incoming.body.len() + data.len() > self.options.limits.max_body_size {
+            let mut incoming = self.streams.remove(&id).unwrap();
+            incoming.error_status = Some(413);
+            incoming.body = Vec::new();
+            incoming.open = frame.flags & END_STREAM == 0;
+            self.ready.push_back((id, incoming));
+            return Ok(());
+        }
+        incoming.body.extend_from_slice(data);
+        match frame.flags & END_STREAM != 0 {
+            true => {
+                let mut incoming = self.streams.remove(&id).unwrap();
+                incoming.open = false;
+                self.ready.push_back((id, incoming));
+            }
+            false if length > 0 => {
+                write_frame(
+                    &mut self.writer,
+                    WINDOW_UPDATE,
+                    0,
+                    id,
+                    &length.to_be_bytes(),
+                )?;
+            }
+            false => {}
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream != 0 {
+            return Err(protocol_error("SETTINGS on a stream"));
+        }
+        if frame.flags & ACK != 0 {
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(H2Error::Connection(
+                FRAME_SIZE_ERROR,
+                "Invalid SETTINGS".to_string(),
+            ));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(protocol_error("Invalid SETTINGS_ENABLE_PUSH"))
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(H2Error::Connection(
+                            FLOW_CONTROL_ERROR,
+                            "Initial window too large".to_string(),
+                        ));
+                    }
+                    for window in self.stream_windows.values_mut() {
+                        *window += value - self.initial_window;
+                    }
+                    self.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_FRAME_SIZE..1 << 24).contains(&(value as usize)) {
+                        return Err(protocol_error("Invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // The encoder does not use the dynamic table
+                _ => {}
+            }
+        }
+        write_frame(&mut self.writer, SETTINGS, ACK, 0, &[])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), H2Error> {
+        let Ok(increment) = <[u8; 4]>::try_from(frame.payload.as_slice()) else {
+            return Err(H2Error::Connection(
+                FRAME_SIZE_ERROR,
+                "Invalid WINDOW_UPDATE".to_string(),
+            ));
+        };
+        let increment = (u32::from_be_bytes(increment) & 0x7fff_ffff) as i64;
+        if frame.stream == 0 {
+            self.send_window += increment;
+            if increment == 0 || self.send_window > MAX_WINDOW {
+                return Err(H2Error::Connection(
+                    FLOW_CONTROL_ERROR,
+                    "Invalid connection window".to_string(),
+                ));
+            }
+        } else if let Some(window) = self.stream_windows.get_mut(&frame.stream) {
+            *window += increment;
+            if increment == 0 || *window > MAX_WINDOW {
+                return Err(H2Error::Stream(frame.stream, FLOW_CONTROL_ERROR));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget a stream closed by the client or the server
+    fn close(&mut self, id: u32) {
+        self.streams.remove(&id);
+        self.ready.retain(|(ready, _)| *ready != id);
+        self.stream_windows.remove(&id);
+    }
+
+    fn reset(&mut self, id: u32, code: u32) -> Result<(), H2Error> {
+        self.close(id);
+        write_frame(&mut self.writer, RST_STREAM, 0, id, &code.to_be_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn go_away(&mut self, code: u32) -> Result<(), H2Error> {
+        let payload = [self.last_stream.to_be_bytes(), code.to_be_bytes()].concat();
+        write_frame(&mut self.writer, GOAWAY, 0, 0, &payload)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        id: u32,
+        incoming: Incoming,
+        respond: &impl Fn(Request) -> Response,
+    ) -> Result<(), H2Error> {
+        let started = Instant::now();
+        let (response, access) = match incoming.error_status {
+            Some(status) => (Response::new(None, status), None),
+            None => match to_request(incoming.headers, incoming.body) {
+                Ok(request) => {
+                    let access = AccessLog::new(&request, self.client);
+                    (respond(request), Some(access))
+                }
+                Err(message) => (Response::new(Some(message), 400), None),
+            },
+        };
+        let status = response.status_code;
+        let (sent, bytes) = self.send_response(id, response)?;
+        if sent && incoming.open {
+            // The rest of the request is not needed
+            self.reset(id, NO_ERROR)?;
+        }
+        self.stream_windows.remove(&id);
+        self.writer.flush()?;
+        if let Some(mut access) = access {
+            access.status = status;
+            access.bytes = bytes;
+            access.duration = started.elapsed();
+            self.options.logger.access(&access);
+        }
+        Ok(())
+    }
+
+    /// Send the headers and body of a response.
+    /// Returns false if the client reset the stream before it was sent,
+    /// and the bytes of the body that were sent.
+    fn send_response(&mut self, id: u32, mut response: Response) -> Result<(bool, usize), H2Error> {
+        let bytes = response.bytes.take().unwrap_or_default();
+        let stream_body = response.stream.take();
+        let has_header = |response: &Response, name: &str| {
+            response
+                .headers
+                .keys()
+                .any(|k| k.eq_ignore_ascii_case(name))
+        };
+        if stream_body.is_none()
+            // Responses to HEAD requests keep the length of the GET response
+            && !has_header(&response, "Content-Length")
+            && !matches!(response.status_code, 100..=199 | 204 | 304)
+        {
+            response
+                .headers
+                .insert("content-length".into(), bytes.len().to_string());
+        }
+        let status = response.status_code.to_string();
+        let headers = self
+            .options
+            .default_headers
+            .iter()
+            .filter(|(k, _)| !has_header(&response, k))
+            .chain(response.headers.iter())
+            .map(|(k, v)| (k.to_ascii_lowercase(), v.as_str()))
+            .filter(|(k, _)| !CONNECTION_HEADERS.contains(&k.as_str()))
+            .collect::<Vec<_>>();
+        let fields = [(":status", status.as_str())]
+            .into_iter()
+            .chain(headers.iter().map(|(k, v)| (k.as_str(), *v)))
+            .collect::<Vec<_>>();
+        let block = hpack::encode(&fields);
+
+        let ends = bytes.is_empty() && stream_body.is_none();
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let mut flags = if chunks.peek().is_none() {
+                END_HEADERS
+            } else {
+                0
+            };
+            if kind == HEADERS && ends {
+                flags |= END_STREAM;
+            }
+            write_frame(&mut self.writer, kind, flags, id, chunk)?;
+            kind = CONTINUATION;
+        }
+        if !bytes.is_empty() && !self.send_data(id, &bytes, stream_body.is_none())? {
+            return Ok((false, 0));
+        }
+        let Some(write_body) = stream_body else {
+            return Ok((true, bytes.len()));
+        };
+        let mut writer = BufWriter::with_capacity(
+            self.max_frame_size,
+            DataWriter {
+                connection: self,
+                stream: id,
+                written: 0,
+                error: None,
+            },
+        );
+        let result = write_body(&mut writer).and_then(|_| Ok(writer.flush()?));
+        let (DataWriter { written, error, .. }, _) = writer.into_parts();
+        let written = bytes.len() + written;
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if !self.stream_windows.contains_key(&id) {
+            return Ok((false, written));
+        }
+        match result {
+            Ok(()) => Ok((self.send_data(id, &[], true)?, written)),
+            Err(e) => {
+                self.options.logger.log(
+                    Level::Warn,
+                    format_args!("Could not write response body. {:?}", e),
+                );
+                self.reset(id, INTERNAL_ERROR)?;
+                Ok((false, written))
+            }
+        }
+    }
+
+    /// Send data as far as the flow control windows allow, reading frames while they are full.
+    /// Returns false if the client reset the stream.
+    fn send_data(&mut self, id: u32, mut data: &[u8], end_stream: bool) -> Result<bool, H2Error> {
+        loop {
+            let Some(&stream_window) = self.stream_windows.get(&id) else {
+                return Ok(false);
+            };
+            let window = self.send_window.min(stream_window).max(0) as usize;
+            let size = data.len().min(self.max_frame_size).min(window);
+            if size == 0 && !data.is_empty() {
+                self.writer.flush()?;
+                if !self.receive()? {
+                    return Err(H2Error::Io(ErrorKind::UnexpectedEof.into()));
+                }
+                continue;
+            }
+            let (chunk, rest) = data.split_at(size);
+            let flags = if rest.is_empty() && end_stream {
+                END_STREAM
+            } else {
+                0
+            };
+            write_frame(&mut self.writer, DATA, flags, id, chunk)?;
+            self.send_window -= size as i64;
+            self.stream_windows.insert(id, stream_window - size as i64);
+            data = rest;
+            if data.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Writes a streamed response body as `DATA` frames
+struct DataWriter<'c, 'a, R: Read, W: Write> {
+    connection: &'c mut Connection<'a, R, W>,
+    stream: u32,
+    /// Bytes sent in `DATA` frames
+    written: usize,
+    /// Error that closes the connection
+    error: Option<H2Error>,
+}
+
+impl<R: Read, W: Write> Write for DataWriter<'_, '_, R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.connection.send_data(self.stream, buf, false) {
+            Ok(true) => {
+                self.written += buf.len();
+                Ok(buf.len())
+            }
+            Ok(false) => Err(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "Stream reset by the client",
+            )),
+            Err(e) => {
+                self.error = Some(e);
+                Err(std::io::Error::other("HTTP/2 connection failed"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.connection.writer.flush()
+    }
+}
+
+/// Build a request from the decoded header fields.
+/// Returns a message for malformed requests.
+fn to_request(fields: Vec<(String, String)>, body: Vec<u8>) -> Result<Request, String> {
+    let mut pseudo = HashMap::new();
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in fields {
+        if let Some(pseudo_name) = name.strip_prefix(':') {
+            if !headers.is_empty() {
+                return Err(format!("Pseudo-header {} after headers", name));
+            }
+            if !["method", "scheme", "path", "authority"].contains(&pseudo_name)
+                || pseudo.insert(pseudo_name.to_string(), value).is_some()
+            {
+                return Err(format!("Invalid pseudo-header {}", name));
+            }
+            continue;
+        }
+        if name.is_empty() || name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err(format!("Invalid header name {}", name));
+        }
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err(format!("Connection-specific header {}", name));
+        }
+        // Cookies may be split into several fields
+        let separator = if name == "cookie" { "; " } else { ", " };
+        headers
+            .entry(name)
+            .and_modify(|v| *v = format!("{}{}{}", v, separator, value))
+            .or_insert(value);
+    }
+    let (Some(method), Some(path)) = (pseudo.get("method"), pseudo.get("path")) else {
+        return Err("Missing :method or :path".to_string());
+    };
+    if !(path.starts_with('/') || (method == "OPTIONS" && path == "*")) {
+        return Err(format!("Invalid path {}", path));
+    }
+    if let Some(authority) = pseudo.get("authority") {
+        headers
+            .entry("host".to_string())
+            .or_insert(authority.clone());
+    }
+    let mut request = Request::new(method, path);
+    request.version = "HTTP/2".to_string();
+    request.headers = headers;
+    request.body = (!body.is_empty()).then_some(body);
+    Ok(request)
+}
+
+/// Answer the requests of an HTTP/2 connection until it closes.
+/// Each answered request is written to the access log once its response is sent.
+pub(crate) fn serve<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: &ServerOptions,
+    client: Option<SocketAddr>,
+    respond: impl Fn(Request) -> Response,
+) -> WebResult<()> {
+    let mut connection = Connection::new(reader, writer, options, client);
+    match connection.run(&respond) {
+        Ok(()) => Ok(()),
+        Err(H2Error::Io(e))
+            if matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::UnexpectedEof
+            ) =>
+        {
+            // Idle connections are closed like HTTP/1.1 connections
+            _ = connection.go_away(NO_ERROR);
+            Ok(())
+        }
+        Err(H2Error::Io(e)) => Err(Error::Connection(format!(
+            "Could not use HTTP/2 stream. {}",
+            e
+        ))),
+        Err(H2Error::Connection(code, message)) => {
+            _ = connection.go_away(code);
+            Err(Error::Connection(format!("HTTP/2 error. {}", message)))
+        }
+        Err(H2Error::Stream(id, code)) => {
+            _ = connection.reset(id, code);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, kind, flags, stream, payload).unwrap();
+        bytes
+    }
+
+    fn request_headers(stream: u32, path: &str, end_stream: bool) -> Vec<u8> {
+        let block = hpack::encode(&[
+            (":method", if end_stream { "GET" } else { "POST" }),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+            ("cookie", "a=1"),
+            ("cookie", "b=2"),
+        ]);
+        let flags = END_HEADERS | if end_stream { END_STREAM } else { 0 };
+        frame(HEADERS, flags, stream, &block)
+    }
+
+    /// Headers and body sent on each stream
+    type Responses = HashMap<u32, (Vec<(String, String)>, Vec<u8>)>;
+
+    /// Run a connection with the input and decode the responses and the error code of GOAWAY
+    fn exchange(input: &[u8], options: &ServerOptions) -> (Responses, Option<u32>) {
+        let mut output = Vec::new();
+        _ = serve(input, &mut output, options, None, |request| {
+            if request.path == "/stream" {
+                return Response::new_stream(200, |writer| Ok(writer.write_all(&[b'x'; 70_000])?));
+            }
+            let body = request.body.clone().unwrap_or_default();
+            let mut response = Response::new_200(format!(
+                "{} {} {} {} {}",
+                request.version,
+                request.path,
+                request.header("host").unwrap_or_default(),
+                request.header("cookie").unwrap_or_default(),
+                body.len()
+            ));
+            response.add_header("Connection", "keep-alive");
+            response
+        });
+        let mut decoder = hpack::Decoder::new(HEADER_TABLE_SIZE);
+        let mut responses = Responses::new();
+        let mut goaway = None;
+        let mut output = output.as_slice();
+        while let Some(frame) = read_frame(&mut output, 1 << 24).unwrap() {
+            let response = responses.entry(frame.stream).or_default();
+            match frame.kind {
+                HEADERS => {
+                    response.0 = decoder.decode(&frame.payload, usize::MAX).unwrap().unwrap()
+                }
+                DATA => response.1.extend(frame.payload),
+                GOAWAY => {
+                    goaway = Some(u32::from_be_bytes(frame.payload[4..8].try_into().unwrap()))
+                }
+                _ => {}
+            }
+        }
+        (responses, goaway)
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_preface() {
+        assert!(starts_with_preface(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0"));
+        assert!(starts_with_preface(b"PRI *"));
+        assert!(!starts_with_preface(b"P"));
+        assert!(!starts_with_preface(b"POST / HTTP/1.1\r\n"));
+        assert!(!starts_with_preface(b"PRI / HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_requests() {
+        /// Keeps the path and bytes of each access log
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+
+        impl crate::logging::Logger for Recorder {
+            fn log(&self, _: Level, _: std::fmt::Arguments) {}
+
+            fn access(&self, entry: &AccessLog) {
+                let line = format!("{} {} {}", entry.target, entry.status, entry.bytes);
+                self.0.lock().unwrap().push(line);
+            }
+        }
+
+        let recorder = std::sync::Arc::new(Recorder::default());
+        let mut options = Server::new(vec![]).options;
+        options.logger = recorder.clone();
+        options
+            .default_headers
+            .insert("Server".to_string(), "unchained".to_string());
+        // Larger windows for the streamed body
+        let settings = [
+            &SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes()[..],
+            &100_000u32.to_be_bytes(),
+        ]
+        .concat();
+        let input = [
+            PREFACE,
+            &frame(SETTINGS, 0, 0, &settings),
+            &frame(WINDOW_UPDATE, 0, 0, &100_000u32.to_be_bytes()),
+            &request_headers(1, "/a?b=c", true),
+            &request_headers(3, "/upload", false),
+            &frame(PING, 0, 0, b"pingpong"),
+            &frame(DATA, 0, 3, b"abc"),
+            &frame(DATA, END_STREAM, 3, b"de"),
+            &request_headers(5, "/stream", true),
+            &frame(GOAWAY, 0, 0, &[0; 8]),
+        ]
+        .concat();
+        let (responses, goaway) = exchange(&input, &options);
+        assert_eq!(goaway, Some(NO_ERROR));
+
+        let (headers, body) = &responses[&1];
+        assert_eq!(header(headers, ":status"), Some("200"));
+        assert_eq!(header(headers, "server"), Some("unchained"));
+        assert_eq!(body, b"HTTP/2 /a localhost a=1; b=2 0");
+        assert_eq!(header(headers, "content-length"), Some("30"));
+        assert_eq!(header(headers, "connection"), None);
+        assert_eq!(responses[&3].1, b"HTTP/2 /upload localhost a=1; b=2 5");
+        let (headers, body) = &responses[&5];
+        assert_eq!(header(headers, "content-length"), None);
+        assert_eq!(body.len(), 70_000);
+        // Streamed bodies are counted as they are sent
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["/a?b=c 200 30", "/upload 200 35", "/stream 200 70000"]
+        );
+    }
+
+    #[test]
+    fn test_limits_and_errors() {
+        let mut options = Server::new(vec![]).options;
+        options.limits.max_body_size = 4;
+        let input = [
+            PREFACE,
+            &request_headers(1, "/upload", false),
+            &frame(DATA, 0, 1, b"abcde"),
+            &request_headers(3, "relative", true),
+        ]
+        .concat();
+        let (responses, goaway) = exchange(&input, &options);
+        assert_eq!(header(&responses[&1].0, ":status"), Some("413"));
+        assert_eq!(header(&responses[&3].0, ":status"), Some("400"));
+        assert_eq!(goaway, Some(NO_ERROR));
+
+        let input = [PREFACE, &request_headers(2, "/", true)].concat();
+        assert_eq!(exchange(&input, &options).1, Some(PROTOCOL_ERROR));
+        let input = [PREFACE, &frame(HEADERS, END_HEADERS, 1, &[0xff])].concat();
+        assert_eq!(exchange(&input, &options).1, Some(COMPRESSION_ERROR));
+        let input = b"PRI * HTTP/1.1\r\n\r\n\r\n\r\n\r\n";
+        assert_eq!(exchange(input, &options).1, Some(PROTOCOL_ERROR));
+    }
+}
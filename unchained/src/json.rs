@@ -0,0 +1,251 @@
+//! Parse JSON into a [ContextTree], e.g. to render a request body.
+//!
+//! Objects become branches and arrays become arrays.
+//! Numbers that are not integers are kept as strings and `null` becomes `false`.
+//!
+//! ```
+//! use unchained_web::json::parse_json;
+//!
+//! let tree = parse_json(r#"{"name": "Ada", "tags": ["math", "code"]}"#).unwrap();
+//! assert_eq!(tree.get_from_branch("name").unwrap().to_string(), "Ada");
+//! ```
+
+use std::{collections::HashMap, iter::Peekable, str::CharIndices};
+
+use crate::{
+    error::{Error, WebResult},
+    templates::context::{ContextTree, Primitive},
+};
+
+/// Nested arrays and objects deeper than this are rejected
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn error(&mut self, expected: &str) -> Error {
+        let position = self.chars.peek().map_or(self.input.len(), |(i, _)| *i);
+        Error::InvalidParams(format!(
+            "Invalid JSON. Expected {} at byte {}",
+            expected, position
+        ))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .next_if(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+    }
+
+    fn expect(&mut self, expected: char) -> WebResult<()> {
+        self.skip_whitespace();
+        match self.chars.next_if(|(_, c)| *c == expected) {
+            Some(_) => Ok(()),
+            None => Err(self.error(&format!("'{}'", expected))),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: ContextTree) -> WebResult<ContextTree> {
+        for expected in word.chars() {
+            if self.chars.next_if(|(_, c)| *c == expected).is_none() {
+                return Err(self.error(word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> WebResult<ContextTree> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("less nesting"));
+        }
+        self.skip_whitespace();
+        match self.chars.peek().map(|(_, c)| *c) {
+            Some('{') => self.object(depth),
+            Some('[') => self.array(depth),
+            Some('"') => Ok(ContextTree::Leaf(Primitive::Str(self.string()?))),
+            Some('t') => self.keyword("true", true.into()),
+            Some('f') => self.keyword("false", false.into()),
+            Some('n') => self.keyword("null", false.into()),
+            Some('-' | '0'..='9') => self.number(),
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> WebResult<ContextTree> {
+        self.expect('{')?;
+        let mut map = HashMap::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+            return Ok(map.into());
+        }
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek().map(|(_, c)| *c) != Some('"') {
+                return Err(self.error("a key"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            map.insert(key, self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, '}')) => return Ok(map.into()),
+                _ => return Err(self.error("',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> WebResult<ContextTree> {
+        self.expect('[')?;
+        let mut items: Vec<ContextTree> = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+            return Ok(items.into());
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, ']')) => return Ok(items.into()),
+                _ => return Err(self.error("',' or ']'")),
+            }
+        }
+    }
+
+    fn hex_escape(&mut self) -> WebResult<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|(_, c)| c.to_digit(16))
+                .ok_or_else(|| self.error("a hex digit"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> WebResult<String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(string),
+                Some((_, '\\')) => {
+                    let escaped = match self.chars.next().map(|(_, c)| c) {
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut code = self.hex_escape()?;
+                            // Characters outside the basic plane are written as surrogate pairs
+                            if (0xD800..0xDC00).contains(&code) {
+                                self.expect('\\')?;
+                                self.expect('u')?;
+                                let low = self.hex_escape()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("a low surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code).ok_or_else(|| self.error("a valid character"))?
+                        }
+                        _ => return Err(self.error("an escape sequence")),
+                    };
+                    string.push(escaped);
+                }
+                Some((_, c)) if c.is_control() => return Err(self.error("'\"'")),
+                Some((_, c)) => string.push(c),
+                None => return Err(self.error("'\"'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> WebResult<ContextTree> {
+        let start = self.chars.peek().map_or(self.input.len(), |(i, _)| *i);
+        while self
+            .chars
+            .next_if(|(_, c)| matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+            .is_some()
+        {}
+        let end = self.chars.peek().map_or(self.input.len(), |(i, _)| *i);
+        let literal = &self.input[start..end];
+        if let Ok(n) = literal.parse::<isize>() {
+            return Ok(n.into());
+        }
+        match literal.parse::<f64>() {
+            Ok(_) => Ok(literal.into()),
+            Err(_) => Err(self.error("a number")),
+        }
+    }
+}
+
+/// Parse a JSON document into a [ContextTree]
+pub fn parse_json(input: &str) -> WebResult<ContextTree> {
+    let mut parser = Parser {
+        input,
+        chars: input.char_indices().peekable(),
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        None => Ok(value),
+        Some(_) => Err(parser.error("the end of the document")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let tree = parse_json(
+            r#" {"title": "Caf\u00e9 \"Ünchained\"\n", "count": -12, "ratio": 1.5e2,
+                "tags": ["a", true, null, {}], "nested": {"empty": []}, "emoji": "\ud83d\ude00"} "#,
+        )
+        .unwrap();
+        let get = |key: &str| tree.get_from_branch(key).unwrap().to_string();
+        assert_eq!(get("title"), "Café \"Ünchained\"\n");
+        assert_eq!(get("count"), "-12");
+        assert!(matches!(
+            tree.get_from_branch("count"),
+            Some(ContextTree::Leaf(Primitive::Num(-12)))
+        ));
+        assert_eq!(get("ratio"), "1.5e2");
+        assert_eq!(get("emoji"), "😀");
+        match tree.get_from_branch("tags") {
+            Some(ContextTree::Array(items)) => assert_eq!(items.len(), 4),
+            other => panic!("Expected array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let invalid = [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "\"unterminated",
+            "tru",
+            "1 2",
+            "--1",
+            "\"\\x\"",
+        ];
+        for input in invalid {
+            assert!(parse_json(input).is_err(), "{:?}", input);
+        }
+        assert!(parse_json(&"[".repeat(1000)).is_err());
+    }
+}
@@ -1,6 +1,25 @@
+pub mod cache;
+pub mod compression;
 pub mod error;
+pub mod export;
+pub mod feed;
+pub mod form;
+#[cfg(feature = "http2")]
+pub mod http2;
+pub mod json;
+pub mod lifecycle;
+pub mod listener;
+pub mod logging;
+pub mod middleware;
+pub mod pattern;
+pub mod range;
+pub mod route_tree;
 pub mod router;
 pub mod server;
+pub mod sitemap;
+pub mod static_files;
 pub mod templates;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod workers;
 pub mod zip_longest;
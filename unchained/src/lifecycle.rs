@@ -0,0 +1,357 @@
+//! Stopping a running [Server](crate::server::Server) gracefully.
+//!
+//! [Server::start](crate::server::Server::start) returns a [ServerHandle] without blocking.
+//! On shutdown the server stops accepting connections, closes idle keep-alive connections
+//! and lets requests in progress finish until the shutdown timeout, after which
+//! the remaining connections are closed.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use unchained_web::server::Server;
+//!
+//! let mut server = Server::new(vec![]);
+//! server.set_shutdown_timeout(Duration::from_secs(10));
+//! let handle = server.start().unwrap();
+//! // Stop on SIGTERM or Ctrl-C, like `Server::listen`
+//! handle.shutdown_on_signals();
+//! handle.wait();
+//! ```
+
+use std::{
+    collections::HashMap,
+    net::{Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::listener::BoundAddress;
+
+/// Controls a server started with [Server::start](crate::server::Server::start).
+/// Clones control the same server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    addresses: Vec<BoundAddress>,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(addresses: Vec<BoundAddress>, lifecycle: Arc<Lifecycle>) -> Self {
+        ServerHandle {
+            addresses,
+            lifecycle,
+        }
+    }
+
+    /// Shut down another server together with this one
+    #[cfg(feature = "tls")]
+    pub(crate) fn add_child(&self, child: ServerHandle) {
+        self.lifecycle.children.lock().unwrap().push(child);
+    }
+
+    /// Addresses the server is listening on, in the order they were added
+    pub fn addresses(&self) -> &[BoundAddress] {
+        &self.addresses
+    }
+
+    /// First TCP address the server is listening on, with the actual port when bound to port 0
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.addresses.iter().find_map(|address| match address {
+            BoundAddress::Tcp(address) => Some(*address),
+            #[cfg(unix)]
+            _ => None,
+        })
+    }
+
+    /// Stop accepting connections and wait for open ones to finish,
+    /// at most for the shutdown timeout of the server.
+    /// Returns when the server has stopped.
+    pub fn shutdown(&self) {
+        let lifecycle = &self.lifecycle;
+        if lifecycle.stopping.swap(true, Ordering::SeqCst) {
+            return self.wait();
+        }
+        // Connecting wakes the threads waiting for connections, which see it is stopping
+        let woken = self
+            .addresses
+            .iter()
+            .filter(|address| address.connect().is_err())
+            .count()
+            == 0;
+        lifecycle.close_idle();
+        let deadline = Instant::now() + lifecycle.shutdown_timeout;
+        let mut connections = lifecycle.connections.lock().unwrap();
+        let mut drained = true;
+        while !connections.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                for (shutdown, _) in connections.values() {
+                    shutdown(Shutdown::Both);
+                }
+                drained = false;
+                break;
+            }
+            connections = lifecycle
+                .changed
+                .wait_timeout(connections, timeout)
+                .unwrap()
+                .0;
+        }
+        drop(connections);
+
+        let children = std::mem::take(&mut *lifecycle.children.lock().unwrap());
+        for child in children {
+            child.shutdown();
+        }
+        let accepting = std::mem::take(&mut *lifecycle.accepting.lock().unwrap());
+        // Joining waits for the workers, which may still be running routes past the deadline,
+        // and forever for a thread that could not be woken
+        if woken && drained {
+            for thread in accepting {
+                let _ = thread.join();
+            }
+        }
+        *lifecycle.stopped.lock().unwrap() = true;
+        lifecycle.changed.notify_all();
+    }
+
+    /// Block until the server has been shut down
+    pub fn wait(&self) {
+        let mut stopped = self.lifecycle.stopped.lock().unwrap();
+        while !*stopped {
+            stopped = self.lifecycle.changed.wait(stopped).unwrap();
+        }
+    }
+
+    /// Shut down when the process receives `SIGTERM` or `SIGINT` (Ctrl-C).
+    /// Only supported on Unix, elsewhere the process ends without draining connections.
+    pub fn shutdown_on_signals(&self) {
+        #[cfg(unix)]
+        {
+            signals::install();
+            let handle = self.clone();
+            std::thread::spawn(move || {
+                while !handle.lifecycle.stopping.load(Ordering::SeqCst) {
+                    if signals::received() {
+                        handle.shutdown();
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            });
+        }
+    }
+}
+
+/// Shuts down the socket of a connection from another thread
+pub(crate) type ShutdownSocket = Box<dyn Fn(Shutdown) + Send>;
+
+/// State of a running server shared by its threads and handles
+pub(crate) struct Lifecycle {
+    stopping: AtomicBool,
+    /// Open connections, shutting down their socket, and whether they are waiting for a request
+    connections: Mutex<HashMap<u64, (ShutdownSocket, bool)>>,
+    next_id: AtomicU64,
+    /// Notified when a connection closes and when the server has stopped
+    changed: Condvar,
+    stopped: Mutex<bool>,
+    shutdown_timeout: Duration,
+    /// Threads accepting connections, the last of which joins the workers when it ends
+    pub(crate) accepting: Mutex<Vec<JoinHandle<()>>>,
+    /// Servers shut down with this one, like the redirect from HTTP to HTTPS
+    children: Mutex<Vec<ServerHandle>>,
+}
+
+impl Lifecycle {
+    pub(crate) fn new(shutdown_timeout: Duration) -> Self {
+        Lifecycle {
+            stopping: AtomicBool::new(false),
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            changed: Condvar::new(),
+            stopped: Mutex::new(false),
+            shutdown_timeout,
+            accepting: Mutex::new(Vec::new()),
+            children: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Track a new connection until the returned guard is dropped
+    pub(crate) fn register(self: &Arc<Self>, shutdown: ShutdownSocket) -> Connection {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, (shutdown, true));
+        Connection {
+            lifecycle: self.clone(),
+            id,
+        }
+    }
+
+    /// Close the reading half of connections waiting for a request, so they end right away
+    fn close_idle(&self) {
+        for (shutdown, idle) in self.connections.lock().unwrap().values() {
+            if *idle {
+                shutdown(Shutdown::Read);
+            }
+        }
+    }
+}
+
+/// A connection tracked by a [Lifecycle]
+pub(crate) struct Connection {
+    lifecycle: Arc<Lifecycle>,
+    id: u64,
+}
+
+impl Connection {
+    /// Mark whether the connection is waiting for a request.
+    /// Returns false if the server is stopping, so the connection should not wait for another.
+    pub(crate) fn set_idle(&self, idle: bool) -> bool {
+        let mut connections = self.lifecycle.connections.lock().unwrap();
+        if let Some(connection) = connections.get_mut(&self.id) {
+            connection.1 = idle;
+        }
+        !self.lifecycle.is_stopping()
+    }
+
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.lifecycle.is_stopping()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.lifecycle.connections.lock().unwrap().remove(&self.id);
+        self.lifecycle.changed.notify_all();
+    }
+}
+
+/// Handling of `SIGINT` and `SIGTERM` without dependencies
+#[cfg(unix)]
+mod signals {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    };
+
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    /// Default disposition, which ends the process
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    /// Sets a flag and restores the default disposition, which is all a signal handler can
+    /// safely do. A second signal ends the process while connections are drained.
+    extern "C" fn on_signal(signum: i32) {
+        RECEIVED.store(true, Ordering::SeqCst);
+        unsafe {
+            signal(signum, SIG_DFL);
+        }
+    }
+
+    pub(super) fn install() {
+        static INSTALL: Once = Once::new();
+        let handler = on_signal as extern "C" fn(i32) as usize;
+        INSTALL.call_once(|| unsafe {
+            signal(SIGINT, handler);
+            signal(SIGTERM, handler);
+        });
+    }
+
+    pub(super) fn received() -> bool {
+        RECEIVED.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        router::{HTTPVerb, Response, ResponseContent, Route},
+        server::Server,
+    };
+
+    fn start(shutdown_timeout: Duration) -> ServerHandle {
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/sleep/:ms",
+            ResponseContent::FromRequest(Box::new(|request| {
+                let ms = request.path_params["ms"].parse().unwrap();
+                thread::sleep(Duration::from_millis(ms));
+                Response::new_200("done".to_string())
+            })),
+        )];
+        let mut server = Server::new(routes);
+        server
+            .set_address("127.0.0.1:0")
+            .set_shutdown_timeout(shutdown_timeout);
+        server.start().unwrap()
+    }
+
+    fn get(address: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        stream
+    }
+
+    fn read_to_end(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let handle = start(Duration::from_secs(5));
+        let address = handle.address().unwrap();
+        let mut idle = get(address, "/sleep/0");
+        let mut buf = [0; 1024];
+        assert!(idle.read(&mut buf).unwrap() > 0);
+        let busy = get(address, "/sleep/300");
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        handle.shutdown();
+        // Neither the keep-alive timeout nor the shutdown timeout is waited for
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let response = read_to_end(busy);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("done"));
+        assert_eq!(idle.read(&mut buf).unwrap(), 0);
+        assert!(TcpStream::connect(address).is_err());
+        handle.wait();
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let handle = start(Duration::from_millis(100));
+        let busy = get(handle.address().unwrap(), "/sleep/2000");
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        handle.clone().shutdown();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(read_to_end(busy), "");
+    }
+}
@@ -0,0 +1,313 @@
+//! Sockets a [Server](crate::server::Server) accepts connections on.
+//!
+//! Addresses are given as strings to [Server::add_address](crate::server::Server::add_address):
+//! - `127.0.0.1:8080` or `[::]:8080` for TCP, where port 0 picks a free port
+//! - `unix:/run/site.sock` for a Unix domain socket, e.g. behind a reverse proxy.
+//!   Connections on it are never encrypted with TLS.
+//! - `systemd` for the sockets passed with systemd socket activation (`LISTEN_FDS`)
+//!
+//! ```
+//! use unchained_web::{listener::BoundAddress, server::Server};
+//!
+//! let mut server = Server::new(vec![]);
+//! server.set_address("127.0.0.1:0").add_address("[::1]:0");
+//! # if std::net::TcpListener::bind("[::1]:0").is_err() { return; }
+//! let handle = server.start().unwrap();
+//! assert!(matches!(handle.addresses()[1], BoundAddress::Tcp(address) if address.is_ipv6()));
+//! handle.shutdown();
+//! ```
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    error::{Error, WebResult},
+    lifecycle::ShutdownSocket,
+};
+
+/// Address a server is listening on
+#[derive(Clone, Debug, PartialEq)]
+pub enum BoundAddress {
+    /// TCP address with the actual port when bound to port 0
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl BoundAddress {
+    /// Connect to the address and close the connection right away
+    pub(crate) fn connect(&self) -> std::io::Result<()> {
+        match self {
+            BoundAddress::Tcp(address) => {
+                let mut address = *address;
+                // Listeners on `0.0.0.0` or `[::]` are reached on the loopback address
+                if address.ip().is_unspecified() {
+                    address.set_ip(match address {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                TcpStream::connect_timeout(&address, Duration::from_secs(1)).map(|_| ())
+            }
+            #[cfg(unix)]
+            BoundAddress::Unix(path) => UnixStream::connect(path).map(|_| ()),
+        }
+    }
+}
+
+/// A socket accepting connections
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed when the listener is dropped if the server created it
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+/// A connection accepted by a [Listener]
+pub(crate) enum Accepted {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Accepted {
+    /// Shuts down the socket from another thread
+    pub(crate) fn shutdown_socket(&self) -> std::io::Result<ShutdownSocket> {
+        Ok(match self {
+            Accepted::Tcp(stream) => {
+                let socket = stream.try_clone()?;
+                Box::new(move |how: Shutdown| {
+                    let _ = socket.shutdown(how);
+                })
+            }
+            #[cfg(unix)]
+            Accepted::Unix(stream) => {
+                let socket = stream.try_clone()?;
+                Box::new(move |how: Shutdown| {
+                    let _ = socket.shutdown(how);
+                })
+            }
+        })
+    }
+}
+
+fn bind_error(address: &str, e: std::io::Error) -> Error {
+    Error::Connection(format!("Could not bind to {}. {}", address, e))
+}
+
+impl Listener {
+    /// Bind to an address. `systemd` may give several listeners.
+    pub(crate) fn bind(address: &str) -> WebResult<Vec<Listener>> {
+        #[cfg(unix)]
+        {
+            if address == "systemd" {
+                return systemd_listeners();
+            }
+            if let Some(path) = address.strip_prefix("unix:") {
+                return Ok(vec![bind_unix(PathBuf::from(path))?]);
+            }
+        }
+        let listener = TcpListener::bind(address).map_err(|e| bind_error(address, e))?;
+        Ok(vec![Listener::Tcp(listener)])
+    }
+
+    pub(crate) fn address(&self) -> std::io::Result<BoundAddress> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(BoundAddress::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let address = listener.local_addr()?;
+                let path = address.as_pathname().unwrap_or("unnamed".as_ref());
+                Ok(BoundAddress::Unix(path.to_path_buf()))
+            }
+        }
+    }
+
+    pub(crate) fn accept(&self) -> std::io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Accepted::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Accepted::Unix(stream))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Bind a Unix socket, replacing a socket file left behind by a server that stopped
+#[cfg(unix)]
+fn bind_unix(path: PathBuf) -> WebResult<Listener> {
+    let display = format!("unix:{}", path.display());
+    let listener = match UnixListener::bind(&path) {
+        Err(e)
+            if e.kind() == std::io::ErrorKind::AddrInUse && UnixStream::connect(&path).is_err() =>
+        {
+            std::fs::remove_file(&path).map_err(|e| bind_error(&display, e))?;
+            UnixListener::bind(&path)
+        }
+        result => result,
+    };
+    let listener = listener.map_err(|e| bind_error(&display, e))?;
+    Ok(Listener::Unix(listener, Some(path)))
+}
+
+/// First file descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Set when the sockets passed by systemd are taken.
+/// The environment is left alone, because changing it is unsound while other threads run.
+#[cfg(unix)]
+static SYSTEMD_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// File descriptors passed to the process with `LISTEN_PID` and `LISTEN_FDS`
+#[cfg(unix)]
+fn systemd_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> WebResult<std::ops::Range<RawFd>> {
+    let missing = || Error::Connection("No sockets were passed by systemd".to_string());
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(pid) {
+        return Err(missing());
+    }
+    match listen_fds.and_then(|n| n.parse::<RawFd>().ok()) {
+        Some(count @ 1..) => Ok(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count),
+        _ => Err(missing()),
+    }
+}
+
+/// Take the sockets passed by systemd socket activation.
+/// See https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+#[cfg(unix)]
+fn systemd_listeners() -> WebResult<Vec<Listener>> {
+    let fds = systemd_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    // Each descriptor may only be owned by one listener
+    if SYSTEMD_TAKEN.swap(true, Ordering::SeqCst) {
+        return Err(Error::Connection(
+            "The sockets passed by systemd are already taken".to_string(),
+        ));
+    }
+    let listeners = fds
+        .map(|fd| {
+            // Safety: systemd passes these descriptors to this process for listening
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // Only the address of TCP sockets can be read as a socket address
+            match listener.local_addr() {
+                Ok(_) => Listener::Tcp(listener),
+                Err(_) => {
+                    let fd = std::os::fd::IntoRawFd::into_raw_fd(listener);
+                    Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }, None)
+                }
+            }
+        })
+        .collect();
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_systemd_fds() {
+        assert_eq!(systemd_fds(Some("42"), Some("2"), 42).unwrap(), 3..5);
+        assert!(systemd_fds(Some("41"), Some("2"), 42).is_err());
+        assert!(systemd_fds(None, Some("2"), 42).is_err());
+        assert!(systemd_fds(Some("42"), Some("0"), 42).is_err());
+        assert!(systemd_fds(Some("42"), None, 42).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("unchained-{}.sock", std::process::id()));
+        let listener = bind_unix(path.clone()).unwrap();
+        assert_eq!(
+            listener.address().unwrap(),
+            BoundAddress::Unix(path.clone())
+        );
+        // A socket in use is not replaced
+        assert!(bind_unix(path.clone()).is_err());
+        drop(listener);
+        assert!(!path.exists());
+
+        // A socket left behind is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(path.clone()).unwrap();
+        BoundAddress::Unix(path.clone()).connect().unwrap();
+        assert!(matches!(listener.accept().unwrap(), Accepted::Unix(_)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serve_unix_socket() {
+        use std::io::{Read, Write};
+
+        use crate::{
+            router::{HTTPVerb, ResponseContent, Route},
+            server::Server,
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("unchained-serve-{}.sock", std::process::id()));
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/",
+            ResponseContent::Str("local".to_string()),
+        )];
+        let mut server = Server::new(routes);
+        server
+            .set_address("127.0.0.1:0")
+            .add_address(&format!("unix:{}", path.display()));
+        let handle = server.start().unwrap();
+        assert_eq!(handle.addresses()[1], BoundAddress::Unix(path.clone()));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("local"));
+
+        handle.shutdown();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bind_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(Listener::bind(&address).is_err());
+        assert!(Listener::bind("not an address").is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Messages and access logs of a [Server](crate::server::Server).
+//!
+//! The server writes messages at a [Level] and one [AccessLog] per answered request
+//! to its [Logger]. The default [ConsoleLogger] writes access logs in the Common Log Format
+//! to stdout and messages at [Level::Info] and above to stderr.
+//! Request headers are only logged at [Level::Trace].
+//! With the `log` or `tracing` features, [LogFacade] or [TracingFacade] forward to those crates.
+//!
+//! ```
+//! use unchained_web::{
+//!     logging::{AccessLogFormat, ConsoleLogger, Level},
+//!     server::Server,
+//! };
+//!
+//! let mut server = Server::new(vec![]);
+//! server.set_logger(ConsoleLogger::new(Level::Warn, Some(AccessLogFormat::Json)));
+//! ```
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    cache::{time_of_day, unix_seconds, MONTHS},
+    router::Request,
+    templates::i18n::Date,
+};
+
+/// Importance of a message, from most to least important
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Format of access log lines
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`
+    Common,
+    /// The common format followed by the `Referer` and `User-Agent` headers
+    Combined,
+    /// One JSON object per line, including the duration in milliseconds
+    Json,
+}
+
+/// A request answered by the server
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    /// Address of the client, unknown for Unix sockets
+    pub client: Option<SocketAddr>,
+    /// When the request was received
+    pub time: SystemTime,
+    pub verb: String,
+    /// Path with the query string
+    pub target: String,
+    pub version: String,
+    pub status: u32,
+    /// Bytes of the response body
+    pub bytes: usize,
+    /// How long answering the request took
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessLog {
+    /// Entry for a request, to be completed when the response is written
+    pub fn new(request: &Request, client: Option<SocketAddr>) -> Self {
+        let target = match request.query.as_str() {
+            "" => request.path.clone(),
+            query => format!("{}?{}", request.path, query),
+        };
+        AccessLog {
+            client,
+            time: SystemTime::now(),
+            verb: request.verb.clone(),
+            target,
+            version: request.version.clone(),
+            status: 0,
+            bytes: 0,
+            duration: Duration::ZERO,
+            referer: request.header("Referer").map(str::to_string),
+            user_agent: request.header("User-Agent").map(str::to_string),
+        }
+    }
+
+    /// A line in the format, without a line break
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        let client = self
+            .client
+            .map_or("-".to_string(), |client| client.ip().to_string());
+        let seconds = unix_seconds(self.time);
+        let date = Date::from_timestamp(seconds as i64);
+        let (hours, minutes, seconds) = time_of_day(seconds);
+        if format == AccessLogFormat::Json {
+            let optional =
+                |value: &Option<String>| value.as_deref().map_or("null".to_string(), json_string);
+            let time = format!(
+                "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                date.year, date.month, date.day, hours, minutes, seconds
+            );
+            let fields = [
+                ("time", json_string(&time)),
+                ("client", json_string(&client)),
+                ("method", json_string(&self.verb)),
+                ("path", json_string(&self.target)),
+                ("version", json_string(&self.version)),
+                ("status", self.status.to_string()),
+                ("bytes", self.bytes.to_string()),
+                (
+                    "duration_ms",
+                    format!("{:.3}", self.duration.as_secs_f64() * 1000.0),
+                ),
+                ("referer", optional(&self.referer)),
+                ("user_agent", optional(&self.user_agent)),
+            ];
+            let fields = fields
+                .iter()
+                .map(|(name, value)| format!("\"{}\":{}", name, value))
+                .collect::<Vec<_>>()
+                .join(",");
+            return format!("{{{}}}", fields);
+        }
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let mut line = format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {}",
+            client,
+            date.day,
+            MONTHS[date.month as usize - 1],
+            date.year,
+            hours,
+            minutes,
+            seconds,
+            quoted(&self.verb),
+            quoted(&self.target),
+            quoted(&self.version),
+            self.status,
+            bytes
+        );
+        if format == AccessLogFormat::Combined {
+            let header = |value: &Option<String>| value.as_deref().map_or("-".to_string(), quoted);
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                header(&self.referer),
+                header(&self.user_agent)
+            ));
+        }
+        line
+    }
+}
+
+/// Escape quotes and control characters for a quoted field of the Common Log Format
+fn quoted(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+/// A JSON string with quotes
+fn json_string(value: &str) -> String {
+    let mut json = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Receives the messages and access logs of a server
+pub trait Logger: Send + Sync {
+    /// Write a message if its level is enabled
+    fn log(&self, level: Level, message: fmt::Arguments);
+
+    /// Write a request answered by the server
+    fn access(&self, entry: &AccessLog);
+}
+
+/// Writes messages to stderr and access logs to stdout
+#[derive(Clone, Debug)]
+pub struct ConsoleLogger {
+    /// Least important level of messages that are written
+    pub level: Level,
+    /// Format of access logs, or None to not write them
+    pub access_format: Option<AccessLogFormat>,
+}
+
+impl ConsoleLogger {
+    pub fn new(level: Level, access_format: Option<AccessLogFormat>) -> Self {
+        ConsoleLogger {
+            level,
+            access_format,
+        }
+    }
+}
+
+impl Default for ConsoleLogger {
+    fn default() -> Self {
+        ConsoleLogger::new(Level::Info, Some(AccessLogFormat::Common))
+    }
+}
+
+impl Logger for ConsoleLogger {
+    fn log(&self, level: Level, message: fmt::Arguments) {
+        if level <= self.level {
+            eprintln!("[{}] {}", level.as_str(), message);
+        }
+    }
+
+    fn access(&self, entry: &AccessLog) {
+        if let Some(format) = self.access_format {
+            println!("{}", entry.format(format));
+        }
+    }
+}
+
+/// Forwards messages to the `log` crate with the target `unchained_web`
+/// and access logs at the info level with the target `unchained_web::access`
+#[cfg(feature = "log")]
+#[derive(Clone, Debug)]
+pub struct LogFacade {
+    pub access_format: AccessLogFormat,
+}
+
+#[cfg(feature = "log")]
+impl LogFacade {
+    pub fn new(access_format: AccessLogFormat) -> Self {
+        LogFacade { access_format }
+    }
+}
+
+#[cfg(feature = "log")]
+impl Logger for LogFacade {
+    fn log(&self, level: Level, message: fmt::Arguments) {
+        let level = match level {
+            Level::Error => log::Level::Error,
+            Level::Warn => log::Level::Warn,
+            Level::Info => log::Level::Info,
+            Level::Debug => log::Level::Debug,
+            Level::Trace => log::Level::Trace,
+        };
+        log::log!(target: "unchained_web", level, "{}", message);
+    }
+
+    fn access(&self, entry: &AccessLog) {
+        if log::log_enabled!(target: "unchained_web::access", log::Level::Info) {
+            log::info!(target: "unchained_web::access", "{}", entry.format(self.access_format));
+        }
+    }
+}
+
+/// Forwards messages to `tracing` events with the target `unchained_web`,
+/// and access logs as info events with fields and the target `unchained_web::access`
+#[cfg(feature = "tracing")]
+#[derive(Clone, Debug, Default)]
+pub struct TracingFacade;
+
+#[cfg(feature = "tracing")]
+impl Logger for TracingFacade {
+    fn log(&self, level: Level, message: fmt::Arguments) {
+        match level {
+            Level::Error => tracing::error!(target: "unchained_web", "{}", message),
+            Level::Warn => tracing::warn!(target: "unchained_web", "{}", message),
+            Level::Info => tracing::info!(target: "unchained_web", "{}", message),
+            Level::Debug => tracing::debug!(target: "unchained_web", "{}", message),
+            Level::Trace => tracing::trace!(target: "unchained_web", "{}", message),
+        }
+    }
+
+    fn access(&self, entry: &AccessLog) {
+        tracing::info!(
+            target: "unchained_web::access",
+            client = entry.client.map(|client| client.ip().to_string()).as_deref(),
+            method = entry.verb.as_str(),
+            path = entry.target.as_str(),
+            version = entry.version.as_str(),
+            status = entry.status,
+            bytes = entry.bytes,
+            duration_ms = entry.duration.as_secs_f64() * 1000.0,
+            referer = entry.referer.as_deref(),
+            user_agent = entry.user_agent.as_deref(),
+            "{} {} {}",
+            entry.verb,
+            entry.target,
+            entry.status
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn entry() -> AccessLog {
+        let mut request = Request::new("GET", "/search?q=\"x\"");
+        request.version = "HTTP/1.1".to_string();
+        request
+            .headers
+            .insert("user-agent".to_string(), "curl/8.0".to_string());
+        let mut entry = AccessLog::new(&request, Some("127.0.0.1:51234".parse().unwrap()));
+        entry.time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        entry.status = 200;
+        entry.bytes = 2326;
+        entry.duration = Duration::from_micros(1500);
+        entry
+    }
+
+    #[test]
+    fn test_access_log_formats() {
+        let entry = entry();
+        let common = "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /search?q=\\\"x\\\" HTTP/1.1\" 200 2326";
+        assert_eq!(entry.format(AccessLogFormat::Common), common);
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            format!("{} \"-\" \"curl/8.0\"", common)
+        );
+        assert_eq!(
+            entry.format(AccessLogFormat::Json),
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\",\
+            \"path\":\"/search?q=\\\"x\\\"\",\"version\":\"HTTP/1.1\",\"status\":200,\"bytes\":2326,\
+            \"duration_ms\":1.500,\"referer\":null,\"user_agent\":\"curl/8.0\"}"
+        );
+
+        let mut empty = AccessLog::new(&Request::new("HEAD", "/"), None);
+        empty.time = entry.time;
+        empty.status = 304;
+        assert_eq!(
+            empty.format(AccessLogFormat::Common),
+            "- - - [10/Oct/2000:13:55:36 +0000] \"HEAD / HTTP/1.1\" 304 -"
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(Level::Error < Level::Warn && Level::Debug < Level::Trace);
+        assert_eq!(json_string("a\"b\\\n\u{1}"), "\"a\\\"b\\\\\\n\\u0001\"");
+    }
+}
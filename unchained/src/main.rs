@@ -3,5 +3,5 @@ use unchained_web::server::Server;
 fn main() {
     let routes = vec![];
     let server = Server::new(routes);
-    server.listen();
+    server.listen().unwrap();
 }
@@ -0,0 +1,210 @@
+//! Hooks running before and after routes, e.g. for logging, authentication or CORS.
+//!
+//! Middleware added to the [Server](crate::server::Server) runs for every request,
+//! before the route is found and before the request body is read.
+//! Middleware added to a [Route] runs when the route responds.
+//!
+//! ```
+//! use unchained_web::{
+//!     middleware::{after, before},
+//!     router::{HTTPVerb::*, Response, ResponseContent, Route},
+//!     server::Server,
+//! };
+//!
+//! let mut admin = Route::new(GET, "/admin", ResponseContent::Str("Admin".to_string()));
+//! admin.add_middleware(before(|req| match req.header("Authorization") {
+//!     Some("Bearer secret") => None,
+//!     _ => Some(Response::new(None, 401)),
+//! }));
+//! let mut server = Server::new(vec![admin]);
+//! server.add_middleware(after(|req, res| {
+//!     res.add_header("X-Path", &req.path);
+//! }));
+//! ```
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::router::{Request, Response};
+
+pub trait Middleware: Send + Sync {
+    /// Inspect or modify the request before the route responds.
+    /// Returning a response skips the route and the remaining middleware.
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    /// Modify the response. The request has no body at this point.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+pub struct Before<F>(F);
+
+impl<F> Middleware for Before<F>
+where
+    F: Fn(&mut Request) -> Option<Response> + Send + Sync,
+{
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        (self.0)(request)
+    }
+}
+
+/// Middleware from a function running before the route
+pub fn before<F>(f: F) -> Before<F>
+where
+    F: Fn(&mut Request) -> Option<Response> + Send + Sync,
+{
+    Before(f)
+}
+
+pub struct After<F>(F);
+
+impl<F> Middleware for After<F>
+where
+    F: Fn(&Request, &mut Response) + Send + Sync,
+{
+    fn after(&self, request: &Request, response: &mut Response) {
+        (self.0)(request, response)
+    }
+}
+
+/// Middleware from a function running after the route
+pub fn after<F>(f: F) -> After<F>
+where
+    F: Fn(&Request, &mut Response) + Send + Sync,
+{
+    After(f)
+}
+
+/// Adds headers to responses that do not set them
+pub struct DefaultHeaders(pub HashMap<String, String>);
+
+impl Middleware for DefaultHeaders {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        for (key, value) in self.0.iter() {
+            let exists = response.headers.keys().any(|k| k.eq_ignore_ascii_case(key));
+            if !exists {
+                response.add_header(key, value);
+            }
+        }
+    }
+}
+
+/// The request without its body, given to [Middleware::after]
+fn without_body(request: &mut Request) -> Request {
+    let body = request.body.take();
+    let copy = request.clone();
+    request.body = body;
+    copy
+}
+
+/// Run the middleware around `handler`.
+/// `before` runs in the order the middleware is given and `after` in reverse order.
+/// If a middleware responds early, only the middleware before it runs `after`.
+pub fn run<F>(middleware: &[Arc<dyn Middleware>], mut request: Request, handler: F) -> Response
+where
+    F: FnOnce(Request) -> Response,
+{
+    for (i, m) in middleware.iter().enumerate() {
+        if let Some(mut response) = m.before(&mut request) {
+            let request = without_body(&mut request);
+            for m in middleware[..i].iter().rev() {
+                m.after(&request, &mut response);
+            }
+            return response;
+        }
+    }
+    let copy = match middleware.is_empty() {
+        true => None,
+        false => Some(without_body(&mut request)),
+    };
+    let mut response = handler(request);
+    if let Some(request) = copy {
+        for m in middleware.iter().rev() {
+            m.after(&request, &mut response);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records the order middleware runs in
+    struct Trace {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        respond: bool,
+    }
+
+    impl Middleware for Trace {
+        fn before(&self, request: &mut Request) -> Option<Response> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            request
+                .path_params
+                .insert(self.name.to_string(), String::new());
+            self.respond.then(|| Response::new(None, 403))
+        }
+
+        fn after(&self, request: &Request, response: &mut Response) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+            assert!(request.body.is_none());
+            response.add_header(self.name, "");
+        }
+    }
+
+    #[test]
+    fn test_middleware_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let trace = |name, respond| -> Arc<dyn Middleware> {
+            Arc::new(Trace {
+                name,
+                log: log.clone(),
+                respond,
+            })
+        };
+        let middleware = vec![trace("a", false), trace("b", false)];
+        let mut request = Request::new("POST", "/");
+        request.body = Some(b"body".to_vec());
+        let response = run(&middleware, request, |req| {
+            assert!(req.path_params.contains_key("b"));
+            assert_eq!(req.body.as_deref(), Some(&b"body"[..]));
+            Response::new_200("ok".to_string())
+        });
+        assert!(response.headers.contains_key("a") && response.headers.contains_key("b"));
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "after b", "after a"]
+        );
+
+        log.lock().unwrap().clear();
+        let middleware = vec![trace("a", false), trace("b", true), trace("c", false)];
+        let response = run(&middleware, Request::new("GET", "/"), |_| unreachable!());
+        assert_eq!(response.status_code, 403);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "after a"]
+        );
+    }
+
+    #[test]
+    fn test_default_headers() {
+        let headers = DefaultHeaders(HashMap::from([
+            ("X-Frame-Options".to_string(), "DENY".to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ]));
+        let mut response = Response::new_200(String::new());
+        response.add_header("cache-control", "max-age=60");
+        headers.after(&Request::new("GET", "/"), &mut response);
+        assert_eq!(response.headers["X-Frame-Options"], "DENY");
+        assert!(!response.headers.contains_key("Cache-Control"));
+    }
+}
@@ -0,0 +1,380 @@
+//! Small regular expressions for constraining path parameters.
+//!
+//! Supports literals, `.`, classes like `[a-z0-9-]` and `[^/]`, the escapes
+//! `\d`, `\w` and `\s`, groups with alternatives `(a|b)` and the quantifiers
+//! `*`, `+`, `?`, `{n}`, `{n,}` and `{n,m}`.
+//! Patterns always match the whole value.
+//!
+//! ```
+//! use unchained_web::pattern::Pattern;
+//!
+//! let slug = Pattern::new("[a-z0-9]+(-[a-z0-9]+)*").unwrap();
+//! assert!(slug.is_match("radix-tree-router"));
+//! assert!(!slug.is_match("Not a slug"));
+//! ```
+
+use std::{cell::Cell, iter::Peekable, str::Chars};
+
+use crate::error::{Error, WebResult};
+
+/// Steps after which matching gives up, so badly written patterns can not hang a request
+const MAX_STEPS: usize = 100_000;
+
+/// Repetitions of a group after which no more are tried
+const MAX_GROUP_REPETITIONS: usize = 256;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Group(Vec<Vec<Item>>),
+}
+
+#[derive(Clone, Debug)]
+struct Item {
+    node: Node,
+    min: usize,
+    max: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    alternatives: Vec<Vec<Item>>,
+}
+
+const DIGITS: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &str) -> Error {
+        Error::InvalidParams(format!("Invalid pattern '{}'. {}", self.source, reason))
+    }
+
+    fn alternatives(&mut self, depth: usize) -> WebResult<Vec<Vec<Item>>> {
+        let mut alternatives = vec![self.sequence(depth)?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            alternatives.push(self.sequence(depth)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, depth: usize) -> WebResult<Vec<Item>> {
+        let mut items = Vec::new();
+        while let Some(c) = self.chars.peek().copied() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            self.chars.next();
+            let node = match c {
+                '(' => {
+                    if depth > 32 {
+                        return Err(self.error("Too many nested groups"));
+                    }
+                    // Groups never capture, so `(?:...)` is the same as `(...)`
+                    if self.chars.next_if_eq(&'?').is_some() && self.chars.next() != Some(':') {
+                        return Err(self.error("Unsupported group"));
+                    }
+                    let group = self.alternatives(depth + 1)?;
+                    if self.chars.next() != Some(')') {
+                        return Err(self.error("Missing ')'"));
+                    }
+                    Node::Group(group)
+                }
+                '[' => self.class()?,
+                '.' => Node::Any,
+                '\\' => self.escape()?,
+                // The whole value is always matched
+                '^' if items.is_empty() => continue,
+                '$' if self.chars.peek().is_none() => continue,
+                '*' | '+' | '?' | '{' => return Err(self.error("Nothing to repeat")),
+                c => Node::Char(c),
+            };
+            let (min, max) = self.quantifier()?;
+            items.push(Item { node, min, max });
+        }
+        Ok(items)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        digits.parse().ok()
+    }
+
+    fn quantifier(&mut self) -> WebResult<(usize, Option<usize>)> {
+        let quantifier = match self.chars.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.chars.next();
+                let min = self
+                    .number()
+                    .ok_or_else(|| self.error("Invalid repetition"))?;
+                let max = match self.chars.next() {
+                    Some('}') => return Ok((min, Some(min))),
+                    Some(',') => self.number(),
+                    _ => return Err(self.error("Invalid repetition")),
+                };
+                if self.chars.next() != Some('}') || max.is_some_and(|max| max < min) {
+                    return Err(self.error("Invalid repetition"));
+                }
+                return Ok((min, max));
+            }
+            _ => return Ok((1, Some(1))),
+        };
+        self.chars.next();
+        Ok(quantifier)
+    }
+
+    fn escape(&mut self) -> WebResult<Node> {
+        let class = |ranges: &[(char, char)], negated| Node::Class {
+            ranges: ranges.to_vec(),
+            negated,
+        };
+        Ok(match self.chars.next() {
+            Some('d') => class(DIGITS, false),
+            Some('D') => class(DIGITS, true),
+            Some('w') => class(WORD, false),
+            Some('W') => class(WORD, true),
+            Some('s') => class(SPACE, false),
+            Some('S') => class(SPACE, true),
+            Some(c) => Node::Char(c),
+            None => return Err(self.error("Pattern ends with '\\'")),
+        })
+    }
+
+    fn class(&mut self) -> WebResult<Node> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let start = match self.chars.next() {
+                Some(']') if !first => break,
+                Some('\\') => match self.chars.next() {
+                    Some('d') => {
+                        ranges.extend_from_slice(DIGITS);
+                        continue;
+                    }
+                    Some('w') => {
+                        ranges.extend_from_slice(WORD);
+                        continue;
+                    }
+                    Some('s') => {
+                        ranges.extend_from_slice(SPACE);
+                        continue;
+                    }
+                    Some(c) => c,
+                    None => return Err(self.error("Missing ']'")),
+                },
+                Some(c) => c,
+                None => return Err(self.error("Missing ']'")),
+            };
+            first = false;
+            let is_range = self.chars.peek() == Some(&'-') && {
+                let mut lookahead = self.chars.clone();
+                lookahead.next();
+                !matches!(lookahead.peek(), Some(']') | None)
+            };
+            if !is_range {
+                ranges.push((start, start));
+                continue;
+            }
+            self.chars.next();
+            let end = match self.chars.next() {
+                Some('\\') => self.chars.next(),
+                c => c,
+            }
+            .ok_or_else(|| self.error("Missing ']'"))?;
+            if end < start {
+                return Err(self.error("Invalid range in class"));
+            }
+            ranges.push((start, end));
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+}
+
+/// Continuation called with the position after a match
+type Next<'a> = &'a dyn Fn(usize) -> bool;
+
+struct Matcher<'a> {
+    input: &'a [char],
+    steps: Cell<usize>,
+}
+
+impl Matcher<'_> {
+    fn step(&self) -> bool {
+        self.steps.set(self.steps.get() + 1);
+        self.steps.get() < MAX_STEPS
+    }
+
+    fn items(&self, items: &[Item], pos: usize, next: Next) -> bool {
+        match items.split_first() {
+            None => next(pos),
+            Some((item, rest)) => self.repeat(item, 0, pos, &|p| self.items(rest, p, next)),
+        }
+    }
+
+    /// Match as many repetitions as possible, backtracking to fewer
+    fn repeat(&self, item: &Item, count: usize, pos: usize, next: Next) -> bool {
+        let Node::Group(alternatives) = &item.node else {
+            return self.repeat_char(item, pos, next);
+        };
+        if !self.step() {
+            return false;
+        }
+        // Every repetition of a group recurses, so their number is limited to bound the stack
+        let more = item.max.is_none_or(|max| count < max)
+            && count < MAX_GROUP_REPETITIONS
+            && alternatives.iter().any(|items| {
+                self.items(items, pos, &|p| {
+                    // Repetitions matching nothing would loop forever
+                    p != pos && self.repeat(item, count + 1, p, next)
+                })
+            });
+        more || (count >= item.min && next(pos))
+    }
+
+    /// Repeat a node matching a single character in a loop,
+    /// so long values do not overflow the stack
+    fn repeat_char(&self, item: &Item, pos: usize, next: Next) -> bool {
+        let max = item.max.unwrap_or(usize::MAX);
+        let mut end = pos;
+        while end - pos < max && self.char_matches(&item.node, end) {
+            end += 1;
+        }
+        (pos + item.min..=end).rev().any(|p| self.step() && next(p))
+    }
+
+    fn char_matches(&self, node: &Node, pos: usize) -> bool {
+        let Some(c) = self.input.get(pos) else {
+            return false;
+        };
+        match node {
+            Node::Char(expected) => c == expected,
+            Node::Any => true,
+            Node::Class { ranges, negated } => {
+                ranges.iter().any(|(start, end)| (start..=end).contains(&c)) != *negated
+            }
+            Node::Group(_) => false,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> WebResult<Pattern> {
+        let mut parser = Parser {
+            source,
+            chars: source.chars().peekable(),
+        };
+        let alternatives = parser.alternatives(0)?;
+        if parser.chars.next().is_some() {
+            return Err(parser.error("Unmatched ')'"));
+        }
+        Ok(Pattern {
+            source: source.to_string(),
+            alternatives,
+        })
+    }
+
+    /// Whether the pattern matches the whole value
+    pub fn is_match(&self, value: &str) -> bool {
+        let input = value.chars().collect::<Vec<_>>();
+        let matcher = Matcher {
+            input: &input,
+            steps: Cell::new(0),
+        };
+        self.alternatives
+            .iter()
+            .any(|items| matcher.items(items, 0, &|pos| pos == input.len()))
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let cases = [
+            (
+                "-?[0-9]+",
+                vec!["0", "-12", "345"],
+                vec!["", "-", "1a", "+1"],
+            ),
+            ("[a-z0-9-]+", vec!["my-slug-2"], vec!["My-slug", "a_b", ""]),
+            ("\\d{4}-\\d{2}", vec!["2024-01"], vec!["24-01", "2024-011"]),
+            (
+                "(en|nl)(-[A-Z]{2})?",
+                vec!["en", "nl-BE"],
+                vec!["de", "en-us"],
+            ),
+            ("a.c|x*", vec!["abc", "a-c", "", "xxx"], vec!["ac", "xy"]),
+            (
+                "[^/.]+\\.(png|jpe?g)",
+                vec!["a.png", "b.jpg", "c.jpeg"],
+                vec!["a.gif", ".png"],
+            ),
+            ("^\\w{2,3}$", vec!["ab", "a_1"], vec!["a", "abcd"]),
+            ("[]a]+[-x]", vec!["]a-", "aax"], vec!["a"]),
+        ];
+        for (source, matching, not_matching) in cases {
+            let pattern = Pattern::new(source).unwrap();
+            for value in matching {
+                assert!(pattern.is_match(value), "{} should match {}", source, value);
+            }
+            for value in not_matching {
+                assert!(
+                    !pattern.is_match(value),
+                    "{} should not match {}",
+                    source,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for source in ["(a", "a)", "[a-", "*a", "a{2,1}", "[z-a]", "a\\", "(?=a)"] {
+            assert!(Pattern::new(source).is_err(), "{}", source);
+        }
+        // Patterns with catastrophic backtracking give up instead of hanging
+        let pattern = Pattern::new("(a*)*b").unwrap();
+        assert!(!pattern.is_match(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn test_long_values() {
+        // Matching a whole header sized segment must not overflow the stack of a worker
+        std::thread::spawn(|| {
+            let segment = "a".repeat(16 * 1024);
+            assert!(Pattern::new("[a-z0-9-]+").unwrap().is_match(&segment));
+            assert!(Pattern::new(".*a").unwrap().is_match(&segment));
+            assert!(!Pattern::new("[a-z]+b").unwrap().is_match(&segment));
+            let slug = Pattern::new("[a-z0-9]+(-[a-z0-9]+)*").unwrap();
+            assert!(slug.is_match("ab-".repeat(100).trim_end_matches('-')));
+            assert!(!slug.is_match(&"a-".repeat(8 * 1024)));
+        })
+        .join()
+        .unwrap();
+    }
+}
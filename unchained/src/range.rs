@@ -0,0 +1,138 @@
+//! Byte ranges of the `Range` header, for resuming downloads and seeking in media.
+//!
+//! ```
+//! use unchained_web::range::{parse_range, Ranges};
+//!
+//! assert_eq!(parse_range("bytes=0-99, -100", 1000), Ranges::Satisfiable(vec![(0, 99), (900, 999)]));
+//! assert_eq!(parse_range("bytes=2000-", 1000), Ranges::Unsatisfiable);
+//! assert_eq!(parse_range("lines=1-2", 1000), Ranges::Ignored);
+//! ```
+
+use crate::cache::parse_http_date;
+
+/// Ranges in one request above which the `Range` header is ignored
+const MAX_RANGES: usize = 16;
+
+/// Result of parsing a `Range` header for a representation of a length
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ranges {
+    /// Invalid or unsupported header. The whole content is sent.
+    Ignored,
+    /// No range overlaps the content. Answered with `416 Range Not Satisfiable`.
+    Unsatisfiable,
+    /// Inclusive start and end of the ranges to send
+    Satisfiable(Vec<(u64, u64)>),
+}
+
+/// Parse a `Range` header like `bytes=0-499, 1000-, -500`
+pub fn parse_range(header: &str, length: u64) -> Ranges {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return Ranges::Ignored;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ranges::Ignored;
+    }
+    let specs = specs
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Ranges::Ignored;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let Some((start, end)) = spec.split_once('-') else {
+            return Ranges::Ignored;
+        };
+        let number = |n: &str| n.trim().parse::<u64>().ok();
+        let range = match (start.trim().is_empty(), end.trim().is_empty()) {
+            // The last `n` bytes
+            (true, false) => match number(end) {
+                Some(0) => None,
+                Some(n) => Some((length.saturating_sub(n), length.saturating_sub(1))),
+                None => return Ranges::Ignored,
+            },
+            (false, _) => {
+                let Some(start) = number(start) else {
+                    return Ranges::Ignored;
+                };
+                let end = match end.trim().is_empty() {
+                    true => u64::MAX,
+                    false => match number(end) {
+                        Some(end) if end >= start => end,
+                        _ => return Ranges::Ignored,
+                    },
+                };
+                (start < length).then(|| (start, end.min(length - 1)))
+            }
+            (true, true) => return Ranges::Ignored,
+        };
+        ranges.extend(range.filter(|_| length > 0));
+    }
+    match ranges.is_empty() {
+        true => Ranges::Unsatisfiable,
+        false => Ranges::Satisfiable(ranges),
+    }
+}
+
+/// Whether the `If-Range` header allows sending ranges of a representation.
+/// Only strong `ETag`s and exact modification dates match.
+pub fn if_range_matches(if_range: &str, etag: Option<&str>, last_modified: Option<u64>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !if_range.starts_with("W/") && etag == Some(if_range);
+    }
+    parse_http_date(if_range).is_some_and(|date| last_modified == Some(date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let satisfiable = |header: &str| match parse_range(header, 1000) {
+            Ranges::Satisfiable(ranges) => ranges,
+            other => panic!("{} gave {:?}", header, other),
+        };
+        assert_eq!(satisfiable("bytes=0-499"), vec![(0, 499)]);
+        assert_eq!(satisfiable("bytes=500-"), vec![(500, 999)]);
+        assert_eq!(satisfiable("bytes=-300"), vec![(700, 999)]);
+        assert_eq!(satisfiable("bytes=-5000"), vec![(0, 999)]);
+        assert_eq!(satisfiable("bytes=990-2000"), vec![(990, 999)]);
+        assert_eq!(
+            satisfiable("Bytes=0-0 , 2000-3000, -1"),
+            vec![(0, 0), (999, 999)]
+        );
+
+        for ignored in [
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=1",
+            "items=0-1",
+            "0-1",
+        ] {
+            assert_eq!(parse_range(ignored, 1000), Ranges::Ignored, "{}", ignored);
+        }
+        assert_eq!(
+            parse_range(&format!("bytes={}", "0-1,".repeat(20)), 1000),
+            Ranges::Ignored
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_if_range() {
+        assert!(if_range_matches("\"abc\"", Some("\"abc\""), None));
+        assert!(!if_range_matches("W/\"abc\"", Some("W/\"abc\""), None));
+        assert!(!if_range_matches("\"abc\"", Some("\"abd\""), None));
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(if_range_matches(date, None, Some(784111777)));
+        assert!(!if_range_matches(date, None, Some(784111778)));
+        assert!(!if_range_matches("garbage", None, Some(784111777)));
+    }
+}
@@ -0,0 +1,746 @@
+//! Routes compiled into a tree of path segments.
+//!
+//! Matching does not depend on the order routes are added in.
+//! Static segments are tried before `:param` segments, which are tried before `*` wildcards.
+//!
+//! Parameters can be constrained with a [Pattern](crate::pattern::Pattern) like
+//! `:slug<[a-z0-9-]+>` or one of the names `int`, `uint`, `alpha`, `alnum`, `slug` and `uuid`.
+//! Constrained parameters are tried before unconstrained ones.
+//! A `?` makes a parameter optional, as in `/posts/:page<uint>?`,
+//! and `*name` at the end of a route captures the rest of the path.
+//!
+//! ```
+//! use unchained_web::{
+//!     route_tree::RouteTree,
+//!     router::{HTTPVerb::*, Request, ResponseContent, Route},
+//! };
+//!
+//! let routes = vec![
+//!     Route::new(GET, "/*", ResponseContent::Str("Not found".to_string())),
+//!     Route::new(GET, "/posts/:id", ResponseContent::Str("Post".to_string())),
+//!     Route::new(GET, "/posts/new", ResponseContent::Str("New post".to_string())),
+//! ];
+//! let tree = RouteTree::new(&routes);
+//! let (route, _) = tree.find(&Request::new("GET", "/posts/new")).unwrap();
+//! assert_eq!(route.path, "/posts/new");
+//! let (route, params) = tree.find(&Request::new("GET", "/posts/1")).unwrap();
+//! assert_eq!((route.path.as_str(), params["id"].as_str()), ("/posts/:id", "1"));
+//! ```
+
+use std::{collections::HashMap, io::Read};
+
+use crate::{
+    cache::Preconditions,
+    error::{Error, WebResult},
+    form::percent_decode,
+    middleware,
+    pattern::Pattern,
+    router::{allow_response, route_response, strip_body, HTTPVerb, Request, Response, Route},
+};
+
+/// A segment of a route path
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Segment<'a> {
+    Static(&'a str),
+    /// `:name`, `:name<constraint>` or `:name?` for an optional segment
+    Param {
+        name: &'a str,
+        constraint: Option<&'a str>,
+        optional: bool,
+    },
+    /// `*` in the middle of a route, matching one segment
+    Wildcard,
+    /// `*` or `*name` at the end of a route, matching the rest of the path
+    CatchAll(Option<&'a str>),
+}
+
+/// Named constraints usable as `:id<int>`
+fn named_constraint(name: &str) -> Option<&'static str> {
+    match name {
+        "int" => Some("-?[0-9]+"),
+        "uint" => Some("[0-9]+"),
+        "alpha" => Some("[a-zA-Z]+"),
+        "alnum" => Some("[a-zA-Z0-9]+"),
+        "slug" => Some("[a-z0-9]+(-[a-z0-9]+)*"),
+        "uuid" => {
+            Some("[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}")
+        }
+        _ => None,
+    }
+}
+
+/// Pattern of a parameter constraint, which can be one of the named constraints
+pub(crate) fn constraint_pattern(constraint: &str) -> WebResult<Pattern> {
+    Pattern::new(named_constraint(constraint).unwrap_or(constraint))
+}
+
+/// Split a route path into segments
+pub(crate) fn parse_route(path: &str) -> Vec<Segment<'_>> {
+    let parts = segments(path).collect::<Vec<_>>();
+    let last = parts.len().saturating_sub(1);
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if let Some(param) = part.strip_prefix(':') {
+                let (param, optional) = match param.strip_suffix('?') {
+                    Some(param) => (param, true),
+                    None => (param, false),
+                };
+                let (name, constraint) = match param.split_once('<') {
+                    Some((name, rest)) if rest.ends_with('>') => {
+                        (name, Some(&rest[..rest.len() - 1]))
+                    }
+                    _ => (param, None),
+                };
+                Segment::Param {
+                    name,
+                    constraint,
+                    optional,
+                }
+            } else if let Some(name) = part.strip_prefix('*').filter(|_| i == last) {
+                Segment::CatchAll((!name.is_empty()).then_some(name))
+            } else if part == "*" {
+                Segment::Wildcard
+            } else {
+                Segment::Static(part)
+            }
+        })
+        .collect()
+}
+
+/// Every combination of optional segments being left out or included
+fn expand_optional<'a>(route: &[Segment<'a>]) -> Vec<Vec<Segment<'a>>> {
+    let mut variants = vec![Vec::new()];
+    for segment in route {
+        let optional = matches!(segment, Segment::Param { optional: true, .. });
+        let mut next = Vec::new();
+        for variant in variants {
+            if optional {
+                next.push(variant.clone());
+            }
+            let mut with_segment = variant;
+            with_segment.push(segment.clone());
+            next.push(with_segment);
+        }
+        variants = next;
+    }
+    variants
+}
+
+/// A route ending at a node and the names of its parameters
+struct Leaf {
+    route: usize,
+    names: Vec<String>,
+}
+
+#[derive(Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    /// Parameters with a constraint come before the parameter without one
+    params: Vec<(Option<Pattern>, Node)>,
+    /// `*` in the middle of a route, matching one segment
+    wildcard: Option<Box<Node>>,
+    /// Routes ending at this node
+    routes: Vec<Leaf>,
+    /// Routes ending with `*`, matching the rest of the path
+    catch_all: Vec<Leaf>,
+}
+
+/// Index of a matching route and its path parameters
+type Match = (usize, HashMap<String, String>);
+
+fn found(leaf: &Leaf, captured: &[String]) -> Match {
+    let params = leaf.names.iter().cloned().zip(captured.iter().cloned());
+    (leaf.route, params.collect())
+}
+
+impl Node {
+    /// Walk the routes matching the segments, the most specific first,
+    /// and stop at the first route that is accepted
+    fn first(
+        &self,
+        segments: &[String],
+        captured: &mut Vec<String>,
+        accept: &mut dyn FnMut(usize) -> bool,
+    ) -> Option<Match> {
+        match segments.split_first() {
+            None => {
+                if let Some(leaf) = self.routes.iter().find(|leaf| accept(leaf.route)) {
+                    return Some(found(leaf, captured));
+                }
+            }
+            Some((segment, rest)) => {
+                if let Some(child) = self.statics.get(segment) {
+                    if let Some(found) = child.first(rest, captured, accept) {
+                        return Some(found);
+                    }
+                }
+                for (constraint, child) in self.params.iter() {
+                    if constraint.as_ref().is_some_and(|c| !c.is_match(segment)) {
+                        continue;
+                    }
+                    captured.push(segment.clone());
+                    let found = child.first(rest, captured, accept);
+                    captured.pop();
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+                if let Some(child) = &self.wildcard {
+                    if let Some(found) = child.first(rest, captured, accept) {
+                        return Some(found);
+                    }
+                }
+            }
+        }
+        let leaf = self.catch_all.iter().find(|leaf| accept(leaf.route))?;
+        captured.push(segments.join("/"));
+        let found = found(leaf, captured);
+        captured.pop();
+        Some(found)
+    }
+
+    /// Add a route to the tree.
+    /// Returns the shape of the route, which is the same for routes matching the same paths.
+    fn insert(&mut self, index: usize, route: &[Segment]) -> WebResult<Vec<String>> {
+        let mut node = self;
+        let mut names = Vec::new();
+        let mut shape = Vec::new();
+        for segment in route {
+            node = match segment {
+                Segment::Static(part) => {
+                    shape.push(part.to_string());
+                    node.statics.entry(part.to_string()).or_default()
+                }
+                Segment::Param {
+                    name, constraint, ..
+                } => {
+                    names.push(name.to_string());
+                    let pattern = constraint.map(constraint_pattern).transpose()?;
+                    shape.push(format!(":{}", pattern.as_ref().map_or("", |p| p.source())));
+                    node.param_child(pattern)
+                }
+                Segment::Wildcard => {
+                    shape.push("*".to_string());
+                    node.wildcard.get_or_insert_with(Box::default)
+                }
+                Segment::CatchAll(name) => {
+                    shape.push("**".to_string());
+                    // Anonymous catch-alls do not capture the rest of the path
+                    names.extend(name.map(|n| n.to_string()));
+                    node.catch_all.push(Leaf {
+                        route: index,
+                        names,
+                    });
+                    return Ok(shape);
+                }
+            };
+        }
+        node.routes.push(Leaf {
+            route: index,
+            names,
+        });
+        Ok(shape)
+    }
+
+    fn param_child(&mut self, constraint: Option<Pattern>) -> &mut Node {
+        let source = constraint.as_ref().map(|c| c.source().to_string());
+        let position = self
+            .params
+            .iter()
+            .position(|(c, _)| c.as_ref().map(|c| c.source()) == source.as_deref());
+        let index = match position {
+            Some(index) => index,
+            None if constraint.is_some() => {
+                // Keep the parameter without a constraint last
+                let index = self.params.iter().take_while(|(c, _)| c.is_some()).count();
+                self.params.insert(index, (constraint, Node::default()));
+                index
+            }
+            None => {
+                self.params.push((None, Node::default()));
+                self.params.len() - 1
+            }
+        };
+        &mut self.params[index].1
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+/// Decode a path segment, keeping encoded slashes as `%2F`
+/// so parameters can not contain `/`, e.g. to escape a directory
+fn decode_segment(segment: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = segment;
+    // Uppercasing ASCII keeps the byte positions the same
+    while let Some(index) = rest.to_ascii_uppercase().find("%2F") {
+        decoded.push_str(&percent_decode(&rest[..index]));
+        decoded.push_str("%2F");
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(&percent_decode(rest));
+    decoded
+}
+
+/// Routes compiled for matching requests.
+/// The tree keeps the routes it was created from,
+/// owned like `Arc<[Route]>` or borrowed like `&[Route]`.
+pub struct RouteTree<R> {
+    routes: R,
+    root: Node,
+    conflicts: Vec<String>,
+}
+
+/// Whether both routes respond to one of the same methods
+fn verbs_overlap(a: &Route, b: &Route) -> bool {
+    a.verbs.is_empty() || b.verbs.is_empty() || a.verbs.iter().any(|v| b.verbs.contains(v))
+}
+
+fn describe(route: &Route) -> String {
+    let verbs = match route.verbs.is_empty() {
+        true => "ANY".to_string(),
+        false => route
+            .verbs
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join("|"),
+    };
+    format!("{} {}", verbs, route.path)
+}
+
+impl<R: AsRef<[Route]>> RouteTree<R> {
+    /// Compile the routes.
+    /// Routes that can never be reached are listed in [RouteTree::conflicts].
+    pub fn new(routes: R) -> RouteTree<R> {
+        let mut root = Node::default();
+        let mut conflicts = Vec::new();
+        let all = routes.as_ref();
+        // Routes with the same shape, e.g. `/posts/:id` and `/posts/:slug`
+        let mut shapes: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+
+        for (index, route) in all.iter().enumerate() {
+            for variant in expand_optional(&parse_route(&route.path)) {
+                let shape = match root.insert(index, &variant) {
+                    Ok(shape) => shape,
+                    Err(e) => {
+                        conflicts.push(format!("Route '{}' is invalid. {:?}", describe(route), e));
+                        break;
+                    }
+                };
+                let same_shape = shapes.entry(shape).or_default();
+                for earlier in same_shape.iter().filter(|i| **i != index) {
+                    let conflict = format!(
+                        "Route '{}' is unreachable because '{}' matches the same requests",
+                        describe(route),
+                        describe(&all[*earlier])
+                    );
+                    if verbs_overlap(&all[*earlier], route) && !conflicts.contains(&conflict) {
+                        conflicts.push(conflict);
+                    }
+                }
+                same_shape.push(index);
+            }
+        }
+        let mut names: HashMap<&str, &Route> = HashMap::new();
+        for route in all {
+            let Some(name) = &route.name else {
+                continue;
+            };
+            match names.insert(name, route) {
+                Some(earlier) if earlier.path != route.path => conflicts.push(format!(
+                    "Route name '{}' is used by '{}' and '{}'",
+                    name,
+                    describe(earlier),
+                    describe(route)
+                )),
+                _ => {}
+            }
+        }
+        RouteTree {
+            routes,
+            root,
+            conflicts,
+        }
+    }
+
+    /// Like [RouteTree::new], but fails if a route can never be reached
+    /// because an earlier route matches the same paths and methods.
+    pub fn checked(routes: R) -> WebResult<RouteTree<R>> {
+        let tree = RouteTree::new(routes);
+        match tree.conflicts.is_empty() {
+            true => Ok(tree),
+            false => Err(Error::InvalidParams(tree.conflicts.join("\n"))),
+        }
+    }
+
+    /// Descriptions of routes that are unreachable
+    pub fn conflicts(&self) -> &[String] {
+        &self.conflicts
+    }
+
+    /// The routes the tree was created from
+    pub fn routes(&self) -> &[Route] {
+        self.routes.as_ref()
+    }
+
+    /// The most specific route matching the path that is accepted
+    fn first_match<'t>(
+        &'t self,
+        path: &str,
+        accept: &mut dyn FnMut(&'t Route) -> bool,
+    ) -> Option<Match> {
+        let decoded = segments(path).map(decode_segment).collect::<Vec<_>>();
+        let routes = self.routes();
+        self.root.first(&decoded, &mut Vec::new(), &mut |index| {
+            accept(&routes[index])
+        })
+    }
+
+    /// Path parameters of the most specific route matching the path, ignoring methods
+    pub fn match_path(&self, path: &str) -> Option<(&Route, HashMap<String, String>)> {
+        let (index, params) = self.first_match(path, &mut |_| true)?;
+        Some((&self.routes()[index], params))
+    }
+
+    /// Find the most specific route matching the verb and path of the request.
+    /// `HEAD` requests are also answered by `GET` routes.
+    /// Returns the route and the path parameters.
+    pub fn find(&self, request: &Request) -> Option<(&Route, HashMap<String, String>)> {
+        let (index, params) = self.find_index(request)?;
+        Some((&self.routes()[index], params))
+    }
+
+    /// Like [RouteTree::find], but returns the index of the route
+    pub(crate) fn find_index(&self, request: &Request) -> Option<Match> {
+        let verb = request.method();
+        self.first_match(&request.path, &mut |route| {
+            route.accepts(&verb) || (verb == HTTPVerb::HEAD && route.accepts(&HTTPVerb::GET))
+        })
+    }
+
+    /// Methods of the routes matching the path, for the `Allow` header.
+    /// The path `*` gives the methods of all routes.
+    pub fn allowed_verbs(&self, path: &str) -> Vec<HTTPVerb> {
+        let mut verbs = Vec::new();
+        let mut matching = Vec::new();
+        match path {
+            "*" => matching.extend(self.routes()),
+            _ => {
+                // Visits every matching route
+                self.first_match(path, &mut |route| {
+                    matching.push(route);
+                    false
+                });
+            }
+        };
+        for route in matching {
+            if route.verbs.is_empty() {
+                verbs.extend(HTTPVerb::STANDARD);
+            }
+            verbs.extend(route.verbs.iter().cloned());
+            if route.accepts(&HTTPVerb::GET) {
+                verbs.push(HTTPVerb::HEAD);
+            }
+        }
+        if verbs.is_empty() {
+            return verbs;
+        }
+        verbs.push(HTTPVerb::OPTIONS);
+        let mut allowed = HTTPVerb::STANDARD
+            .into_iter()
+            .filter(|verb| verbs.contains(verb))
+            .collect::<Vec<_>>();
+        for verb in verbs {
+            if matches!(verb, HTTPVerb::Custom(_)) && !allowed.contains(&verb) {
+                allowed.push(verb);
+            }
+        }
+        allowed
+    }
+
+    /// Respond to a request with the matching route.
+    /// [ResponseContent::Upload](crate::router::ResponseContent::Upload) routes
+    /// read the body from `body`.
+    pub fn respond(&self, mut request: Request, body: &mut dyn Read) -> Response {
+        let verb = request.method();
+        let Some((route, params)) = self.find(&request) else {
+            let allowed = self.allowed_verbs(&request.path);
+            return match verb {
+                _ if allowed.is_empty() => Response::new(None, 404),
+                HTTPVerb::OPTIONS => allow_response(204, &allowed),
+                _ => allow_response(405, &allowed),
+            };
+        };
+        request.set_path_params(params.clone());
+        let preconditions = Preconditions::from_request(&request);
+        let mut response = middleware::run(&route.middleware, request, |request| {
+            route_response(route, request, params, body)
+        });
+        preconditions.apply(&mut response);
+        if verb == HTTPVerb::HEAD {
+            strip_body(&mut response);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{router::ResponseContent, zip_longest::ZipLongest};
+
+    fn route(verb: HTTPVerb, path: &str) -> Route {
+        Route::new(verb, path, ResponseContent::Str(path.to_string()))
+    }
+
+    fn matched(tree: &RouteTree<&Vec<Route>>, path: &str) -> Option<String> {
+        tree.find(&Request::new("GET", path))
+            .map(|(route, _)| route.path.clone())
+    }
+
+    #[test]
+    fn test_matching_stops_at_first_accepted_route() {
+        let routes = vec![
+            route(HTTPVerb::GET, "/*"),
+            route(HTTPVerb::GET, "/posts/:id"),
+            route(HTTPVerb::GET, "/posts/new"),
+        ];
+        let tree = RouteTree::new(&routes);
+        let mut visited = Vec::new();
+        let found = tree.first_match("/posts/new", &mut |route| {
+            visited.push(route.path.as_str());
+            true
+        });
+        assert_eq!(found.unwrap().0, 2);
+        assert_eq!(visited, ["/posts/new"]);
+
+        visited.clear();
+        assert!(tree
+            .first_match("/posts/new", &mut |route| {
+                visited.push(route.path.as_str());
+                false
+            })
+            .is_none());
+        assert_eq!(visited, ["/posts/new", "/posts/:id", "/*"]);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let routes = vec![
+            route(HTTPVerb::GET, "/*"),
+            route(HTTPVerb::GET, "/courses/*"),
+            route(HTTPVerb::GET, "/courses/:id/*/edit"),
+            route(HTTPVerb::GET, "/courses/:id"),
+            route(HTTPVerb::GET, "/courses/:id/info"),
+            route(HTTPVerb::GET, "/courses/new"),
+            route(HTTPVerb::GET, "/courses/new/:page"),
+            route(HTTPVerb::GET, "/"),
+        ];
+        let tree = RouteTree::new(&routes);
+        let cases = [
+            ("/", "/"),
+            ("/courses/new", "/courses/new"),
+            ("/courses/CS4505", "/courses/:id"),
+            ("/courses/CS4505/info", "/courses/:id/info"),
+            ("/courses/new/2", "/courses/new/:page"),
+            ("/courses/a/b/edit", "/courses/:id/*/edit"),
+            ("/courses/a/b/c", "/courses/*"),
+            ("/courses", "/courses/*"),
+            ("/other/path", "/*"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(matched(&tree, path).as_deref(), Some(expected), "{}", path);
+        }
+        let (_, params) = tree
+            .find(&Request::new("GET", "/courses/new%20one/info"))
+            .unwrap();
+        assert_eq!(params["id"], "new one");
+        assert!(tree.conflicts().is_empty());
+    }
+
+    #[test]
+    fn test_verbs_and_conflicts() {
+        let routes = vec![
+            route(HTTPVerb::GET, "/posts/:id"),
+            route(HTTPVerb::DELETE, "/posts/:id"),
+            route(HTTPVerb::GET, "/posts/:slug/"),
+            Route::any("/posts/:id", ResponseContent::Str(String::new())),
+            route(HTTPVerb::GET, "/files/*"),
+            route(HTTPVerb::GET, "/files/*/"),
+        ];
+        let tree = RouteTree::new(&routes);
+        assert_eq!(tree.conflicts().len(), 5);
+        assert!(tree.conflicts()[0].contains("'GET /posts/:slug/'"));
+        assert!(RouteTree::checked(&routes[..2]).is_ok());
+        assert!(RouteTree::checked(&routes).is_err());
+
+        let request = Request::new("DELETE", "/posts/1");
+        assert_eq!(tree.find(&request).unwrap().0.path, "/posts/:id");
+        let get_only = &routes[..1];
+        assert!(RouteTree::new(get_only).find(&request).is_none());
+
+        // A name can be shared by routes with the same path
+        let mut named = vec![
+            route(HTTPVerb::GET, "/contact"),
+            route(HTTPVerb::POST, "/contact"),
+            route(HTTPVerb::GET, "/about"),
+        ];
+        named[0].set_name("contact");
+        named[1].set_name("contact");
+        assert!(RouteTree::checked(&named).is_ok());
+        assert!(crate::router::Urls::from_routes(&named).is_ok());
+        named[2].set_name("contact");
+        let conflicts = RouteTree::new(&named).conflicts().to_vec();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("name 'contact'"));
+        assert!(crate::router::Urls::from_routes(&named).is_err());
+    }
+
+    #[test]
+    fn test_constrained_and_optional_params() {
+        let routes = vec![
+            route(HTTPVerb::GET, "/posts/:slug"),
+            route(HTTPVerb::GET, "/posts/:id<int>"),
+            route(HTTPVerb::GET, "/posts/:year<\\d{4}>/:month<uint>?"),
+            route(HTTPVerb::GET, "/files/*path"),
+            route(HTTPVerb::GET, "/archive/:page<uint>?/*"),
+        ];
+        let tree = RouteTree::new(&routes);
+        assert!(tree.conflicts().is_empty(), "{:?}", tree.conflicts());
+        let find = |path: &str| {
+            let (route, params) = tree.find(&Request::new("GET", path)).unwrap();
+            let mut params = params.into_iter().collect::<Vec<_>>();
+            params.sort();
+            (route.path.as_str(), params)
+        };
+        let param = |k: &str, v: &str| (k.to_string(), v.to_string());
+
+        assert_eq!(
+            find("/posts/-12"),
+            ("/posts/:id<int>", vec![param("id", "-12")])
+        );
+        assert_eq!(
+            find("/posts/hello"),
+            ("/posts/:slug", vec![param("slug", "hello")])
+        );
+        // Constrained parameters matching the same segment are tried in the order they were added
+        assert_eq!(find("/posts/2024").0, "/posts/:id<int>");
+        assert_eq!(
+            find("/posts/2024/03").1,
+            vec![param("month", "03"), param("year", "2024")]
+        );
+        assert!(tree
+            .find(&Request::new("GET", "/posts/2024/march"))
+            .is_none());
+        assert_eq!(
+            find("/files/docs/a%20b.txt").1,
+            vec![param("path", "docs/a b.txt")]
+        );
+        assert_eq!(find("/files").1, vec![param("path", "")]);
+        assert_eq!(find("/archive/2/x/y").1, vec![param("page", "2")]);
+        assert_eq!(find("/archive/x/y").1, vec![]);
+
+        let invalid = vec![route(HTTPVerb::GET, "/:id<[0-9>")];
+        assert!(RouteTree::new(&invalid).conflicts()[0].contains("invalid"));
+        let conflicting = vec![
+            route(HTTPVerb::GET, "/posts/:page?"),
+            route(HTTPVerb::GET, "/posts"),
+            route(HTTPVerb::GET, "/posts/:n<int>"),
+        ];
+        assert_eq!(RouteTree::new(&conflicting).conflicts().len(), 1);
+    }
+
+    /// The matcher used before routes were compiled, comparing one route at a time
+    fn linear_match(route: &str, req_path: &str) -> (bool, HashMap<String, String>) {
+        let route_parts = route
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        let req_parts = req_path
+            .trim_start_matches('/')
+            .trim_end_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty());
+        let mut params = HashMap::new();
+        let mut match_route = true;
+        let last_is_star = route.ends_with('*');
+
+        for (route_part, req_part) in route_parts.iter().zip_longest(req_parts) {
+            match (route_part, req_part) {
+                (None, None) => break,
+                (None, Some(_)) => {
+                    match_route = last_is_star;
+                    break;
+                }
+                (Some(part), None) => {
+                    match_route = *part == "*";
+                    break;
+                }
+                (Some(route_part), Some(req_part)) => {
+                    if let Some(route_no_prefix) = route_part.strip_prefix(':') {
+                        params.insert(route_no_prefix.to_string(), req_part.to_string());
+                    } else if *route_part != req_part {
+                        match_route = *route_part == "*";
+                        if !match_route {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        (match_route, params)
+    }
+
+    /// Compare with matching every route in order.
+    /// Run with `cargo test --release bench_route_tree -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_route_tree() {
+        let mut routes = Vec::new();
+        for i in 0..200 {
+            routes.push(route(HTTPVerb::GET, &format!("/section{}/:id", i)));
+            routes.push(route(HTTPVerb::GET, &format!("/section{}/:id/edit", i)));
+            routes.push(route(HTTPVerb::POST, &format!("/section{}/static/page", i)));
+        }
+        routes.push(route(HTTPVerb::GET, "/*"));
+        let requests = (0..1000)
+            .map(|i| Request::new("GET", &format!("/section{}/{}/edit", i % 250, i)))
+            .collect::<Vec<_>>();
+
+        let started = Instant::now();
+        let mut linear_found = 0;
+        for request in requests.iter() {
+            let found = routes.iter().find(|route| {
+                route.accepts(&HTTPVerb::GET) && linear_match(&route.path, &request.path).0
+            });
+            linear_found += found.is_some() as usize;
+        }
+        let linear = started.elapsed();
+
+        let tree = RouteTree::new(&routes);
+        let started = Instant::now();
+        let mut tree_found = 0;
+        for request in requests.iter() {
+            tree_found += tree.find(request).is_some() as usize;
+        }
+        let compiled = started.elapsed();
+
+        assert_eq!(linear_found, tree_found);
+        println!(
+            "{} routes, {} requests: linear {:?}, tree {:?}",
+            routes.len(),
+            requests.len(),
+            linear,
+            compiled
+        );
+    }
+}
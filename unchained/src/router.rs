@@ -1,47 +1,218 @@
-use std::{collections::HashMap, fmt::Display, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
 
-use crate::zip_longest::ZipLongest;
+use crate::{
+    cache::{content_etag, CachePolicy},
+    error::{Error, WebResult},
+    form::{parse_query, percent_decode, percent_encode, Form, MultiMap},
+    json::parse_json,
+    middleware::Middleware,
+    route_tree::{constraint_pattern, parse_route, RouteTree, Segment},
+    static_files::StaticFiles,
+    templates::{
+        context::ContextTree,
+        i18n::{locale_from_path, negotiate_locale},
+    },
+};
 
 #[derive(Clone, Debug)]
 pub struct Request {
     pub verb: String,
+    /// Path without the query string
     pub path: String,
+    /// Query string without the leading `?`
+    pub query: String,
+    /// Decoded parameters of the query string
+    pub query_params: MultiMap,
+    /// Decoded parameters of the matched route
     pub path_params: HashMap<String, String>,
-    pub body: Option<String>,
+    pub body: Option<Vec<u8>>,
     pub headers: HashMap<String, String>,
+    /// Protocol version, e.g. `HTTP/1.1`
+    pub version: String,
 }
 
 impl Request {
+    /// Create a request without a body or headers.
+    /// The query string is split from the path.
+    pub fn new(verb: &str, path: &str) -> Request {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        Request {
+            verb: verb.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            query_params: parse_query(query),
+            path_params: HashMap::new(),
+            body: None,
+            headers: HashMap::new(),
+            version: "HTTP/1.1".to_string(),
+        }
+    }
+
+    /// Value of a header. Names are case-insensitive.
+    /// Headers sent several times are combined into a comma separated list.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|v| v.as_str())
+    }
+
+    /// Values of a header that is a comma separated list
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.header(name)
+            .map(|v| {
+                v.split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The method of the request
+    pub fn method(&self) -> HTTPVerb {
+        HTTPVerb::from(self.verb.as_str())
+    }
+
+    /// Path parameter parsed as `T`, e.g. `req.param::<u32>("id")`.
+    /// Fails with a `404 Not Found` response if the parameter is missing
+    /// or a `400 Bad Request` response if it can not be parsed.
+    pub fn param<T: FromStr>(&self, name: &str) -> Result<T, Response> {
+        let value = self.path_params.get(name).ok_or_else(|| {
+            Response::new(Some(format!("Missing path parameter '{}'", name)), 404)
+        })?;
+        value.parse().map_err(|_| {
+            Response::new(
+                Some(format!("Invalid path parameter '{}': '{}'", name, value)),
+                400,
+            )
+        })
+    }
+
+    /// First value of a query parameter
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_params
+            .get(name)
+            .and_then(|v| v.first())
+            .map(|v| v.as_str())
+    }
+
+    /// The body as text. Fails if it is not valid UTF-8.
+    pub fn text(&self) -> WebResult<&str> {
+        std::str::from_utf8(self.body.as_deref().unwrap_or_default())
+            .map_err(|e| Error::InvalidParams(format!("Body is not valid UTF-8. {}", e)))
+    }
+
+    /// Parse the body as JSON
+    pub fn json(&self) -> WebResult<ContextTree> {
+        parse_json(self.text()?)
+    }
+
+    /// Parse the body as a form based on the `Content-Type` header
+    pub fn form(&self) -> WebResult<Form> {
+        let content_type = self.header("Content-Type").unwrap_or_default();
+        Form::parse(content_type, self.body.as_deref().unwrap_or_default())
+    }
+
+    /// Locale of the request out of `available`.
+    /// A leading locale segment of the path, like `/nl/...`, is preferred
+    /// over the `Accept-Language` header.
+    /// ```
+    /// use unchained_web::router::Request;
+    /// let mut req = Request::new("GET", "/courses");
+    /// req.headers.insert("accept-language".to_string(), "nl-BE,en;q=0.5".to_string());
+    /// assert_eq!(req.locale(&["en", "nl"]), Some("nl".to_string()));
+    /// assert_eq!(Request::new("GET", "/en/courses").locale(&["en", "nl"]), Some("en".to_string()));
+    /// ```
+    pub fn locale(&self, available: &[&str]) -> Option<String> {
+        locale_from_path(&self.path, available)
+            .map(|(locale, _)| locale)
+            .or_else(|| negotiate_locale(self.header("Accept-Language")?, available))
+    }
+
     pub fn set_path_params(&mut self, params: HashMap<String, String>) -> &mut Self {
         self.path_params = params;
         self
     }
 }
 
+/// Request methods.
+/// See https://www.rfc-editor.org/rfc/rfc9110#name-methods
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HTTPVerb {
     GET,
+    HEAD,
     POST,
-    UPDATE,
+    PUT,
     DELETE,
-    HEAD,
+    CONNECT,
+    OPTIONS,
+    TRACE,
+    PATCH,
+    /// Any other method, e.g. `PROPFIND` of WebDAV
+    Custom(String),
+}
+
+impl HTTPVerb {
+    /// Standard methods in the order they are listed in `Allow` headers
+    pub const STANDARD: [HTTPVerb; 9] = [
+        HTTPVerb::GET,
+        HTTPVerb::HEAD,
+        HTTPVerb::POST,
+        HTTPVerb::PUT,
+        HTTPVerb::DELETE,
+        HTTPVerb::CONNECT,
+        HTTPVerb::OPTIONS,
+        HTTPVerb::TRACE,
+        HTTPVerb::PATCH,
+    ];
+}
+
+impl From<&str> for HTTPVerb {
+    /// Methods are case-sensitive, so `get` is a custom method
+    fn from(value: &str) -> Self {
+        HTTPVerb::STANDARD
+            .into_iter()
+            .find(|verb| verb.to_string() == value)
+            .unwrap_or_else(|| HTTPVerb::Custom(value.to_string()))
+    }
 }
 
 impl Display for HTTPVerb {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             HTTPVerb::GET => write!(f, "GET"),
+            HTTPVerb::HEAD => write!(f, "HEAD"),
             HTTPVerb::POST => write!(f, "POST"),
-            HTTPVerb::UPDATE => write!(f, "UPDATE"),
+            HTTPVerb::PUT => write!(f, "PUT"),
             HTTPVerb::DELETE => write!(f, "DELETE"),
-            HTTPVerb::HEAD => write!(f, "HEAD"),
+            HTTPVerb::CONNECT => write!(f, "CONNECT"),
+            HTTPVerb::OPTIONS => write!(f, "OPTIONS"),
+            HTTPVerb::TRACE => write!(f, "TRACE"),
+            HTTPVerb::PATCH => write!(f, "PATCH"),
+            HTTPVerb::Custom(verb) => write!(f, "{}", verb),
         }
     }
 }
 
+/// Writes a response body to the connection as it is produced
+pub type StreamBody = Box<dyn FnOnce(&mut dyn Write) -> WebResult<()> + Send>;
+
+/// Handles a request while reading its body from the connection
+pub type UploadHandler = Box<dyn Fn(Request, &mut dyn Read) -> Response + Sync + Send>;
+
 pub struct Response {
     pub bytes: Option<Vec<u8>>,
     pub status_code: u32,
     pub headers: HashMap<String, String>,
+    /// Body written straight to the connection instead of [Response::bytes].
+    /// Sent with chunked transfer encoding unless a `Content-Length` header is set.
+    pub stream: Option<StreamBody>,
 }
 
 impl Response {
@@ -50,6 +221,7 @@ impl Response {
             bytes: content.map(|s| s.as_bytes().to_vec()),
             status_code,
             headers: HashMap::new(),
+            stream: None,
         }
     }
     pub fn new_200(content: String) -> Response {
@@ -57,6 +229,30 @@ impl Response {
             bytes: Some(content.as_bytes().to_vec()),
             status_code: 200,
             headers: HashMap::new(),
+            stream: None,
+        }
+    }
+
+    /// Create a response where the body is written by `write_body`
+    /// while the response is sent.
+    /// ```
+    /// use unchained_web::{router::Response, templates::render::{render_to, RenderOptions}};
+    ///
+    /// let template = "<h1>{* get title *}</h1>".to_string();
+    /// let response = Response::new_stream(200, move |writer| {
+    ///     let context = [("title".to_string(), "Streamed".into())].into();
+    ///     render_to(&template, Some(context), &RenderOptions::empty(), writer)
+    /// });
+    /// ```
+    pub fn new_stream<F>(status_code: u32, write_body: F) -> Response
+    where
+        F: FnOnce(&mut dyn Write) -> WebResult<()> + Send + 'static,
+    {
+        Response {
+            bytes: None,
+            status_code,
+            headers: HashMap::new(),
+            stream: Some(Box::new(write_body)),
         }
     }
 
@@ -70,23 +266,47 @@ pub enum ResponseContent {
     Str(String),
     Bytes(Vec<u8>),
     FromRequest(Box<dyn Fn(Request) -> Response + Sync + Send>),
+    /// Files at the request path, relative to the working directory
     FolderAccess,
+    /// Files from a directory, at the request path after the static part of the route
+    Static(StaticFiles),
+    /// Handler reading the body from a stream instead of `Request.body`,
+    /// so large uploads are not buffered in memory.
+    Upload(UploadHandler),
 }
 
 pub struct Route {
-    pub verb: HTTPVerb,
+    /// Methods the route responds to. Responds to any method if empty.
+    pub verbs: Vec<HTTPVerb>,
     pub path: String,
     pub response: ResponseContent,
     pub returned_headers: HashMap<String, String>,
+    /// Middleware running when the route responds, in order
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    /// Name for creating links to the route. See [Urls]
+    pub name: Option<String>,
+    /// `Cache-Control` of responses that do not set it
+    pub cache_policy: Option<CachePolicy>,
+    /// ETag of a `Str` or `Bytes` response, hashed on the first response
+    etag: OnceLock<String>,
 }
 
 impl Route {
     pub fn new(verb: HTTPVerb, path: &str, response: ResponseContent) -> Route {
+        Route::with_verbs(&[verb], path, response)
+    }
+
+    /// Route responding to several methods, e.g. `GET` and `POST` for a form
+    pub fn with_verbs(verbs: &[HTTPVerb], path: &str, response: ResponseContent) -> Route {
         Route {
-            verb,
+            verbs: verbs.to_vec(),
             path: path.to_string(),
             response,
             returned_headers: HashMap::new(),
+            middleware: Vec::new(),
+            name: None,
+            cache_policy: None,
+            etag: OnceLock::new(),
         }
     }
 
@@ -96,97 +316,447 @@ impl Route {
         response: ResponseContent,
         headers: HashMap<String, String>,
     ) -> Route {
-        Route {
-            verb,
-            path: path.to_string(),
-            response,
-            returned_headers: headers,
+        let mut route = Route::new(verb, path, response);
+        route.returned_headers = headers;
+        route
+    }
+
+    /// Add middleware running when this route responds
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// ETag of a `Str` or `Bytes` response.
+    /// It is hashed once, so `response` should not change after the route responded.
+    pub fn etag(&self) -> Option<&str> {
+        let content = match &self.response {
+            ResponseContent::Str(s) => s.as_bytes(),
+            ResponseContent::Bytes(b) => b.as_slice(),
+            _ => return None,
+        };
+        Some(self.etag.get_or_init(|| content_etag(content)))
+    }
+
+    pub fn set_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) -> &mut Self {
+        self.cache_policy = Some(policy);
+        self
+    }
+
+    /// Route responding to every method
+    pub fn any(path: &str, response: ResponseContent) -> Route {
+        Route::with_verbs(&[], path, response)
+    }
+
+    /// Path parameters if the route matches the path, ignoring the method
+    pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        RouteTree::new(std::slice::from_ref(self))
+            .match_path(path)
+            .map(|(_, params)| params)
+    }
+
+    /// Whether the route responds to the method
+    pub fn accepts(&self, verb: &HTTPVerb) -> bool {
+        self.verbs.is_empty() || self.verbs.contains(verb)
+    }
+}
+
+/// Group of routes sharing middleware and default headers,
+/// which can be nested under a path prefix or merged with other routers.
+/// ```
+/// use unchained_web::{
+///     middleware::after,
+///     router::{HTTPVerb::*, ResponseContent, Route, Router},
+///     server::Server,
+/// };
+///
+/// let mut blog = Router::new();
+/// blog.route(Route::new(GET, "/", ResponseContent::Str("Posts".to_string())))
+///     .route(Route::new(GET, "/:slug", ResponseContent::Str("Post".to_string())))
+///     .add_default_header("Cache-Control", "max-age=60");
+///
+/// let mut site = Router::new();
+/// site.route(Route::new(GET, "/", ResponseContent::Str("Home".to_string())))
+///     .nest("/blog", blog)
+///     .add_middleware(after(|_, res| {
+///         res.add_header("X-Frame-Options", "DENY");
+///     }));
+/// let routes = site.into_routes();
+/// assert_eq!(routes[2].path, "/blog/:slug");
+/// let server = Server::new(routes);
+/// ```
+#[derive(Default)]
+pub struct Router {
+    pub routes: Vec<Route>,
+    /// Middleware running before the middleware of each route
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    /// Headers added to routes that do not set them
+    pub default_headers: HashMap<String, String>,
+    /// Cache policy of routes without one
+    pub cache_policy: Option<CachePolicy>,
+}
+
+/// Join a prefix like `/blog` with a route path like `/:slug`
+fn join_paths(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+    match (prefix.is_empty(), path.is_empty()) {
+        (true, _) => format!("/{}", path),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{}/{}", prefix, path),
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route(&mut self, route: Route) -> &mut Self {
+        self.routes.push(route);
+        self
+    }
+
+    pub fn named_route(&mut self, name: &str, mut route: Route) -> &mut Self {
+        route.set_name(name);
+        self.route(route)
+    }
+
+    /// Add middleware running for every route in the group
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn add_default_header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.default_headers
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn set_cache_policy(&mut self, policy: CachePolicy) -> &mut Self {
+        self.cache_policy = Some(policy);
+        self
+    }
+
+    /// Add the routes of `router` with their paths under `prefix`.
+    /// [ResponseContent::FolderAccess] routes read files from the prefixed path.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> &mut Self {
+        for mut route in router.into_routes() {
+            route.path = join_paths(prefix, &route.path);
+            self.routes.push(route);
+        }
+        self
+    }
+
+    /// Add the routes of another router, keeping its middleware and headers
+    pub fn merge(&mut self, router: Router) -> &mut Self {
+        self.nest("", router)
+    }
+
+    /// Paths of the named routes.
+    /// Fails if two routes with different paths have the same name.
+    pub fn urls(&self) -> WebResult<Urls> {
+        Urls::from_routes(&self.routes)
+    }
+
+    /// Path to the route with the name, see [Urls::url_for]
+    pub fn url_for(&self, name: &str, params: &HashMap<String, String>) -> WebResult<String> {
+        self.urls()?.url_for(name, params)
+    }
+
+    /// Routes with the middleware and default headers of the group applied
+    pub fn into_routes(self) -> Vec<Route> {
+        let mut routes = self.routes;
+        for route in routes.iter_mut() {
+            let mut middleware = self.middleware.clone();
+            middleware.append(&mut route.middleware);
+            route.middleware = middleware;
+            if route.cache_policy.is_none() {
+                route.cache_policy = self.cache_policy.clone();
+            }
+            for (key, value) in self.default_headers.iter() {
+                let exists = route
+                    .returned_headers
+                    .keys()
+                    .any(|k| k.eq_ignore_ascii_case(key));
+                if !exists {
+                    route.returned_headers.insert(key.clone(), value.clone());
+                }
+            }
         }
+        routes
     }
 }
 
-fn read_file_to_respond(file: &str) -> Response {
-    let buf = PathBuf::from(file);
-    let file = std::fs::read(buf).ok();
-    Response {
-        bytes: file.clone(),
-        status_code: if file.is_some() { 200 } else { 404 },
-        headers: HashMap::new(),
+/// Paths of named routes, for creating links that follow the routes when they change.
+/// ```
+/// use std::collections::HashMap;
+/// use unchained_web::router::{HTTPVerb::*, ResponseContent, Route, Router};
+///
+/// let mut router = Router::new();
+/// router.named_route(
+///     "course_detail",
+///     Route::new(GET, "/courses/:courseid", ResponseContent::FolderAccess),
+/// );
+/// let params = HashMap::from([("courseid".to_string(), "CS4505".to_string())]);
+/// assert_eq!(router.url_for("course_detail", &params).unwrap(), "/courses/CS4505");
+/// assert!(router.url_for("course_detail", &HashMap::new()).is_err());
+/// assert!(router.url_for("missing", &params).is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Urls {
+    /// Route paths by name
+    pub paths: HashMap<String, String>,
+}
+
+impl Urls {
+    pub fn new() -> Urls {
+        Urls::default()
+    }
+
+    /// Paths of the named routes.
+    /// Fails if two routes with different paths have the same name.
+    pub fn from_routes(routes: &[Route]) -> WebResult<Urls> {
+        let mut urls = Urls::new();
+        for route in routes {
+            if let Some(name) = &route.name {
+                urls.add(name, &route.path)?;
+            }
+        }
+        Ok(urls)
+    }
+
+    /// Add the path of a named route.
+    /// Fails if the name is already used for another path.
+    pub fn add(&mut self, name: &str, path: &str) -> WebResult<&mut Self> {
+        match self.paths.get(name) {
+            Some(existing) if existing != path => Err(Error::InvalidParams(format!(
+                "Route name '{}' is used by {} and {}",
+                name, existing, path
+            ))),
+            _ => {
+                self.paths.insert(name.to_string(), path.to_string());
+                Ok(self)
+            }
+        }
+    }
+
+    /// Path to the route with the name, with its parameters filled by [fill_path_params]
+    pub fn url_for(&self, name: &str, params: &HashMap<String, String>) -> WebResult<String> {
+        let path = self
+            .paths
+            .get(name)
+            .ok_or_else(|| Error::InvalidParams(format!("No route named '{}'", name)))?;
+        fill_path_params(path, params)
     }
 }
 
+/// Create a path from a route by replacing `:name` and `*name` parameters with
+/// percent-encoded values.
+/// Fails if a parameter is missing, does not match its constraint,
+/// or the route has an unnamed wildcard.
+/// ```
+/// use std::collections::HashMap;
+/// use unchained_web::router::fill_path_params;
+/// let params = HashMap::from([("courseid".to_string(), "CS4505".to_string())]);
+/// assert_eq!(fill_path_params("courses/:courseid", &params).unwrap(), "/courses/CS4505");
+/// assert_eq!(fill_path_params("courses/:page<int>?", &params).unwrap(), "/courses");
+/// ```
+pub fn fill_path_params(route: &str, params: &HashMap<String, String>) -> WebResult<String> {
+    let mut path = String::new();
+    let missing = |name: &str| {
+        Error::InvalidParams(format!("Missing parameter {} for route {}", name, route))
+    };
+    for segment in parse_route(route) {
+        let filled = match segment {
+            Segment::Static(part) => part.to_string(),
+            Segment::Param {
+                name,
+                constraint,
+                optional,
+            } => {
+                let value = match params.get(name) {
+                    Some(value) => value,
+                    None if optional => continue,
+                    None => return Err(missing(name)),
+                };
+                if let Some(constraint) = constraint {
+                    if value.is_empty() || !constraint_pattern(constraint)?.is_match(value) {
+                        return Err(Error::InvalidParams(format!(
+                            "Parameter {} '{}' does not match the constraint of route {}",
+                            name, value, route
+                        )));
+                    }
+                }
+                percent_encode(value)
+            }
+            Segment::CatchAll(Some(name)) => {
+                let value = params.get(name).ok_or_else(|| missing(name))?;
+                value
+                    .split('/')
+                    .map(percent_encode)
+                    .collect::<Vec<_>>()
+                    .join("/")
+            }
+            Segment::Wildcard | Segment::CatchAll(None) => {
+                return Err(Error::InvalidParams(format!(
+                    "Can not create a path from wildcard route {}",
+                    route
+                )))
+            }
+        };
+        path.push('/');
+        path.push_str(&filled);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+/// Decoded part of the request path after the static segments of the route,
+/// e.g. `css/site.css` for `/assets/*` and `/assets/css/site.css`
+fn path_after_static_segments(route: &str, request_path: &str) -> String {
+    let prefix = parse_route(route)
+        .iter()
+        .take_while(|s| matches!(s, Segment::Static(_)))
+        .count();
+    let rest = request_path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .skip(prefix)
+        .collect::<Vec<_>>()
+        .join("/");
+    percent_decode(&rest)
+}
+
 /// Checks if the route matches the path.
 /// Finds path params in the request.
-/// Removes all empty parameters.
+#[cfg(test)]
 fn compare_route_w_path_and_get_path_params(
     route: &str,
     req_path: &str,
 ) -> (bool, HashMap<String, String>) {
-    let route_parts = route
-        .trim_start_matches('/')
-        .trim_end_matches('/')
-        .split('/')
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<_>>();
-    let req_parts = req_path
-        .trim_start_matches('/')
-        .trim_end_matches('/')
-        .split('/')
-        .filter(|s| !s.is_empty());
-    let mut params = HashMap::new();
-    let mut match_route = true;
-    let last_is_star = route.ends_with('*');
-
-    for (route_part, req_part) in route_parts.iter().zip_longest(req_parts) {
-        match (route_part, req_part) {
-            (None, None) => break,
-            (None, Some(_)) => {
-                match_route = last_is_star;
-                break;
-            }
-            (Some(part), None) => {
-                match_route = *part == "*";
-                break;
-            }
-            (Some(route_part), Some(req_part)) => {
-                if let Some(route_no_prefix) = route_part.strip_prefix(':') {
-                    params.insert(route_no_prefix.to_string(), req_part.to_string());
-                } else if *route_part != req_part {
-                    match_route = *route_part == "*";
-                    if !match_route {
-                        break;
-                    }
-                }
-            }
-        }
+    let route = Route::new(HTTPVerb::GET, route, ResponseContent::Str(String::new()));
+    match route.matches(req_path) {
+        Some(params) => (true, params),
+        None => (false, HashMap::new()),
     }
-    (match_route, params)
 }
 
+/// Find the most specific route matching the verb and path of the request.
+/// See [RouteTree::find]
+pub fn find_route<'a>(
+    routes: &'a [Route],
+    request: &Request,
+) -> Option<(&'a Route, HashMap<String, String>)> {
+    let (index, params) = RouteTree::new(routes).find_index(request)?;
+    Some((&routes[index], params))
+}
+
+/// Methods of the routes matching the path, for the `Allow` header.
+/// See [RouteTree::allowed_verbs]
+pub fn allowed_verbs(routes: &[Route], path: &str) -> Vec<HTTPVerb> {
+    RouteTree::new(routes).allowed_verbs(path)
+}
+
+/// `204` response to an `OPTIONS` request or `405` response to an unsupported method
+pub(crate) fn allow_response(status_code: u32, allowed: &[HTTPVerb]) -> Response {
+    let allow = allowed
+        .iter()
+        .map(|verb| verb.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut response = Response::new(None, status_code);
+    response.add_header("Allow", &allow);
+    response
+}
+
+/// Respond to a request with the most specific matching route.
+/// Compiles the routes first, so use a [RouteTree] when responding to many requests.
 pub fn check_routes(routes: &[Route], request: Request) -> Response {
-    for route in routes {
-        let route_verb = route.verb.to_string();
-
-        let (matches, params) =
-            compare_route_w_path_and_get_path_params(&route.path, &request.path);
-        let modified_request = request.clone().set_path_params(params).to_owned();
-
-        if route_verb == request.verb && matches {
-            return match &route.response {
-                ResponseContent::Str(s) => Response::new_200(s.to_string()),
-                ResponseContent::Bytes(b) => Response {
-                    bytes: Some(b.to_vec()),
-                    status_code: 200,
-                    headers: HashMap::new(),
-                },
-                ResponseContent::FromRequest(f) => f(modified_request),
-                ResponseContent::FolderAccess => {
-                    read_file_to_respond(request.path.trim_start_matches('/'))
-                }
+    let body = request.body.clone().unwrap_or_default();
+    check_routes_with_body(routes, request, &mut body.as_slice())
+}
+
+/// Like [check_routes], but [ResponseContent::Upload] routes read the body from `body`
+pub fn check_routes_with_body(routes: &[Route], request: Request, body: &mut dyn Read) -> Response {
+    RouteTree::new(routes).respond(request, body)
+}
+
+/// Response of a route to a request with the given path parameters
+pub(crate) fn route_response(
+    route: &Route,
+    mut request: Request,
+    params: HashMap<String, String>,
+    body: &mut dyn Read,
+) -> Response {
+    request.set_path_params(params);
+    let mut response = match &route.response {
+        ResponseContent::Str(s) => {
+            let mut response = Response::new_200(s.to_string());
+            response.add_header("ETag", route.etag().unwrap_or_default());
+            response
+        }
+        ResponseContent::Bytes(b) => {
+            let mut response = Response {
+                bytes: Some(b.to_vec()),
+                status_code: 200,
+                headers: HashMap::new(),
+                stream: None,
             };
+            response.add_header("ETag", route.etag().unwrap_or_default());
+            response
+        }
+        ResponseContent::FromRequest(f) => f(request),
+        ResponseContent::FolderAccess => {
+            StaticFiles::new(".").respond(&percent_decode(&request.path), &request)
+        }
+        ResponseContent::Static(files) => {
+            let path = path_after_static_segments(&route.path, &request.path);
+            files.respond(&path, &request)
+        }
+        ResponseContent::Upload(f) => f(request, body),
+    };
+    for (key, value) in route.returned_headers.iter() {
+        response
+            .headers
+            .entry(key.to_string())
+            .or_insert(value.to_string());
+    }
+    let has_cache_control = response
+        .headers
+        .keys()
+        .any(|k| k.eq_ignore_ascii_case("Cache-Control"));
+    if let (Some(policy), false) = (&route.cache_policy, has_cache_control) {
+        response.add_header("Cache-Control", &policy.header());
+    }
+    response
+}
+
+/// Remove the body of a response to a `HEAD` request,
+/// keeping the headers a `GET` request would get.
+pub(crate) fn strip_body(response: &mut Response) {
+    let has_header = |response: &Response, name: &str| {
+        response
+            .headers
+            .keys()
+            .any(|k| k.eq_ignore_ascii_case(name))
+    };
+    if let Some(bytes) = response.bytes.take() {
+        if !has_header(response, "Content-Length") {
+            response.add_header("Content-Length", &bytes.len().to_string());
         }
     }
-    Response::new(None, 404)
+    if response.stream.take().is_some() && !has_header(response, "Content-Length") {
+        response.add_header("Transfer-Encoding", "chunked");
+    }
 }
 
 #[cfg(test)]
@@ -295,4 +865,208 @@ mod tests {
             assert!(!matches);
         }
     }
+
+    #[test]
+    fn test_query_and_decoded_params() {
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/courses/:name",
+            ResponseContent::FromRequest(Box::new(|req| {
+                Response::new_200(format!(
+                    "{} {:?}",
+                    req.path_params["name"],
+                    req.query_param("tag")
+                ))
+            })),
+        )];
+        let request = Request::new("GET", "/courses/machine%20learning?tag=ml&tag=ai");
+        assert_eq!(request.path, "/courses/machine%20learning");
+        assert_eq!(request.query, "tag=ml&tag=ai");
+        assert_eq!(request.query_params["tag"], vec!["ml", "ai"]);
+        let response = check_routes(&routes, request);
+        assert_eq!(response.bytes.unwrap(), b"machine learning Some(\"ml\")");
+    }
+
+    #[test]
+    fn test_encoded_slash_in_params() {
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/courses/:name",
+            ResponseContent::FromRequest(Box::new(|req| {
+                Response::new_200(req.path_params["name"].clone())
+            })),
+        )];
+        let request = Request::new("GET", "/courses/..%2F..%2F..%2fetc%2Fsecret");
+        let response = check_routes(&routes, request);
+        assert_eq!(response.bytes.unwrap(), b"..%2F..%2F..%2Fetc%2Fsecret");
+    }
+
+    #[test]
+    fn test_verbs() {
+        assert_eq!(HTTPVerb::from("PATCH"), HTTPVerb::PATCH);
+        assert_eq!(HTTPVerb::from("get"), HTTPVerb::Custom("get".to_string()));
+        assert_eq!(
+            HTTPVerb::Custom("PROPFIND".to_string()).to_string(),
+            "PROPFIND"
+        );
+    }
+
+    #[test]
+    fn test_route_etag() {
+        let route = Route::new(
+            HTTPVerb::GET,
+            "/",
+            ResponseContent::Str("content".to_string()),
+        );
+        let etag = content_etag(b"content");
+        assert_eq!(route.etag(), Some(etag.as_str()));
+        let response = check_routes(std::slice::from_ref(&route), Request::new("GET", "/"));
+        assert_eq!(response.headers["ETag"], etag);
+        let route = Route::new(HTTPVerb::GET, "/", ResponseContent::FolderAccess);
+        assert_eq!(route.etag(), None);
+    }
+
+    #[test]
+    fn test_method_handling() {
+        let text = |s: &str| ResponseContent::Str(s.to_string());
+        let routes = vec![
+            Route::new(HTTPVerb::GET, "/page", text("page")),
+            Route::with_verbs(&[HTTPVerb::PUT, HTTPVerb::PATCH], "/page", text("updated")),
+            Route::new(
+                HTTPVerb::Custom("PURGE".to_string()),
+                "/page",
+                text("purged"),
+            ),
+            Route::any("/any", text("any")),
+            Route::new(
+                HTTPVerb::GET,
+                "/stream",
+                ResponseContent::FromRequest(Box::new(|_| {
+                    Response::new_stream(200, |w| Ok(w.write_all(b"streamed")?))
+                })),
+            ),
+        ];
+        let respond = |verb: &str, path: &str| check_routes(&routes, Request::new(verb, path));
+
+        assert_eq!(respond("PATCH", "/page").bytes.unwrap(), b"updated");
+        assert_eq!(respond("PURGE", "/page").bytes.unwrap(), b"purged");
+        assert_eq!(respond("DELETE", "/any").bytes.unwrap(), b"any");
+        assert_eq!(respond("DELETE", "/missing").status_code, 404);
+
+        let head = respond("HEAD", "/page");
+        assert_eq!(head.status_code, 200);
+        assert!(head.bytes.is_none());
+        assert_eq!(head.headers["Content-Length"], "4");
+        let head = respond("HEAD", "/stream");
+        assert!(head.stream.is_none());
+        assert_eq!(head.headers["Transfer-Encoding"], "chunked");
+
+        let not_allowed = respond("POST", "/page");
+        assert_eq!(not_allowed.status_code, 405);
+        assert_eq!(
+            not_allowed.headers["Allow"],
+            "GET, HEAD, PUT, OPTIONS, PATCH, PURGE"
+        );
+        let options = respond("OPTIONS", "/page");
+        assert_eq!(options.status_code, 204);
+        assert_eq!(options.headers["Allow"], not_allowed.headers["Allow"]);
+        assert_eq!(respond("OPTIONS", "/any").status_code, 200);
+        assert_eq!(
+            respond("OPTIONS", "*").headers["Allow"],
+            "GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH, PURGE"
+        );
+    }
+
+    #[test]
+    fn test_typed_params() {
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/users/:id",
+            ResponseContent::FromRequest(Box::new(|req| {
+                let id = match req.param::<u32>("id") {
+                    Ok(id) => id,
+                    Err(response) => return response,
+                };
+                assert!(req.param::<u32>("missing").unwrap_err().status_code == 404);
+                Response::new_200(format!("user {}", id + 1))
+            })),
+        )];
+        let respond = |path: &str| check_routes(&routes, Request::new("GET", path));
+        assert_eq!(respond("/users/41").bytes.unwrap(), b"user 42");
+        assert_eq!(respond("/users/abc").status_code, 400);
+    }
+
+    #[test]
+    fn test_fill_constrained_params() {
+        let params = HashMap::from([
+            ("id".to_string(), "12".to_string()),
+            ("rest".to_string(), "a/b".to_string()),
+        ]);
+        assert_eq!(
+            fill_path_params("/posts/:id<int>/*rest", &params).unwrap(),
+            "/posts/12/a/b"
+        );
+        assert_eq!(
+            fill_path_params("/posts/:id/:page<uint>?", &params).unwrap(),
+            "/posts/12"
+        );
+        assert!(fill_path_params("/posts/:id<alpha>", &params).is_err());
+        assert!(fill_path_params("/posts/*", &params).is_err());
+
+        let params = HashMap::from([
+            ("id".to_string(), "a b".to_string()),
+            ("rest".to_string(), "café/x?y".to_string()),
+        ]);
+        let path = fill_path_params("/posts/:id/*rest", &params).unwrap();
+        assert_eq!(path, "/posts/a%20b/caf%C3%A9/x%3Fy");
+        let route = Route::new(
+            HTTPVerb::GET,
+            "/posts/:id/*rest",
+            ResponseContent::FolderAccess,
+        );
+        assert_eq!(route.matches(&path).unwrap(), params);
+    }
+
+    #[test]
+    fn test_router_groups() {
+        let tag = |name: &'static str| {
+            crate::middleware::after(move |_, res| {
+                let tags = res.headers.get("X-Tags").cloned().unwrap_or_default();
+                res.add_header("X-Tags", &format!("{}{}", tags, name));
+            })
+        };
+        let str_route =
+            |path: &str| Route::new(HTTPVerb::GET, path, ResponseContent::Str(path.to_string()));
+
+        let mut post = str_route("/:slug");
+        post.add_middleware(tag("route"));
+        post.returned_headers
+            .insert("cache-control".to_string(), "no-store".to_string());
+        let mut blog = Router::new();
+        blog.route(str_route("/"))
+            .route(post)
+            .add_middleware(tag("blog"))
+            .add_default_header("Cache-Control", "max-age=60");
+        let mut docs = Router::new();
+        docs.route(str_route("guide"));
+
+        let mut site = Router::new();
+        site.add_middleware(tag("site"))
+            .nest("/blog/", blog)
+            .merge(docs);
+        let routes = site.into_routes();
+        let paths = routes.iter().map(|r| r.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/blog", "/blog/:slug", "/guide"]);
+
+        let respond = |path: &str| check_routes(&routes, Request::new("GET", path));
+        // `after` runs from the innermost middleware out
+        let response = respond("/blog/hello");
+        assert_eq!(response.headers["X-Tags"], "routeblogsite");
+        assert_eq!(response.headers["cache-control"], "no-store");
+        assert!(!response.headers.contains_key("Cache-Control"));
+        let response = respond("/blog");
+        assert_eq!(response.headers["X-Tags"], "blogsite");
+        assert_eq!(response.headers["Cache-Control"], "max-age=60");
+        assert_eq!(respond("/guide").headers["X-Tags"], "site");
+    }
 }
@@ -1,192 +1,959 @@
 use std::{
+    cell::RefCell,
     collections::HashMap,
-    io::{BufRead, BufReader, BufWriter, Read, Write},
-    net::{Shutdown, TcpListener, TcpStream},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "http2")]
+use crate::http2;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsOptions, TlsStream};
 use crate::{
     error::{Error, WebResult},
-    router::{check_routes, Request, Route},
+    lifecycle::{Connection, Lifecycle, ServerHandle},
+    listener::{Accepted, BoundAddress, Listener},
+    logging::{AccessLog, ConsoleLogger, Level, Logger},
+    middleware::{self, Middleware},
+    route_tree::RouteTree,
+    router::{Request, Response, ResponseContent, Route},
     workers::Workers,
 };
 
+/// Connection to a client, like a [TcpStream] or a TLS stream
+pub(crate) trait Transport: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    /// Close the writing half after the last response
+    fn close(&mut self) -> std::io::Result<()>;
+
+    /// Address of the client, for access logs
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Protocol negotiated with ALPN during the TLS handshake
+    #[cfg(feature = "http2")]
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Transport for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()?;
+        self.sock.shutdown(Shutdown::Write)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.sock.peer_addr().ok()
+    }
+
+    #[cfg(feature = "http2")]
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol().map(|p| p.to_vec())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        (**self).close()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    #[cfg(feature = "http2")]
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        (**self).alpn_protocol()
+    }
+}
+
+/// Transport read and written through shared references, like `&TcpStream`,
+/// so responses can be written while the connection is read
+struct Shared<T>(RefCell<T>);
+
+impl<T: Read> Read for &Shared<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<T: Write> Write for &Shared<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
 /// Close Write part of stream and map io::Error to [Error::Connection]
-fn close_stream(stream: TcpStream) -> Result<(), Error> {
-    stream
-        .shutdown(Shutdown::Write)
-        .map_err(|e| Error::Connection(format!("Could not close TCP stream properly. {}", e)))
+fn close_stream<T: Transport>(mut stream: T) -> Result<(), Error> {
+    match stream.close() {
+        // The client closed the connection first
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset
+            ) =>
+        {
+            Ok(())
+        }
+        result => result
+            .map_err(|e| Error::Connection(format!("Could not close TCP stream properly. {}", e))),
+    }
 }
 
-fn handle_connection(
-    stream: TcpStream,
-    routes: &[Route],
+/// Whether the client wants the connection to stay open after the response.
+/// HTTP/1.1 connections are persistent unless `Connection: close` is sent,
+/// while HTTP/1.0 connections close unless `Connection: keep-alive` is sent.
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.header_values("Connection");
+    let has_option = |option: &str| connection.iter().any(|c| c.eq_ignore_ascii_case(option));
+    match request.version.as_str() {
+        "HTTP/1.0" => has_option("keep-alive"),
+        _ => !has_option("close"),
+    }
+}
+
+/// Answer requests on a connection in the order they are received
+/// until it closes, is idle for too long, reaches the request limit or the server stops.
+fn handle_connection<T: Transport, R: AsRef<[Route]>>(
+    stream: T,
+    tree: &RouteTree<R>,
     options: &ServerOptions,
+    connection: &Connection,
 ) -> WebResult<()> {
-    for _ in 0..options.threads - 1 {
-        let res = respond_to_request(&stream, routes, options);
-        if let Ok(should_close) = res.as_ref() {
-            if *should_close {
+    stream.set_read_timeout(Some(options.keep_alive_timeout))?;
+    let client = stream.peer_addr();
+    let stream = Shared(RefCell::new(stream));
+    // Keep the reader for the whole connection so pipelined requests are not lost
+    let mut reader = BufReader::new(&stream);
+    #[cfg(feature = "http2")]
+    {
+        // Reading also completes the TLS handshake, which negotiates the protocol
+        let preface = reader.fill_buf().is_ok_and(http2::starts_with_preface);
+        let alpn = stream.0.borrow().alpn_protocol();
+        if preface || alpn.as_deref() == Some(b"h2") {
+            // Closing the connection on shutdown makes it send GOAWAY after the current responses
+            connection.set_idle(true);
+            http2::serve(&mut reader, &stream, options, client, |request| {
+                middleware::run(&options.middleware, request, |request| {
+                    let body = request.body.clone().unwrap_or_default();
+                    tree.respond(request, &mut body.as_slice())
+                })
+            })?;
+            drop(reader);
+            return close_stream(stream.0.into_inner());
+        }
+    }
+    let mut handled = 0;
+    loop {
+        // Only the first request is read after the server started stopping
+        if !connection.set_idle(true) && handled > 0 {
+            break;
+        }
+        let (request, body_length) = match read_request_head(&mut reader, &options.limits) {
+            Ok(Some(head)) => head,
+            Ok(None) => break,
+            // TLS clients may close the connection without a `close_notify` alert
+            Err(ParseError::Io(e))
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::UnexpectedEof
+                ) =>
+            {
+                break
+            }
+            Err(ParseError::Io(e)) => {
+                return Err(Error::Connection(format!(
+                    "Could not read from stream. {}",
+                    e
+                )))
+            }
+            Err(e) => {
+                options.logger.log(
+                    Level::Debug,
+                    format_args!("Invalid request. {}", e.message()),
+                );
+                let response = Response::new(Some(e.message()), e.status_code());
+                write_response(&stream, response, options, false, "HTTP/1.1")?;
                 break;
             }
+        };
+        handled += 1;
+        connection.set_idle(false);
+        let started = Instant::now();
+        let mut access = AccessLog::new(&request, client);
+        options.logger.log(
+            Level::Trace,
+            format_args!("Request headers {:?}", request.headers),
+        );
+        let mut keep_alive =
+            wants_keep_alive(&request) && handled < options.max_requests_per_connection;
+        let version = request.version.clone();
+        // HTTP/1.0 clients do not wait for 100 Continue
+        let expects_continue = version == "HTTP/1.1" && request.header("Expect").is_some();
+        let mut body = BodyReader::new(
+            &mut reader,
+            body_length,
+            options.limits.max_body_size,
+            expects_continue.then_some(&stream),
+        );
+
+        let mut read_error = None;
+        let response = middleware::run(&options.middleware, request, |mut request| {
+            let streams_body = tree
+                .find(&request)
+                .is_some_and(|(route, _)| matches!(route.response, ResponseContent::Upload(_)));
+            if streams_body {
+                return tree.respond(request, &mut body);
+            }
+            let mut bytes = Vec::new();
+            match body.read_to_end(&mut bytes).map_err(ParseError::from) {
+                Ok(_) => {
+                    request.body = (!bytes.is_empty()).then_some(bytes);
+                    let bytes = request.body.clone().unwrap_or_default();
+                    tree.respond(request, &mut bytes.as_slice())
+                }
+                Err(ParseError::Io(e)) => {
+                    read_error = Some(e);
+                    Response::new(None, 500)
+                }
+                Err(e) => {
+                    keep_alive = false;
+                    Response::new(Some(e.message()), e.status_code())
+                }
+            }
+        });
+        if let Some(e) = read_error {
+            return Err(Error::Connection(format!(
+                "Could not read body from stream. {}",
+                e
+            )));
+        }
+        keep_alive = keep_alive && !connection.is_stopping();
+        // The rest of the body has to be read before the next request
+        if keep_alive && !body.finished {
+            keep_alive =
+                !body.continue_pending() && std::io::copy(&mut body, &mut std::io::sink()).is_ok();
+        }
+        access.status = response.status_code;
+        let (keep_alive, bytes) = write_response(&stream, response, options, keep_alive, &version)?;
+        access.bytes = bytes;
+        access.duration = started.elapsed();
+        options.logger.access(&access);
+        if !keep_alive {
+            break;
         }
-        println!("Waiting for next request...");
     }
-    println!("Closing request");
-    close_stream(stream)?;
+    options.logger.log(
+        Level::Debug,
+        format_args!("Closing connection after {} requests", handled),
+    );
+    drop(reader);
+    close_stream(stream.0.into_inner())?;
     Ok(())
 }
 
-/// Returns true if connection should close
-fn respond_to_request(
-    mut stream: &TcpStream,
-    routes: &[Route],
-    options: &ServerOptions,
-) -> WebResult<bool> {
-    let mut content_read = String::new();
-    let mut buf_read = BufReader::new(&mut stream);
-    let res = buf_read.read_line(&mut content_read);
-    if res.is_err() {
-        return Err(Error::Connection(
-            "Could not read from stream. Invalid buffer.".to_string(),
-        ));
+/// Limits on the size of requests.
+/// Requests exceeding them are answered with
+/// `413 Content Too Large` or `431 Request Header Fields Too Large`.
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    /// Maximum bytes of the request line and headers together
+    pub max_header_size: usize,
+    /// Maximum number of header lines
+    pub max_headers: usize,
+    /// Maximum bytes of a request body
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_size: 16 * 1024,
+            max_headers: 100,
+            max_body_size: 10 * 1024 * 1024,
+        }
     }
+}
 
-    print!("[Request] {}", &content_read);
-    let (verb, path) = match content_read.split(' ').collect::<Vec<_>>()[..] {
-        [verb, path, _version] => (verb, path),
-        _ => {
-            return Err(Error::Connection(format!(
-                "Unimplemented request handle for: '{}",
-                content_read
-            )));
+/// Reasons a request could not be parsed
+#[derive(Debug)]
+enum ParseError {
+    BadRequest(String),
+    UriTooLong,
+    HeadersTooLarge,
+    ContentTooLarge,
+    NotImplemented(String),
+    ExpectationFailed,
+    VersionNotSupported,
+    Io(std::io::Error),
+}
+
+impl ParseError {
+    fn status_code(&self) -> u32 {
+        match self {
+            ParseError::BadRequest(_) | ParseError::Io(_) => 400,
+            ParseError::UriTooLong => 414,
+            ParseError::HeadersTooLarge => 431,
+            ParseError::ContentTooLarge => 413,
+            ParseError::NotImplemented(_) => 501,
+            ParseError::ExpectationFailed => 417,
+            ParseError::VersionNotSupported => 505,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ParseError::BadRequest(s) | ParseError::NotImplemented(s) => s.to_string(),
+            ParseError::Io(e) => e.to_string(),
+            e => reason_phrase(e.status_code()).to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(value: std::io::Error) -> Self {
+        match value.kind() {
+            ErrorKind::FileTooLarge => ParseError::ContentTooLarge,
+            ErrorKind::InvalidData => ParseError::BadRequest(value.to_string()),
+            _ => ParseError::Io(value),
+        }
+    }
+}
+
+/// Reason phrase of a status code
+pub fn reason_phrase(status_code: u32) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Characters allowed in methods and header names.
+/// See https://www.rfc-editor.org/rfc/rfc9110#name-tokens
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Read a line ending in LF of at most `max` bytes.
+/// Returns the line without the line ending, or None if the connection closed.
+fn read_line_limited<R: BufRead>(
+    reader: &mut R,
+    max: usize,
+    too_long: fn() -> ParseError,
+) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    let read = reader.take(max as u64 + 1).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(if read > max {
+            too_long()
+        } else {
+            ParseError::BadRequest("Connection closed in the middle of a line".to_string())
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ParseError::BadRequest("Request head is not valid UTF-8".to_string()))
+}
+
+/// Read and validate the request line and headers following RFC 9112.
+/// Returns None if the connection closed before a request was sent.
+/// See https://www.rfc-editor.org/rfc/rfc9112
+fn read_request_head<R: BufRead>(
+    reader: &mut R,
+    limits: &RequestLimits,
+) -> Result<Option<(Request, BodyLength)>, ParseError> {
+    let mut remaining = limits.max_header_size;
+
+    // Empty lines before the request line should be ignored
+    let request_line = loop {
+        match read_line_limited(reader, remaining, || ParseError::UriTooLong)? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => remaining = remaining.saturating_sub(2),
+            Some(line) => break line,
         }
     };
+    remaining = remaining.saturating_sub(request_line.len() + 2);
 
-    let mut headers = HashMap::new();
-    let mut content_read = String::new();
+    let (verb, target, version) = match request_line.split(' ').collect::<Vec<_>>()[..] {
+        [verb, target, version] => (verb, target, version),
+        _ => {
+            return Err(ParseError::BadRequest(format!(
+                "Invalid request line '{}'",
+                request_line
+            )))
+        }
+    };
+    if !is_token(verb) {
+        return Err(ParseError::BadRequest(format!("Invalid method '{}'", verb)));
+    }
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => {}
+        v if v.starts_with("HTTP/") => return Err(ParseError::VersionNotSupported),
+        _ => return Err(ParseError::BadRequest("Invalid HTTP version".to_string())),
+    }
+    let path = match target {
+        t if t.starts_with('/') => t.to_string(),
+        "*" if verb == "OPTIONS" => target.to_string(),
+        // Absolute form, e.g. http://example.com/path
+        t if t.starts_with("http://") || t.starts_with("https://") => {
+            let without_scheme = t.split_once("://").map(|(_, rest)| rest).unwrap_or(t);
+            match without_scheme.find('/') {
+                Some(index) => without_scheme[index..].to_string(),
+                None => "/".to_string(),
+            }
+        }
+        _ => {
+            return Err(ParseError::BadRequest(format!(
+                "Invalid request target '{}'",
+                target
+            )))
+        }
+    };
 
-    println!("\t[Buffer] {:?}", &buf_read);
-    // Read headers from stream
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut header_count = 0;
     loop {
-        content_read.clear();
-        let res = buf_read.read_line(&mut content_read);
-        if res.is_err() {
-            return Err(Error::Connection(
-                "Could not read from stream. Invalid buffer.".to_string(),
+        let line = read_line_limited(reader, remaining, || ParseError::HeadersTooLarge)?
+            .ok_or_else(|| {
+                ParseError::BadRequest("Connection closed before end of headers".to_string())
+            })?;
+        remaining = remaining.saturating_sub(line.len() + 2);
+        if line.is_empty() {
+            break;
+        }
+        header_count += 1;
+        if header_count > limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        if line.starts_with([' ', '\t']) {
+            return Err(ParseError::BadRequest(
+                "Obsolete line folding is not allowed".to_string(),
             ));
         }
-        match content_read
-            .trim()
-            .split(": ")
-            .collect::<Vec<_>>()
-            .as_slice()
-        {
-            [k, v] => headers.insert(k.to_string(), v.to_string()),
-            _ => None,
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::BadRequest(format!("Invalid header '{}'", line)));
         };
-        if content_read.trim().is_empty() {
-            break;
+        if !is_token(name) {
+            return Err(ParseError::BadRequest(format!(
+                "Invalid header name '{}'",
+                name
+            )));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+            return Err(ParseError::BadRequest(format!(
+                "Invalid value of header '{}'",
+                name
+            )));
+        }
+        // Several lines with the same name are combined into a list.
+        // Cookies are separated like in a single Cookie header.
+        let name = name.to_ascii_lowercase();
+        let separator = if name == "cookie" { "; " } else { ", " };
+        headers
+            .entry(name)
+            .and_modify(|v| {
+                v.push_str(separator);
+                v.push_str(value);
+            })
+            .or_insert(value.to_string());
+    }
+
+    if version == "HTTP/1.1" {
+        match headers.get("host") {
+            Some(host) if !host.contains(',') => {}
+            _ => {
+                return Err(ParseError::BadRequest(
+                    "Requests need exactly one Host header".to_string(),
+                ))
+            }
+        }
+    }
+    if let Some(expect) = headers.get("expect") {
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return Err(ParseError::ExpectationFailed);
+        }
+    }
+    if let Some(encoding) = headers.get("transfer-encoding") {
+        if headers.contains_key("content-length") {
+            return Err(ParseError::BadRequest(
+                "Both Transfer-Encoding and Content-Length are given".to_string(),
+            ));
+        }
+        if !encoding.eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::NotImplemented(format!(
+                "Transfer-Encoding '{}' is not supported",
+                encoding
+            )));
         }
     }
-    println!("\t[Req Headers] {:?}", &headers);
 
-    // Read body from stream
     // HTTP/1.1 Content-Length SHOULD be included in the headers when body is present
     // See https://www.rfc-editor.org/rfc/rfc9110.html#name-content-length
-    let body_len = headers
-        .get("Content-Length")
-        .unwrap_or(&String::from("0"))
-        .parse::<u64>()
-        .unwrap_or(0);
-    let mut buf = vec![0; body_len as usize];
-    let res = buf_read.read_exact(&mut buf);
-    let body = match res {
-        Ok(_) => String::from_utf8(buf).ok(),
-        Err(_) => {
-            return Err(Error::Connection(format!(
-                "Failed to read body of content length {}.",
-                body_len
-            )));
+    let body_length = match headers.get("content-length") {
+        None if headers.contains_key("transfer-encoding") => BodyLength::Chunked,
+        None => BodyLength::Fixed(0),
+        Some(value) => {
+            let mut lengths = value.split(',').map(|v| v.trim().parse::<usize>());
+            let first = lengths.next().and_then(|l| l.ok());
+            match first {
+                Some(len) if lengths.all(|l| l.ok() == Some(len)) => BodyLength::Fixed(len),
+                _ => {
+                    return Err(ParseError::BadRequest(format!(
+                        "Invalid Content-Length '{}'",
+                        value
+                    )))
+                }
+            }
         }
     };
+    if matches!(body_length, BodyLength::Fixed(len) if len > limits.max_body_size) {
+        return Err(ParseError::ContentTooLarge);
+    }
 
-    // Should close? Default to true if not found
-    let close = !matches!(
-        headers.get("Connection").map(|s| s.as_str()),
-        Some("keep-alive")
-    );
+    let mut request = Request::new(verb, &path);
+    request.version = version.to_string();
+    request.headers = headers;
+    Ok(Some((request, body_length)))
+}
 
-    let request = Request {
-        verb: verb.to_string(),
-        path: path.to_string(),
-        path_params: HashMap::new(),
-        body,
-        headers,
-    };
+/// How the end of a request body is found
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
 
-    let response = check_routes(routes, request);
+/// Reads the body of a request from the connection.
+/// Decodes chunked bodies and enforces the maximum body size.
+/// If the client expects `100 Continue`, it is sent before the first read.
+struct BodyReader<R: BufRead, W: Write> {
+    inner: R,
+    length: BodyLength,
+    /// Bytes left of a fixed length body or of the current chunk
+    remaining: usize,
+    /// Bytes of all chunks so far
+    chunked_size: usize,
+    max_size: usize,
+    finished: bool,
+    send_continue: Option<W>,
+}
 
-    // Add headers to response
-    let mut response_headers = response.headers.clone();
-    let response_bytes = response.bytes.unwrap_or_default();
-    response_headers
-        .entry("Content-Length".into())
-        .or_insert(response_bytes.len().to_string());
-    response_headers.insert("Connection".into(), "close".into());
+impl<R: BufRead, W: Write> BodyReader<R, W> {
+    fn new(inner: R, length: BodyLength, max_size: usize, send_continue: Option<W>) -> Self {
+        BodyReader {
+            inner,
+            length,
+            remaining: match length {
+                BodyLength::Fixed(len) => len,
+                BodyLength::Chunked => 0,
+            },
+            chunked_size: 0,
+            max_size,
+            finished: length == BodyLength::Fixed(0),
+            send_continue,
+        }
+    }
+
+    fn read_line(&mut self) -> std::io::Result<String> {
+        match read_line_limited(&mut self.inner, 4096, || {
+            ParseError::BadRequest("Chunk line is too long".to_string())
+        }) {
+            Ok(Some(line)) => Ok(line),
+            Ok(None) => Err(ErrorKind::UnexpectedEof.into()),
+            Err(ParseError::Io(e)) => Err(e),
+            Err(e) => Err(std::io::Error::new(ErrorKind::InvalidData, e.message())),
+        }
+    }
+
+    /// Read the size line of the next chunk, or the trailers after the last chunk.
+    /// See https://www.rfc-editor.org/rfc/rfc9112#name-chunked-transfer-coding
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        let invalid = |message: &str| std::io::Error::new(ErrorKind::InvalidData, message);
+        if self.chunked_size > 0 && !self.read_line()?.is_empty() {
+            return Err(invalid("Missing line break after chunk"));
+        }
+        let line = self.read_line()?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("Invalid chunk size"))?;
+        if size == 0 {
+            // Trailer fields are ignored
+            while !self.read_line()?.is_empty() {}
+            self.finished = true;
+            return Ok(());
+        }
+        self.chunked_size = self.chunked_size.saturating_add(size);
+        if self.chunked_size > self.max_size {
+            return Err(ErrorKind::FileTooLarge.into());
+        }
+        self.remaining = size;
+        Ok(())
+    }
+
+    /// Whether the client still waits for `100 Continue` before sending the body
+    fn continue_pending(&self) -> bool {
+        self.send_continue.is_some()
+    }
+}
+
+impl<R: BufRead, W: Write> Read for BodyReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(mut writer) = self.send_continue.take() {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+        if self.remaining == 0 {
+            match self.length {
+                BodyLength::Fixed(_) => self.finished = true,
+                BodyLength::Chunked => self.next_chunk()?,
+            }
+            if self.finished {
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read;
+        if self.remaining == 0 && matches!(self.length, BodyLength::Fixed(_)) {
+            self.finished = true;
+        }
+        Ok(read)
+    }
+}
+
+/// Write a response with headers, including `options.default_headers`.
+/// Returns whether the connection can be kept open for another request
+/// and the bytes of the body.
+fn write_response<W: Write>(
+    stream: W,
+    mut response: Response,
+    options: &ServerOptions,
+    keep_alive: bool,
+    version: &str,
+) -> WebResult<(bool, usize)> {
+    let stream_body = response.stream.take();
+    let has_header = |response: &Response, name: &str| {
+        response
+            .headers
+            .keys()
+            .any(|k| k.eq_ignore_ascii_case(name))
+    };
+    let streamed_without_length = stream_body.is_some() && !has_header(&response, "Content-Length");
+    // HTTP/1.0 clients do not know chunked encoding, so the end of the body is marked by closing
+    let chunked = streamed_without_length && version != "HTTP/1.0";
+    let handler_closes = response
+        .headers
+        .iter()
+        .any(|(k, v)| k.eq_ignore_ascii_case("Connection") && v.eq_ignore_ascii_case("close"));
+    let close_delimited = streamed_without_length && !chunked;
+    let keep_alive = keep_alive && !handler_closes && !close_delimited;
+    let response_bytes = response.bytes.take().unwrap_or_default();
+    if chunked {
+        response
+            .headers
+            .insert("Transfer-Encoding".into(), "chunked".into());
+    } else if stream_body.is_none()
+        // Responses to HEAD requests keep the framing headers of the GET response
+        && !has_header(&response, "Content-Length")
+        && !has_header(&response, "Transfer-Encoding")
+        // These responses never have a body
+        && !matches!(response.status_code, 100..=199 | 204 | 304)
+    {
+        response
+            .headers
+            .insert("Content-Length".into(), response_bytes.len().to_string());
+    }
+    if !keep_alive {
+        response.headers.insert("Connection".into(), "close".into());
+    } else if version == "HTTP/1.0" {
+        response
+            .headers
+            .insert("Connection".into(), "keep-alive".into());
+    }
 
     let headers = options
         .default_headers
         .iter()
+        .filter(|(k, _)| !has_header(&response, k))
         .chain(response.headers.iter())
         .map(|(k, v)| format!("{}: {}", k, v))
         .collect::<Vec<String>>()
         .join("\r\n");
 
-    {
-        let mut buf_writer = BufWriter::new(&mut stream);
-        buf_writer
-            .write_fmt(format_args!(
-                "HTTP/1.1 {}\r\n{}\r\n\r\n",
-                response.status_code, headers
-            ))
-            .and_then(|_| buf_writer.write_all(&response_bytes))?;
-        buf_writer.flush()?
+    let mut buf_writer = BufWriter::new(stream);
+    buf_writer
+        .write_fmt(format_args!(
+            "HTTP/1.1 {} {}\r\n{}\r\n\r\n",
+            response.status_code,
+            reason_phrase(response.status_code),
+            headers
+        ))
+        .and_then(|_| buf_writer.write_all(&response_bytes))?;
+    let mut bytes = response_bytes.len();
+    match stream_body {
+        Some(write_body) if chunked => {
+            let mut chunk_writer = CountingWriter {
+                inner: BufWriter::new(ChunkedWriter {
+                    inner: &mut buf_writer,
+                }),
+                count: 0,
+            };
+            write_body(&mut chunk_writer)?;
+            bytes += chunk_writer.count;
+            chunk_writer
+                .inner
+                .into_inner()
+                .map_err(|e| Error::Connection(e.to_string()))?
+                .finish()?;
+        }
+        Some(write_body) => {
+            let mut counting_writer = CountingWriter {
+                inner: &mut buf_writer,
+                count: 0,
+            };
+            write_body(&mut counting_writer)?;
+            bytes += counting_writer.count;
+        }
+        None => {}
+    }
+    buf_writer.flush()?;
+    Ok((keep_alive, bytes))
+}
+
+/// Counts the bytes written to the inner writer, for access logs
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes each buffer as a chunk of the chunked transfer coding.
+/// See https://www.rfc-editor.org/rfc/rfc9112#name-chunked-transfer-coding
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Write the last chunk to end the body
+    fn finish(mut self) -> std::io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
     }
+}
 
-    Ok(close)
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 #[derive(Clone)]
 pub struct ServerOptions {
-    pub address: String,
+    /// Addresses to listen on. See [crate::listener] for the supported formats.
+    pub addresses: Vec<String>,
     pub threads: u32,
     pub default_headers: HashMap<String, String>,
+    pub limits: RequestLimits,
+    /// How long an idle connection is kept open waiting for the next request
+    pub keep_alive_timeout: Duration,
+    /// Requests answered on one connection before it is closed
+    pub max_requests_per_connection: usize,
+    /// Middleware running for every request before the route is found.
+    /// The request body is not read yet when `before` is called.
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    /// Certificates for serving HTTPS instead of HTTP
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsOptions>,
+    /// Address of a plain HTTP listener redirecting to HTTPS, e.g. `0.0.0.0:80`
+    #[cfg(feature = "tls")]
+    pub redirect_http: Option<String>,
+    /// How long requests in progress may take to finish when the server shuts down
+    pub shutdown_timeout: Duration,
+    /// Receives messages and access logs, see [crate::logging]
+    pub logger: Arc<dyn Logger>,
 }
 
 const ADDRESS: &str = "0.0.0.0:8080";
 
+/// Wait before accepting again after an error like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether an accept error only concerns the connection that was being accepted,
+/// so the next one can be accepted right away
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+    )
+}
+
+/// Protocols offered to TLS clients, preferring HTTP/2 when it is enabled
+#[cfg(feature = "tls")]
+const ALPN_PROTOCOLS: &[&str] = match cfg!(feature = "http2") {
+    true => &["h2", "http/1.1"],
+    false => &[],
+};
+
 pub struct Server {
     pub routes: Arc<[Route]>,
     pub options: ServerOptions,
 }
 
 impl Server {
+    /// Create a server responding with the routes.
+    /// The routes are checked for conflicts when the server starts.
     pub fn new(routes: Vec<Route>) -> Server {
         let a: Arc<[Route]> = Arc::from(routes);
         Server {
             routes: a,
             options: ServerOptions {
-                address: ADDRESS.to_string(),
+                addresses: vec![ADDRESS.to_string()],
                 threads: 6,
                 default_headers: HashMap::new(),
+                limits: RequestLimits::default(),
+                keep_alive_timeout: Duration::from_secs(5),
+                max_requests_per_connection: 100,
+                middleware: Vec::new(),
+                #[cfg(feature = "tls")]
+                tls: None,
+                #[cfg(feature = "tls")]
+                redirect_http: None,
+                shutdown_timeout: Duration::from_secs(30),
+                logger: Arc::new(ConsoleLogger::default()),
             },
         }
     }
 
+    /// Listen only on the address, instead of the ones set before
     pub fn set_address(&mut self, address: &str) -> &mut Self {
-        self.options.address = address.to_string();
+        self.options.addresses = vec![address.to_string()];
+        self
+    }
+
+    /// Listen on the address as well, e.g. `[::]:8080` next to `0.0.0.0:8080`
+    pub fn add_address(&mut self, address: &str) -> &mut Self {
+        self.options.addresses.push(address.to_string());
         self
     }
 
@@ -202,21 +969,699 @@ impl Server {
         self
     }
 
-    pub fn listen(&self) {
-        let address = TcpListener::bind(self.options.address.clone()).unwrap();
-        let workers = Workers::new(self.options.threads);
-        for stream in address.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let _ = stream.set_read_timeout(Some(Duration::from_millis(50)));
-                    let routes = self.routes.clone();
-                    let options = self.options.clone();
-                    workers.post(move || handle_connection(stream, &routes, &options));
+    pub fn set_request_limits(&mut self, limits: RequestLimits) -> &mut Self {
+        self.options.limits = limits;
+        self
+    }
+
+    pub fn set_keep_alive_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.options.keep_alive_timeout = timeout;
+        self
+    }
+
+    pub fn set_max_requests_per_connection(&mut self, max: usize) -> &mut Self {
+        self.options.max_requests_per_connection = max;
+        self
+    }
+
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.options.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Write messages and access logs to the logger instead of the console
+    pub fn set_logger(&mut self, logger: impl Logger + 'static) -> &mut Self {
+        self.options.logger = Arc::new(logger);
+        self
+    }
+
+    /// Add middleware running for every request, in the order it is added
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.options.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Serve HTTPS with the certificates
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: TlsOptions) -> &mut Self {
+        self.options.tls = Some(tls);
+        self
+    }
+
+    /// Redirect plain HTTP requests on the address to HTTPS, e.g. `0.0.0.0:80`.
+    /// Only used with [Server::set_tls].
+    #[cfg(feature = "tls")]
+    pub fn redirect_http_from(&mut self, address: &str) -> &mut Self {
+        self.options.redirect_http = Some(address.to_string());
+        self
+    }
+
+    /// Answer requests until the process receives `SIGTERM` or `SIGINT`,
+    /// then shut down gracefully. See [Server::start] for stopping it otherwise.
+    /// Fails if the server cannot listen on its addresses.
+    pub fn listen(&self) -> WebResult<()> {
+        let handle = self.start()?;
+        handle.shutdown_on_signals();
+        handle.wait();
+        Ok(())
+    }
+
+    /// Start answering requests on other threads, returning a handle to stop the server.
+    ///
+    /// Fails with [Error::InvalidParams] if a route is unreachable because an earlier route
+    /// has the same path and methods. See [RouteTree::conflicts]
+    /// ```
+    /// use unchained_web::server::Server;
+    ///
+    /// let mut server = Server::new(vec![]);
+    /// server.set_address("127.0.0.1:0");
+    /// let handle = server.start().unwrap();
+    /// assert_ne!(handle.address().unwrap().port(), 0);
+    /// handle.shutdown();
+    /// ```
+    pub fn start(&self) -> WebResult<ServerHandle> {
+        let tree = Arc::new(RouteTree::checked(self.routes.clone())?);
+        let mut listeners = Vec::new();
+        for address in &self.options.addresses {
+            listeners.extend(Listener::bind(address)?);
+        }
+        if listeners.is_empty() {
+            return Err(Error::Connection("No address to listen on".to_string()));
+        }
+        let handle = self.serve(listeners, tree)?;
+        #[cfg(feature = "tls")]
+        if let (Some(_), Some(address)) = (&self.options.tls, &self.options.redirect_http) {
+            let https_port = handle.address().map_or(443, |address| address.port());
+            let mut redirect = Server::new(vec![https_redirect(https_port)]);
+            redirect.set_threads(1).set_address(address);
+            match redirect.start() {
+                Ok(child) => handle.add_child(child),
+                Err(e) => {
+                    // The HTTPS listeners would keep running without a handle to stop them
+                    handle.shutdown();
+                    return Err(e);
                 }
-                Err(_) => {
-                    println!("Could not handle tcp connection.");
+            }
+        }
+        Ok(handle)
+    }
+
+    /// Answer the connections of each listener on another thread, sharing the workers
+    fn serve(
+        &self,
+        listeners: Vec<Listener>,
+        tree: Arc<RouteTree<Arc<[Route]>>>,
+    ) -> WebResult<ServerHandle> {
+        #[cfg(feature = "tls")]
+        let tls = match &self.options.tls {
+            Some(tls) => Some(tls.server_config(ALPN_PROTOCOLS, self.options.logger.clone())?),
+            None => None,
+        };
+        let addresses = listeners
+            .iter()
+            .map(Listener::address)
+            .collect::<std::io::Result<Vec<BoundAddress>>>()?;
+        let lifecycle = Arc::new(Lifecycle::new(self.options.shutdown_timeout));
+        // Dropped with the last accepting thread, which waits for the connections
+        let workers: Arc<Workers<Task, Error>> = Arc::new(Workers::with_logger(
+            self.options.threads,
+            self.options.logger.clone(),
+        ));
+        for listener in listeners {
+            let tree = tree.clone();
+            let options = self.options.clone();
+            let accepting = lifecycle.clone();
+            let workers = workers.clone();
+            #[cfg(feature = "tls")]
+            let tls = tls.clone();
+            let thread = std::thread::spawn(move || loop {
+                let accepted = listener.accept();
+                if accepting.is_stopping() {
+                    break;
                 }
+                let logger = &options.logger;
+                let accepted = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        logger.log(
+                            Level::Warn,
+                            format_args!("Could not accept connection. {}", e),
+                        );
+                        if !is_connection_error(&e) {
+                            std::thread::sleep(ACCEPT_BACKOFF);
+                        }
+                        continue;
+                    }
+                };
+                let connection = match accepted.shutdown_socket() {
+                    Ok(shutdown) => accepting.register(shutdown),
+                    Err(e) => {
+                        logger.log(
+                            Level::Warn,
+                            format_args!("Could not track connection. {}", e),
+                        );
+                        continue;
+                    }
+                };
+                let stream: Box<dyn Transport + Send> = match accepted {
+                    #[cfg(feature = "tls")]
+                    Accepted::Tcp(stream) => match &tls {
+                        Some(config) => match rustls::ServerConnection::new(config.clone()) {
+                            Ok(connection) => Box::new(TlsStream::new(connection, stream)),
+                            Err(e) => {
+                                logger.log(
+                                    Level::Error,
+                                    format_args!("Could not start TLS connection. {:?}", e),
+                                );
+                                continue;
+                            }
+                        },
+                        None => Box::new(stream),
+                    },
+                    #[cfg(not(feature = "tls"))]
+                    Accepted::Tcp(stream) => Box::new(stream),
+                    // Connections on Unix sockets come from a local proxy and are not encrypted
+                    #[cfg(unix)]
+                    Accepted::Unix(stream) => Box::new(stream),
+                };
+                let tree = tree.clone();
+                let options = options.clone();
+                workers.post(Box::new(move || {
+                    // Clients closing or resetting connections are common and not an error of the server
+                    if let Err(e) = handle_connection(stream, &tree, &options, &connection) {
+                        options.logger.log(
+                            Level::Debug,
+                            format_args!("Connection closed with an error. {:?}", e),
+                        );
+                    }
+                    Ok(())
+                }));
+            });
+            lifecycle.accepting.lock().unwrap().push(thread);
+        }
+        Ok(ServerHandle::new(addresses, lifecycle))
+    }
+}
+
+/// Connection handled by a worker
+type Task = Box<dyn FnOnce() -> WebResult<()> + Send>;
+
+/// Route redirecting every request to the same URL with `https`
+#[cfg(feature = "tls")]
+fn https_redirect(https_port: u16) -> Route {
+    Route::any(
+        "/*",
+        ResponseContent::FromRequest(Box::new(move |request| {
+            let Some(host) = request.header("Host") else {
+                return Response::new(Some("Missing Host header".to_string()), 400);
+            };
+            // Remove the port, keeping IPv6 addresses like `[::1]` whole
+            let host = match host.rsplit_once(':') {
+                Some((name, port)) if !port.contains(']') => name,
+                _ => host,
+            };
+            let port = match https_port {
+                443 => String::new(),
+                port => format!(":{}", port),
+            };
+            let mut location = format!("https://{}{}{}", host, port, request.path);
+            if !request.query.is_empty() {
+                location = format!("{}?{}", location, request.query);
+            }
+            let mut response = Response::new(None, 308);
+            response.add_header("Location", &location);
+            response
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::router::HTTPVerb;
+
+    #[test]
+    fn test_accept_errors() {
+        assert!(is_connection_error(&ErrorKind::ConnectionAborted.into()));
+        // Running out of file descriptors is not transient, EMFILE
+        assert!(!is_connection_error(&std::io::Error::from_raw_os_error(24)));
+    }
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut output = Vec::new();
+        let mut writer = ChunkedWriter { inner: &mut output };
+        writer.write_all(b"Hello, ").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"streaming world!").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            output,
+            b"7\r\nHello, \r\n10\r\nstreaming world!\r\n0\r\n\r\n"
+        );
+    }
+
+    /// Read a request with its body
+    fn parse_with(raw: &str, limits: &RequestLimits) -> Result<Option<Request>, ParseError> {
+        let mut reader = raw.as_bytes();
+        let Some((mut request, length)) = read_request_head(&mut reader, limits)? else {
+            return Ok(None);
+        };
+        let mut body = Vec::new();
+        BodyReader::new(&mut reader, length, limits.max_body_size, None::<Vec<u8>>)
+            .read_to_end(&mut body)?;
+        request.body = (!body.is_empty()).then_some(body);
+        Ok(Some(request))
+    }
+
+    fn parse(raw: &str) -> Result<Option<Request>, ParseError> {
+        parse_with(raw, &RequestLimits::default())
+    }
+
+    fn status(raw: &str, limits: &RequestLimits) -> u32 {
+        parse_with(raw, limits)
+            .map(|_| 200)
+            .unwrap_or_else(|e| e.status_code())
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse(
+            "\r\nPOST /form HTTP/1.1\r\nHost: localhost\r\nAccept: text/html\r\n\
+             accept:  application/json \r\nContent-Length: 5\r\n\r\nhelloGET",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.verb, "POST");
+        assert_eq!(request.path, "/form");
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(
+            request.header_values("Accept"),
+            vec!["text/html", "application/json"]
+        );
+        assert_eq!(request.text().unwrap(), "hello");
+
+        let request = parse("GET http://localhost:8000/a?b HTTP/1.0\nX: y\n\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.query, "b");
+        assert_eq!(request.header("x"), Some("y"));
+        assert!(parse("").unwrap().is_none());
+
+        let request = parse("GET / HTTP/1.1\r\nHost: a\r\nCookie: a=1\r\nCookie: b=2, c\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.header("Cookie"), Some("a=1; b=2, c"));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let limits = RequestLimits::default();
+        let invalid = [
+            "GET  / HTTP/1.1\r\nHost: a\r\n\r\n",
+            "GET / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Folded: a\r\n b\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\nInvalid\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            "GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nab",
+            "GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n",
+            "GET index.html HTTP/1.1\r\nHost: a\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: a",
+        ];
+        for raw in invalid {
+            assert_eq!(status(raw, &limits), 400, "{:?}", raw);
+        }
+        assert_eq!(status("GET / HTTP/2.0\r\n\r\n", &limits), 505);
+        assert_eq!(
+            status(
+                "GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n",
+                &limits
+            ),
+            501
+        );
+        assert_eq!(
+            status(
+                "GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 1, 1\r\n\r\na",
+                &limits
+            ),
+            200
+        );
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let limits = RequestLimits {
+            max_header_size: 64,
+            max_headers: 2,
+            max_body_size: 4,
+        };
+        let long_path = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(64));
+        assert_eq!(status(&long_path, &limits), 414);
+        let long_header = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", "a".repeat(64));
+        assert_eq!(status(&long_header, &limits), 431);
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\n\r\n", &limits),
+            431
+        );
+        assert_eq!(
+            status(
+                "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999\r\n\r\n",
+                &limits
+            ),
+            413
+        );
+        assert_eq!(
+            status(
+                "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nabcd",
+                &limits
+            ),
+            200
+        );
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut output = Vec::new();
+        let mut options = Server::new(vec![]).options;
+        options
+            .default_headers
+            .insert("Server".to_string(), "unchained".to_string());
+        let response = Response::new(Some("Not here".to_string()), 404);
+        let (keep_alive, bytes) =
+            write_response(&mut output, response, &options, false, "HTTP/1.1").unwrap();
+        assert!(!keep_alive);
+        assert_eq!(bytes, 8);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(output.contains("Content-Length: 8\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(output.contains("Server: unchained\r\n"));
+        assert!(output.ends_with("\r\n\r\nNot here"));
+    }
+
+    #[test]
+    fn test_http_1_0_responses() {
+        let options = Server::new(vec![]).options;
+        let mut output = Vec::new();
+        let response = Response::new_200("ok".to_string());
+        assert!(
+            write_response(&mut output, response, &options, true, "HTTP/1.0")
+                .unwrap()
+                .0
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Connection: keep-alive\r\n"));
+
+        let mut output = Vec::new();
+        let response = Response::new_stream(200, |w| Ok(w.write_all(b"streamed")?));
+        let (keep_alive, bytes) =
+            write_response(&mut output, response, &options, true, "HTTP/1.0").unwrap();
+        assert!(!keep_alive);
+        assert_eq!(bytes, 8);
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("Transfer-Encoding"));
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(output.ends_with("\r\n\r\nstreamed"));
+    }
+
+    /// Send `raw` to a connection handled by the server and read until it closes
+    fn exchange(raw: &[u8], options: ServerOptions) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let routes = vec![
+                Route::new(
+                    crate::router::HTTPVerb::GET,
+                    "/:name",
+                    ResponseContent::FromRequest(Box::new(|req| {
+                        Response::new_200(format!("hello {}", req.path_params["name"]))
+                    })),
+                ),
+                Route::new(
+                    crate::router::HTTPVerb::POST,
+                    "/upload",
+                    ResponseContent::Upload(Box::new(|_, body| {
+                        let mut bytes = 0;
+                        let mut buf = [0; 3];
+                        while let Ok(read @ 1..) = body.read(&mut buf) {
+                            bytes += read;
+                        }
+                        Response::new_200(format!("uploaded {}", bytes))
+                    })),
+                ),
+                Route::new(
+                    crate::router::HTTPVerb::POST,
+                    "/ignore",
+                    ResponseContent::Upload(Box::new(|_, _| Response::new(None, 403))),
+                ),
+                Route::new(
+                    crate::router::HTTPVerb::POST,
+                    "/echo",
+                    ResponseContent::FromRequest(Box::new(|req| {
+                        Response::new_200(format!("{:?}", req.body.unwrap_or_default()))
+                    })),
+                ),
+            ];
+            let shutdown = Accepted::Tcp(stream.try_clone().unwrap())
+                .shutdown_socket()
+                .unwrap();
+            let connection = Arc::new(Lifecycle::new(Duration::ZERO)).register(shutdown);
+            let tree = RouteTree::new(&routes);
+            handle_connection(stream, &tree, &options, &connection).unwrap();
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(raw).unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        server.join().unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[test]
+    fn test_access_log() {
+        /// Keeps the access logs and messages
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+
+        impl Logger for Recorder {
+            fn log(&self, level: Level, message: std::fmt::Arguments) {
+                let line = format!("{} {}", level.as_str(), message);
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn access(&self, entry: &AccessLog) {
+                let format = crate::logging::AccessLogFormat::Combined;
+                self.0.lock().unwrap().push(entry.format(format));
             }
         }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut options = Server::new(vec![]).options;
+        options.logger = recorder.clone();
+        exchange(
+            b"GET /a?b=c HTTP/1.1\r\nHost: x\r\nUser-Agent: test\r\nConnection: close\r\n\r\n",
+            options,
+        );
+        let lines = recorder.0.lock().unwrap();
+        assert!(lines[0].starts_with("TRACE Request headers"));
+        assert!(lines[1].starts_with("127.0.0.1 - - ["));
+        assert!(lines[1].ends_with("] \"GET /a?b=c HTTP/1.1\" 200 7 \"-\" \"test\""));
+        assert_eq!(lines[2], "DEBUG Closing connection after 1 requests");
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let options = Server::new(vec![]).options;
+        let output = exchange(
+            b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /b HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n\
+             GET /d HTTP/1.1\r\nHost: x\r\n\r\n",
+            options,
+        );
+        let a = output.find("hello a").unwrap();
+        let b = output.find("hello b").unwrap();
+        let c = output.find("hello c").unwrap();
+        assert!(a < b && b < c);
+        assert!(!output.contains("hello d"));
+        assert_eq!(output.matches("Connection: close").count(), 1);
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let mut options = Server::new(vec![]).options;
+        options.max_requests_per_connection = 2;
+        let output = exchange(
+            b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /b HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /c HTTP/1.1\r\nHost: x\r\n\r\n",
+            options.clone(),
+        );
+        assert!(output.contains("hello b") && !output.contains("hello c"));
+
+        let output = exchange(
+            b"GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n",
+            options.clone(),
+        );
+        assert!(output.contains("hello a") && !output.contains("hello b"));
+
+        // The idle timeout closes a kept-alive connection
+        options.keep_alive_timeout = Duration::from_millis(100);
+        let output = exchange(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n", options);
+        assert!(output.contains("hello a"));
+    }
+
+    #[test]
+    fn test_chunked_request_body() {
+        let request = parse(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+             4;name=value\r\nWiki\r\n6\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n\
+             0\r\nExpires: never\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.text().unwrap(), "Wikipedia in \r\n\r\nchunks.");
+
+        let limits = RequestLimits {
+            max_body_size: 8,
+            ..Default::default()
+        };
+        let chunked = |body: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                body
+            )
+        };
+        assert_eq!(status(&chunked("5\r\nabcde\r\n0\r\n\r\n"), &limits), 200);
+        assert_eq!(
+            status(&chunked("5\r\nabcde\r\n5\r\nabcde\r\n0\r\n\r\n"), &limits),
+            413
+        );
+        assert_eq!(status(&chunked("x\r\nabcde\r\n0\r\n\r\n"), &limits), 400);
+        assert_eq!(status(&chunked("2\r\nabcde\r\n0\r\n\r\n"), &limits), 400);
+        assert_eq!(
+            status(
+                "POST / HTTP/1.1\r\nHost: a\r\nExpect: something\r\n\r\n",
+                &limits
+            ),
+            417
+        );
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let mut written = Vec::new();
+        let mut body = BodyReader::new(
+            "abc".as_bytes(),
+            BodyLength::Fixed(3),
+            10,
+            Some(&mut written),
+        );
+        assert!(body.continue_pending());
+        let mut read = String::new();
+        body.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "abc");
+        assert_eq!(written, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn test_binary_and_streamed_bodies() {
+        let options = Server::new(vec![]).options;
+        let output = exchange(
+            b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\n\xff\x00\x01\
+             POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             4\r\nabcd\r\n3\r\nefg\r\n0\r\n\r\n\
+             POST /ignore HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nab\
+             POST /upload HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nabcde\
+             POST /ignore HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n\
+             GET /never HTTP/1.1\r\nHost: x\r\n\r\n",
+            options,
+        );
+        assert!(output.contains("[255, 0, 1]"));
+        assert!(output.contains("uploaded 7"));
+        assert_eq!(output.matches("HTTP/1.1 403").count(), 2);
+        let continued = output.find("HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+        assert!(continued < output.find("uploaded 5").unwrap());
+        // The ignored body was never requested, so the connection is closed
+        assert_eq!(output.matches("100 Continue").count(), 1);
+        assert!(output.ends_with("\r\n\r\n") && !output.contains("hello never"));
+    }
+
+    #[test]
+    fn test_middleware() {
+        let mut server = Server::new(vec![]);
+        server
+            .add_middleware(middleware::before(|req| {
+                // Rewrite before the route is found
+                req.path = req.path.replace("/old/", "/");
+                None
+            }))
+            .add_middleware(middleware::before(|req| match req.method() {
+                HTTPVerb::POST if req.header("Authorization").is_none() => {
+                    Some(Response::new(None, 401))
+                }
+                _ => None,
+            }))
+            .add_middleware(middleware::after(|req, res| {
+                res.add_header("X-Path", &req.path);
+            }));
+        let output = exchange(
+            b"GET /old/a HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /a/b HTTP/1.1\r\nHost: x\r\n\r\n\
+             POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nab\
+             POST /upload HTTP/1.1\r\nHost: x\r\nAuthorization: a\r\nContent-Length: 2\r\n\r\nab\
+             POST /upload HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n",
+            server.options,
+        );
+        assert!(output.contains("hello a"));
+        assert_eq!(output.matches("X-Path: /a\r\n").count(), 1);
+        assert!(output.contains("HTTP/1.1 404") && output.contains("X-Path: /a/b"));
+        assert_eq!(output.matches("HTTP/1.1 401").count(), 2);
+        assert!(output.contains("uploaded 2"));
+        // The body of a rejected upload is never requested
+        assert!(!output.contains("100 Continue"));
+    }
+
+    #[test]
+    fn test_conflicting_routes_fail_to_start() {
+        let route = || Route::new(HTTPVerb::GET, "/a", ResponseContent::Str("a".to_string()));
+        let mut server = Server::new(vec![route(), route()]);
+        server.set_address("127.0.0.1:0");
+        match server.start() {
+            Err(Error::InvalidParams(conflicts)) => assert!(conflicts.contains("'GET /a'")),
+            _ => panic!("Started with conflicting routes"),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_https_redirect() {
+        let routes = vec![https_redirect(8443)];
+        let redirect = |host: &str, path: &str| {
+            let mut request = Request::new("POST", path);
+            request.headers.insert("host".to_string(), host.to_string());
+            let response = crate::router::check_routes(&routes, request);
+            assert_eq!(response.status_code, 308);
+            response.headers["Location"].clone()
+        };
+        assert_eq!(
+            redirect("example.com:8080", "/a/b?c=d"),
+            "https://example.com:8443/a/b?c=d"
+        );
+        assert_eq!(redirect("[::1]", "/"), "https://[::1]:8443/");
+        assert_eq!(redirect("[::1]:80", "/"), "https://[::1]:8443/");
+        let request = Request::new("GET", "/");
+        assert_eq!(
+            crate::router::check_routes(&routes, request).status_code,
+            400
+        );
     }
 }
@@ -0,0 +1,149 @@
+//! Generate a `sitemap.xml` from the registered routes.
+//! See <https://www.sitemaps.org/protocol.html>
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use unchained_web::{router::{HTTPVerb::*, ResponseContent, Route}, sitemap::Sitemap};
+//!
+//! let routes = vec![
+//!     Route::new(GET, "/", ResponseContent::Str("Home".to_string())),
+//!     Route::new(GET, "/posts/:id", ResponseContent::Str("Post".to_string())),
+//! ];
+//! let mut sitemap = Sitemap::new("https://example.com");
+//! sitemap.expand("/posts/:id", || {
+//!     vec![HashMap::from([("id".to_string(), "hello".to_string())])]
+//! });
+//! let xml = sitemap.to_xml(&routes).unwrap();
+//! assert!(xml.contains("<loc>https://example.com/posts/hello</loc>"));
+//! ```
+
+use std::collections::HashMap;
+
+use crate::{
+    error::WebResult,
+    feed::{escape_xml, xml_route},
+    router::{fill_path_params, HTTPVerb, ResponseContent, Route},
+};
+
+/// Gives the path parameters of every page of a parameterized route
+pub type Expansion = Box<dyn Fn() -> Vec<HashMap<String, String>>>;
+
+pub struct Sitemap {
+    /// Absolute URL of the website without a trailing slash
+    pub base_url: String,
+    /// Paths that are not included, like `/404`
+    pub excluded: Vec<String>,
+    expansions: HashMap<String, Expansion>,
+}
+
+/// Compare paths without leading or trailing slashes
+fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+impl Sitemap {
+    pub fn new(base_url: &str) -> Self {
+        Sitemap {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            excluded: Vec::new(),
+            expansions: HashMap::new(),
+        }
+    }
+
+    /// Include a parameterized route with the parameters given by `expansion`.
+    /// Parameterized routes without an expansion are left out.
+    pub fn expand<F>(&mut self, route_path: &str, expansion: F) -> &mut Self
+    where
+        F: Fn() -> Vec<HashMap<String, String>> + 'static,
+    {
+        self.expansions
+            .insert(normalize(route_path).to_string(), Box::new(expansion));
+        self
+    }
+
+    pub fn exclude(&mut self, path: &str) -> &mut Self {
+        self.excluded.push(normalize(path).to_string());
+        self
+    }
+
+    /// Paths of all pages in the sitemap.
+    /// Includes every `GET` route except file and wildcard routes.
+    pub fn paths(&self, routes: &[Route]) -> WebResult<Vec<String>> {
+        let mut paths = Vec::new();
+        for route in routes.iter() {
+            let is_page = route.accepts(&HTTPVerb::GET)
+                && !matches!(
+                    route.response,
+                    ResponseContent::FolderAccess | ResponseContent::Static(_)
+                )
+                && !route.path.contains('*');
+            if !is_page {
+                continue;
+            }
+            if !route.path.contains(':') {
+                paths.push(fill_path_params(&route.path, &HashMap::new())?);
+            } else if let Some(expansion) = self.expansions.get(normalize(&route.path)) {
+                for params in expansion() {
+                    paths.push(fill_path_params(&route.path, &params)?);
+                }
+            }
+        }
+        paths.retain(|p| !self.excluded.iter().any(|e| e == normalize(p)));
+        paths.dedup();
+        Ok(paths)
+    }
+
+    pub fn to_xml(&self, routes: &[Route]) -> WebResult<String> {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+        for path in self.paths(routes)? {
+            xml.push_str(&format!(
+                "<url><loc>{}</loc></url>\n",
+                escape_xml(&format!("{}{}", self.base_url, path))
+            ));
+        }
+        xml.push_str("</urlset>\n");
+        Ok(xml)
+    }
+
+    /// Route serving the sitemap of `routes` from `/sitemap.xml`
+    pub fn route(&self, routes: &[Route]) -> WebResult<Route> {
+        Ok(xml_route(
+            "/sitemap.xml",
+            self.to_xml(routes)?,
+            "application/xml; charset=utf-8",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sitemap_paths() {
+        let page = || ResponseContent::Str(String::new());
+        let routes = vec![
+            Route::new(HTTPVerb::GET, "/", page()),
+            Route::new(HTTPVerb::GET, "skills", page()),
+            Route::new(HTTPVerb::POST, "/form", page()),
+            Route::new(HTTPVerb::GET, "/courses/:courseid", page()),
+            Route::new(HTTPVerb::GET, "/tags/:tag", page()),
+            Route::new(HTTPVerb::GET, "/images/*", ResponseContent::FolderAccess),
+            Route::new(HTTPVerb::GET, "/404", page()),
+            Route::new(HTTPVerb::GET, "/*", page()),
+        ];
+        let mut sitemap = Sitemap::new("https://example.com/");
+        sitemap.exclude("/404").expand("courses/:courseid", || {
+            ["CS4505", "CS4510"]
+                .map(|id| HashMap::from([("courseid".to_string(), id.to_string())]))
+                .to_vec()
+        });
+        assert_eq!(
+            sitemap.paths(&routes).unwrap(),
+            vec!["/", "/skills", "/courses/CS4505", "/courses/CS4510"]
+        );
+        let xml = sitemap.to_xml(&routes).unwrap();
+        assert!(xml.contains("<url><loc>https://example.com/courses/CS4510</loc></url>"));
+    }
+}
@@ -0,0 +1,523 @@
+//! Serve files from a directory.
+//!
+//! Paths are resolved inside the root directory, so requests with `..`
+//! or symbolic links can not read files outside of it.
+//! Files are streamed with a `Content-Type` from their extension,
+//! and `Range` requests get the requested parts of a file.
+//! Precompressed `site.css.br` or `site.css.gz` files next to `site.css` are sent
+//! to clients accepting their encoding.
+//!
+//! ```
+//! use unchained_web::{
+//!     router::{HTTPVerb::*, ResponseContent, Route},
+//!     static_files::StaticFiles,
+//! };
+//!
+//! // `/assets/css/site.css` is served from `public/css/site.css`
+//! let mut files = StaticFiles::new("public");
+//! files.listings = true;
+//! let route = Route::new(GET, "/assets/*", ResponseContent::Static(files));
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    cache::{content_etag, http_date, unix_seconds},
+    compression::{compressible, negotiate, vary_on_encoding, Encoding},
+    feed::escape_xml,
+    form::percent_encode,
+    range::{if_range_matches, parse_range, Ranges},
+    router::{HTTPVerb, Request, Response},
+};
+
+/// Files in a directory served by a [ResponseContent::Static](crate::router::ResponseContent::Static) route
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    pub root: PathBuf,
+    /// Files served for a directory, in order of preference
+    pub index_files: Vec<String>,
+    /// List the files of directories without an index file
+    pub listings: bool,
+    /// Serve files and directories starting with a `.`, like `.env`
+    pub serve_hidden: bool,
+    /// Serve `.br` and `.gz` files next to the requested file when the client accepts them
+    pub precompressed: bool,
+}
+
+/// Media type of a file from its extension
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "xml" => "application/xml",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            index_files: vec!["index.html".to_string()],
+            listings: false,
+            serve_hidden: false,
+            precompressed: true,
+        }
+    }
+
+    /// File or directory for a decoded path relative to the root.
+    /// `None` if it does not exist or is outside of the root.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for segment in path.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            let hidden = segment.starts_with('.') && !self.serve_hidden;
+            if hidden || segment == ".." || segment.contains(['\\', '\0']) {
+                return None;
+            }
+            file.push(segment);
+        }
+        // Symbolic links can point outside of the root
+        let canonical = fs::canonicalize(&file).ok()?;
+        let root = fs::canonicalize(&self.root).ok()?;
+        canonical.starts_with(root).then_some(file)
+    }
+
+    /// Respond with the file at `path`, relative to the root.
+    /// Directories are answered with an index file or a listing,
+    /// after redirecting to the path with a trailing `/`.
+    pub fn respond(&self, path: &str, request: &Request) -> Response {
+        let Some(mut file) = self.resolve(path) else {
+            return Response::new(None, 404);
+        };
+        if file.is_dir() {
+            if !request.path.ends_with('/') {
+                let mut location = format!("{}/", request.path);
+                if !request.query.is_empty() {
+                    location = format!("{}?{}", location, request.query);
+                }
+                let mut response = Response::new(None, 301);
+                response.add_header("Location", &location);
+                return response;
+            }
+            match self
+                .index_files
+                .iter()
+                .map(|i| file.join(i))
+                .find(|i| i.is_file())
+            {
+                Some(index) => file = index,
+                None if self.listings => return listing(&file, &request.path, self.serve_hidden),
+                None => return Response::new(None, 404),
+            }
+        }
+        let content_type = mime_type(&file);
+        if !self.precompressed || !compressible(content_type) {
+            return serve_file(&file, content_type, None, request);
+        }
+        let siblings = precompressed_files(&file);
+        let accept_encoding = request.header("Accept-Encoding").unwrap_or_default();
+        let encodings = siblings.iter().map(|(e, _)| *e).collect::<Vec<_>>();
+        let sibling = negotiate(accept_encoding, &encodings)
+            .and_then(|encoding| siblings.iter().find(|(e, _)| *e == encoding));
+        let mut response = match sibling {
+            Some((encoding, sibling)) => {
+                serve_file(sibling, content_type, Some(*encoding), request)
+            }
+            None => serve_file(&file, content_type, None, request),
+        };
+        if !siblings.is_empty() {
+            vary_on_encoding(&mut response);
+        }
+        response
+    }
+}
+
+/// Compressed versions of a file, like `site.css.gz` for `site.css`.
+/// Files older than the original are ignored.
+fn precompressed_files(file: &Path) -> Vec<(Encoding, PathBuf)> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(original) = modified(file) else {
+        return Vec::new();
+    };
+    Encoding::ALL
+        .into_iter()
+        .filter_map(|encoding| {
+            let mut name = file.as_os_str().to_owned();
+            name.push(format!(".{}", encoding.extension()?));
+            let sibling = PathBuf::from(name);
+            let fresh = sibling.is_file() && modified(&sibling).is_some_and(|m| m >= original);
+            fresh.then_some((encoding, sibling))
+        })
+        .collect()
+}
+
+/// Stream a file with its `Content-Type`, `Content-Length` and validators.
+/// `GET` requests with a `Range` header get the requested parts of the file.
+/// A file compressed with `encoding` is sent with a `Content-Encoding`.
+fn serve_file(
+    path: &Path,
+    content_type: &'static str,
+    encoding: Option<Encoding>,
+    request: &Request,
+) -> Response {
+    let Ok(mut file) = File::open(path) else {
+        return Response::new(None, 404);
+    };
+    let Ok(metadata) = file.metadata() else {
+        return Response::new(None, 500);
+    };
+    let length = metadata.len();
+    let modified = metadata.modified().ok().map(unix_seconds);
+    let suffix = encoding
+        .map(|e| format!("-{}", e.name()))
+        .unwrap_or_default();
+    let etag = modified.map(|modified| format!("W/\"{:x}-{:x}{}\"", length, modified, suffix));
+
+    let ranges = match (request.method(), request.header("Range")) {
+        (HTTPVerb::GET, Some(range))
+            if request
+                .header("If-Range")
+                .is_none_or(|if_range| if_range_matches(if_range, etag.as_deref(), modified)) =>
+        {
+            parse_range(range, length)
+        }
+        _ => Ranges::Ignored,
+    };
+    let mut response = match ranges {
+        Ranges::Ignored => {
+            let mut response = Response::new_stream(200, move |writer| {
+                std::io::copy(&mut file, writer)?;
+                Ok(())
+            });
+            response
+                .add_header("Content-Type", content_type)
+                .add_header("Content-Length", &length.to_string());
+            response
+        }
+        Ranges::Unsatisfiable => {
+            let mut response = Response::new(None, 416);
+            response.add_header("Content-Range", &format!("bytes */{}", length));
+            response
+        }
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            let mut response = Response::new_stream(206, move |writer| {
+                file.seek(SeekFrom::Start(start))?;
+                std::io::copy(&mut file.take(end - start + 1), writer)?;
+                Ok(())
+            });
+            response
+                .add_header("Content-Type", content_type)
+                .add_header("Content-Length", &(end - start + 1).to_string())
+                .add_header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, end, length),
+                );
+            response
+        }
+        Ranges::Satisfiable(ranges) => byte_ranges(file, content_type, length, ranges),
+    };
+    response.add_header("Accept-Ranges", "bytes");
+    if let (Some(modified), Some(etag)) = (modified, etag) {
+        response
+            .add_header("Last-Modified", &http_date(modified))
+            .add_header("ETag", &etag);
+    }
+    if let (Some(encoding), false) = (encoding, response.status_code == 416) {
+        response.add_header("Content-Encoding", encoding.name());
+    }
+    response
+}
+
+/// `multipart/byteranges` response with a part for each range
+fn byte_ranges(
+    mut file: File,
+    content_type: &str,
+    length: u64,
+    ranges: Vec<(u64, u64)>,
+) -> Response {
+    let boundary = content_etag(format!("{:?}{:?}", ranges, SystemTime::now()).as_bytes());
+    let boundary = boundary.trim_matches('"').to_string();
+    let heads = ranges
+        .iter()
+        .map(|(start, end)| {
+            format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, start, end, length
+            )
+        })
+        .collect::<Vec<_>>();
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    let content_length = heads.iter().map(|h| h.len() as u64).sum::<u64>()
+        + ranges
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .sum::<u64>()
+        + closing.len() as u64;
+
+    let mut response = Response::new_stream(206, move |writer| {
+        for (head, (start, end)) in heads.iter().zip(ranges) {
+            writer.write_all(head.as_bytes())?;
+            file.seek(SeekFrom::Start(start))?;
+            std::io::copy(&mut (&mut file).take(end - start + 1), writer)?;
+        }
+        writer.write_all(closing.as_bytes())?;
+        Ok(())
+    });
+    response
+        .add_header(
+            "Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .add_header("Content-Length", &content_length.to_string());
+    response
+}
+
+/// HTML page linking to the entries of a directory
+fn listing(dir: &Path, request_path: &str, show_hidden: bool) -> Response {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Response::new(None, 404);
+    };
+    let mut names = entries
+        .flatten()
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name, entry.path().is_dir())
+        })
+        .filter(|(name, _)| show_hidden || !name.starts_with('.'))
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let title = escape_xml(&format!("Index of {}", request_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<ul>\n<li><a href=\"../\">../</a></li>\n",
+        title
+    );
+    for (name, is_dir) in names {
+        let slash = if is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{2}\">{}{2}</a></li>\n",
+            escape_xml(&percent_encode(&name)),
+            escape_xml(&name),
+            slash
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    let mut response = Response::new_200(html);
+    response.add_header("Content-Type", "text/html; charset=utf-8");
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{check_routes, HTTPVerb, ResponseContent, Route};
+
+    fn site_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("unchained-static-{}", std::process::id()));
+        for (file, content) in [
+            ("public/index.html", "home"),
+            ("public/css/site.css", "body {}"),
+            ("public/css/site.css.gz", "gzip"),
+            ("public/css/site.css.br", "brotli"),
+            ("public/docs/a <b>.txt", "a"),
+            ("public/docs/.hidden", "hidden"),
+            ("public/docs/sub/x.bin", "x"),
+            ("secret.txt", "secret"),
+        ] {
+            let path = dir.join(file);
+            // Tests run in parallel, so rewriting a file could make its siblings stale
+            if fs::read(&path).ok().as_deref() != Some(content.as_bytes()) {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+        }
+        dir
+    }
+
+    fn body(response: Response) -> String {
+        let mut body = response.bytes.unwrap_or_default();
+        if let Some(write_body) = response.stream {
+            write_body(&mut body).unwrap();
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn test_static_files() {
+        let dir = site_dir();
+        let mut files = StaticFiles::new(dir.join("public"));
+        files.listings = true;
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/assets/*",
+            ResponseContent::Static(files),
+        )];
+        let get = |path: &str| check_routes(&routes, Request::new("GET", path));
+
+        let response = get("/assets/css/site.css");
+        assert_eq!(response.headers["Content-Type"], "text/css; charset=utf-8");
+        assert_eq!(response.headers["Content-Length"], "7");
+        let etag = response.headers["ETag"].clone();
+        assert!(etag.starts_with("W/") && response.headers.contains_key("Last-Modified"));
+        assert_eq!(body(response), "body {}");
+        let mut request = Request::new("GET", "/assets/css/site.css");
+        request.headers.insert("if-none-match".to_string(), etag);
+        assert_eq!(check_routes(&routes, request).status_code, 304);
+        assert_eq!(body(get("/assets/")), "home");
+        assert_eq!(body(get("/assets/docs/a%20%3Cb%3E.txt")), "a");
+
+        let redirect = get("/assets/docs?sort=name");
+        assert_eq!(redirect.status_code, 301);
+        assert_eq!(redirect.headers["Location"], "/assets/docs/?sort=name");
+        let listing = body(get("/assets/docs/"));
+        assert!(listing.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+        assert!(listing.contains("href=\"sub/\"") && !listing.contains(".hidden"));
+
+        for path in [
+            "/assets/../secret.txt",
+            "/assets/%2e%2e/secret.txt",
+            "/assets/css/..%2f..%2fsecret.txt",
+            "/assets/docs/.hidden",
+            "/assets/missing.txt",
+        ] {
+            assert_eq!(get(path).status_code, 404, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_range_requests() {
+        let files = StaticFiles::new(site_dir().join("public"));
+        let get = |headers: &[(&str, &str)]| {
+            let mut request = Request::new("GET", "/css/site.css");
+            for (name, value) in headers {
+                request
+                    .headers
+                    .insert(name.to_ascii_lowercase(), value.to_string());
+            }
+            files.respond("css/site.css", &request)
+        };
+        let full = get(&[]);
+        assert_eq!(full.headers["Accept-Ranges"], "bytes");
+        let last_modified = full.headers["Last-Modified"].clone();
+
+        let response = get(&[("Range", "bytes=-2")]);
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.headers["Content-Range"], "bytes 5-6/7");
+        assert_eq!(response.headers["Content-Length"], "2");
+        assert_eq!(body(response), "{}");
+
+        let response = get(&[("Range", "bytes=0-3,5-")]);
+        let content_type = response.headers["Content-Type"].clone();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let length = response.headers["Content-Length"].clone();
+        let multipart = body(response);
+        assert_eq!(multipart.len().to_string(), length);
+        assert_eq!(
+            multipart,
+            format!(
+                "\r\n--{0}\r\nContent-Type: text/css; charset=utf-8\r\nContent-Range: bytes 0-3/7\r\n\r\nbody\
+                 \r\n--{0}\r\nContent-Type: text/css; charset=utf-8\r\nContent-Range: bytes 5-6/7\r\n\r\n{{}}\
+                 \r\n--{0}--\r\n",
+                boundary
+            )
+        );
+
+        let response = get(&[("Range", "bytes=7-")]);
+        assert_eq!(response.status_code, 416);
+        assert_eq!(response.headers["Content-Range"], "bytes */7");
+        assert_eq!(get(&[("Range", "bytes=3-1")]).status_code, 200);
+        // Ranges of a changed file are not sent
+        let range = [("Range", "bytes=0-1"), ("If-Range", last_modified.as_str())];
+        assert_eq!(get(&range).status_code, 206);
+        let range = [
+            ("Range", "bytes=0-1"),
+            ("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ];
+        assert_eq!(get(&range).status_code, 200);
+    }
+
+    #[test]
+    fn test_precompressed_files() {
+        let mut files = StaticFiles::new(site_dir().join("public"));
+        let get = |files: &StaticFiles, accept_encoding: &str| {
+            let mut request = Request::new("GET", "/css/site.css");
+            request
+                .headers
+                .insert("accept-encoding".to_string(), accept_encoding.to_string());
+            files.respond("css/site.css", &request)
+        };
+        let response = get(&files, "gzip, br;q=0.5");
+        assert_eq!(response.headers["Content-Encoding"], "gzip");
+        assert_eq!(response.headers["Content-Type"], "text/css; charset=utf-8");
+        assert_eq!(response.headers["Vary"], "Accept-Encoding");
+        assert!(response.headers["ETag"].ends_with("-gzip\""));
+        assert_eq!(body(response), "gzip");
+        assert_eq!(body(get(&files, "gzip, br")), "brotli");
+
+        let response = get(&files, "deflate");
+        assert!(!response.headers.contains_key("Content-Encoding"));
+        assert_eq!(response.headers["Vary"], "Accept-Encoding");
+        assert_eq!(body(response), "body {}");
+        files.precompressed = false;
+        assert_eq!(body(get(&files, "br")), "body {}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_outside_root() {
+        let dir = site_dir();
+        let link = dir.join("public/link.txt");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(dir.join("secret.txt"), &link).unwrap();
+        let files = StaticFiles::new(dir.join("public"));
+        assert!(files.resolve("link.txt").is_none());
+        assert!(files.resolve("css/site.css").is_some());
+    }
+
+    #[test]
+    fn test_mime_types() {
+        assert_eq!(mime_type(Path::new("cv.PDF")), "application/pdf");
+        assert_eq!(mime_type(Path::new("a.webp")), "image/webp");
+        assert_eq!(mime_type(Path::new("Makefile")), "application/octet-stream");
+    }
+}
@@ -0,0 +1,751 @@
+//! Translation catalogs, plural rules and locale aware formatting.
+//!
+//! Catalogs are read from gettext `.po` files. Load a folder of catalogs
+//! with [Translations::load_dir] and give them to the renderer through
+//! [RenderOptions](crate::templates::render::RenderOptions):
+//! ```
+//! use std::sync::Arc;
+//! use unchained_web::templates::{i18n::{Catalog, Translations}, render::{render_html, RenderOptions}};
+//!
+//! let nl = Catalog::from_po("nl", r#"
+//! msgid "greeting"
+//! msgstr "Hallo {name}!"
+//! "#).unwrap();
+//! let mut translations = Translations::new("en");
+//! translations.add_catalog(nl);
+//!
+//! let mut options = RenderOptions::empty();
+//! options.translations = Some(Arc::new(translations));
+//! options.locale = Some("nl".to_string());
+//!
+//! let context = [("user".to_string(), "Carl".into())].into();
+//! let html = render_html("{* t greeting name=user *}".to_string(), Some(context), &options);
+//! assert_eq!(html.unwrap(), "Hallo Carl!");
+//! ```
+
+use std::collections::HashMap;
+
+use crate::error::{Error, WebResult};
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Single(String),
+    /// Translations for each plural form. See [plural_index].
+    Plural(Vec<String>),
+}
+
+/// Translated messages for a single locale
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    pub locale: String,
+    pub messages: HashMap<String, Message>,
+}
+
+impl Catalog {
+    pub fn new(locale: &str) -> Self {
+        Catalog {
+            locale: locale.to_string(),
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Read a gettext `.po` file into a catalog for the locale.
+    pub fn load(locale: &str, path: &str) -> WebResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|_| Error::LoadFile(format!("Could not read catalog {}", path)))?;
+        Self::from_po(locale, &content)
+    }
+
+    /// Parse the content of a gettext `.po` file.
+    /// Supports `msgid`, `msgid_plural`, `msgstr`, `msgstr[n]`,
+    /// comments and strings continued over several lines.
+    /// Entries with an empty translation are skipped.
+    pub fn from_po(locale: &str, content: &str) -> WebResult<Self> {
+        let mut catalog = Catalog::new(locale);
+        let mut entry = PoEntry::default();
+        let mut last_field: Option<PoField> = None;
+
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                catalog.insert_entry(std::mem::take(&mut entry));
+                last_field = None;
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let invalid_line =
+                || Error::ParseTemplate(format!("Invalid line {} in catalog", line_number + 1));
+
+            if line.starts_with('"') {
+                let text = unquote_po(line).ok_or_else(invalid_line)?;
+                match last_field {
+                    Some(PoField::Id) => entry.id.push_str(&text),
+                    Some(PoField::IdPlural) => entry
+                        .id_plural
+                        .get_or_insert_with(String::new)
+                        .push_str(&text),
+                    Some(PoField::Str(i)) => entry.strs[i].push_str(&text),
+                    Some(PoField::Context) | None => {}
+                }
+                continue;
+            }
+
+            let (keyword, value) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(invalid_line)?;
+            let text = unquote_po(value.trim()).ok_or_else(invalid_line)?;
+            last_field = Some(match keyword {
+                "msgctxt" => {
+                    catalog.insert_entry(std::mem::take(&mut entry));
+                    PoField::Context
+                }
+                "msgid" => {
+                    if !entry.id.is_empty() || !entry.strs.is_empty() {
+                        catalog.insert_entry(std::mem::take(&mut entry));
+                    }
+                    entry.id = text;
+                    PoField::Id
+                }
+                "msgid_plural" => {
+                    entry.id_plural = Some(text);
+                    PoField::IdPlural
+                }
+                "msgstr" => {
+                    entry.strs = vec![text];
+                    PoField::Str(0)
+                }
+                _ => {
+                    let index = keyword
+                        .strip_prefix("msgstr[")
+                        .and_then(|k| k.strip_suffix(']'))
+                        .and_then(|i| i.parse::<usize>().ok())
+                        .ok_or_else(invalid_line)?;
+                    if entry.strs.len() <= index {
+                        entry.strs.resize(index + 1, String::new());
+                    }
+                    entry.strs[index] = text;
+                    PoField::Str(index)
+                }
+            });
+        }
+        catalog.insert_entry(entry);
+        Ok(catalog)
+    }
+
+    fn insert_entry(&mut self, entry: PoEntry) {
+        // The entry with an empty id is the header of the catalog
+        if entry.id.is_empty() || entry.strs.iter().all(|s| s.is_empty()) {
+            return;
+        }
+        let message = match entry.id_plural {
+            Some(_) => Message::Plural(entry.strs),
+            None => Message::Single(entry.strs.into_iter().next().unwrap_or_default()),
+        };
+        self.messages.insert(entry.id, message);
+    }
+
+    /// Find the translation of the key.
+    /// Plural messages use `count` to pick the plural form of the locale.
+    pub fn get(&self, key: &str, count: Option<isize>) -> Option<&str> {
+        match (self.messages.get(key)?, count) {
+            (Message::Single(s), _) => Some(s),
+            (Message::Plural(forms), count) => {
+                let index = plural_index(&self.locale, count.unwrap_or(1));
+                forms.get(index).or(forms.last()).map(|s| s.as_str())
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct PoEntry {
+    id: String,
+    id_plural: Option<String>,
+    strs: Vec<String>,
+}
+
+enum PoField {
+    Context,
+    Id,
+    IdPlural,
+    Str(usize),
+}
+
+/// Remove quotes around a `.po` string and resolve escaped characters
+fn unquote_po(value: &str) -> Option<String> {
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next()? {
+            'n' => result.push('\n'),
+            't' => result.push('\t'),
+            'r' => result.push('\r'),
+            other => result.push(other),
+        }
+    }
+    Some(result)
+}
+
+/// Catalogs for all available locales.
+/// Lookups fall back to the fallback locale and then to the key itself.
+#[derive(Debug, Clone)]
+pub struct Translations {
+    pub catalogs: HashMap<String, Catalog>,
+    pub fallback: String,
+}
+
+impl Translations {
+    pub fn new(fallback: &str) -> Self {
+        Translations {
+            catalogs: HashMap::new(),
+            fallback: fallback.to_string(),
+        }
+    }
+
+    /// Load every `<locale>.po` file in a directory.
+    pub fn load_dir(dir: &str, fallback: &str) -> WebResult<Self> {
+        let mut translations = Translations::new(fallback);
+        let entries = std::fs::read_dir(dir)
+            .map_err(|_| Error::LoadFile(format!("Could not read directory {}", dir)))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("po") {
+                continue;
+            }
+            if let Some(locale) = path.file_stem().and_then(|s| s.to_str()) {
+                let path_str = path.to_string_lossy();
+                translations.add_catalog(Catalog::load(locale, &path_str)?);
+            }
+        }
+        Ok(translations)
+    }
+
+    pub fn add_catalog(&mut self, catalog: Catalog) -> &mut Self {
+        self.catalogs.insert(catalog.locale.clone(), catalog);
+        self
+    }
+
+    /// Locales with a catalog, including the fallback locale
+    pub fn locales(&self) -> Vec<&str> {
+        let mut locales = self.catalogs.keys().map(|s| s.as_str()).collect::<Vec<_>>();
+        if !self.catalogs.contains_key(&self.fallback) {
+            locales.push(&self.fallback);
+        }
+        locales.sort();
+        locales
+    }
+
+    fn catalog(&self, locale: &str) -> Option<&Catalog> {
+        self.catalogs
+            .get(locale)
+            .or_else(|| self.catalogs.get(language(locale)))
+    }
+
+    /// Translate a key for the locale.
+    /// Returns the key itself if no catalog contains it.
+    pub fn translate<'a>(&'a self, locale: &str, key: &'a str, count: Option<isize>) -> &'a str {
+        self.catalog(locale)
+            .and_then(|c| c.get(key, count))
+            .or_else(|| self.catalog(&self.fallback).and_then(|c| c.get(key, count)))
+            .unwrap_or(key)
+    }
+}
+
+/// Replace `{name}` placeholders in a message with the given values.
+/// Unknown placeholders are kept as is.
+pub fn interpolate(message: &str, values: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) if values.contains_key(&after[..end]) => {
+                result.push_str(&values[&after[..end]]);
+                rest = &after[end + 1..];
+            }
+            _ => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Primary language of a locale. `nl-NL` and `nl_NL` both give `nl`.
+pub fn language(locale: &str) -> &str {
+    locale.split(['-', '_']).next().unwrap_or(locale)
+}
+
+/// Index of the plural form to use for `n` items in a locale.
+/// Follows the gettext `Plural-Forms` conventions of the language.
+pub fn plural_index(locale: &str, n: isize) -> usize {
+    let n = n.unsigned_abs();
+    let locale = locale.to_lowercase().replace('-', "_");
+    // Regional variants with other rules than their language are matched first
+    let lang = match locale.as_str() {
+        "pt_br" => "pt_br",
+        locale => language(locale),
+    };
+    match lang {
+        "ja" | "zh" | "ko" | "vi" | "th" | "id" | "ms" | "tr" => 0,
+        "fr" | "pt_br" => usize::from(n > 1),
+        "ru" | "uk" | "be" | "sr" | "hr" | "bs" => {
+            if n % 10 == 1 && n % 100 != 11 {
+                0
+            } else if (2..=4).contains(&(n % 10)) && !(12..=14).contains(&(n % 100)) {
+                1
+            } else {
+                2
+            }
+        }
+        "pl" => {
+            if n == 1 {
+                0
+            } else if (2..=4).contains(&(n % 10)) && !(12..=14).contains(&(n % 100)) {
+                1
+            } else {
+                2
+            }
+        }
+        "cs" | "sk" => match n {
+            1 => 0,
+            2..=4 => 1,
+            _ => 2,
+        },
+        _ => usize::from(n != 1),
+    }
+}
+
+/// Pick the best available locale from an `Accept-Language` header value.
+/// Matches exact locales first and then the primary language.
+/// ```
+/// use unchained_web::templates::i18n::negotiate_locale;
+/// let locale = negotiate_locale("nl-BE,nl;q=0.9,en;q=0.8", &["en", "nl"]);
+/// assert_eq!(locale, Some("nl".to_string()));
+/// ```
+pub fn negotiate_locale(accept_language: &str, available: &[&str]) -> Option<String> {
+    let mut requested = accept_language
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.trim().split(';');
+            let tag = params.next()?.trim();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect::<Vec<_>>();
+    requested.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    for (tag, _) in requested {
+        if tag == "*" {
+            return available.first().map(|s| s.to_string());
+        }
+        let exact = available.iter().find(|a| a.eq_ignore_ascii_case(tag));
+        let by_language = || {
+            available
+                .iter()
+                .find(|a| language(a).eq_ignore_ascii_case(language(tag)))
+        };
+        if let Some(locale) = exact.or_else(by_language) {
+            return Some(locale.to_string());
+        }
+    }
+    None
+}
+
+/// Split a leading locale segment like `/nl/...` from a path.
+/// Returns the locale and the rest of the path.
+/// ```
+/// use unchained_web::templates::i18n::locale_from_path;
+/// let res = locale_from_path("/nl/courses", &["en", "nl"]);
+/// assert_eq!(res, Some(("nl".to_string(), "/courses".to_string())));
+/// ```
+pub fn locale_from_path(path: &str, available: &[&str]) -> Option<(String, String)> {
+    let trimmed = path.trim_start_matches('/');
+    let (first, rest) = trimmed.split_once('/').unwrap_or((trimmed, ""));
+    let locale = available.iter().find(|a| a.eq_ignore_ascii_case(first))?;
+    Some((locale.to_string(), format!("/{}", rest)))
+}
+
+fn grouping_separator(locale: &str) -> &'static str {
+    match language(locale).to_lowercase().as_str() {
+        "nl" | "de" | "da" | "it" | "es" | "pt" | "id" | "tr" => ".",
+        "fr" | "nb" | "no" | "nn" | "sv" | "fi" | "ru" | "pl" | "cs" | "sk" | "uk" => "\u{a0}",
+        "en" | "ja" | "zh" | "ko" => ",",
+        _ => ",",
+    }
+}
+
+/// Format an integer with the digit grouping of the locale.
+/// ```
+/// use unchained_web::templates::i18n::format_number;
+/// assert_eq!(format_number(1234567, "en"), "1,234,567");
+/// assert_eq!(format_number(-1234, "nl"), "-1.234");
+/// ```
+pub fn format_number(number: isize, locale: &str) -> String {
+    let digits = number.unsigned_abs().to_string();
+    let separator = grouping_separator(locale);
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push_str(separator);
+        }
+        grouped.push(digit);
+    }
+    if number < 0 {
+        grouped.insert(0, '-');
+    }
+    grouped
+}
+
+fn month_names(locale: &str) -> Option<[&'static str; 12]> {
+    Some(match language(locale).to_lowercase().as_str() {
+        "en" => [
+            "January",
+            "February",
+            "March",
+            "April",
+            "May",
+            "June",
+            "July",
+            "August",
+            "September",
+            "October",
+            "November",
+            "December",
+        ],
+        "nl" => [
+            "januari",
+            "februari",
+            "maart",
+            "april",
+            "mei",
+            "juni",
+            "juli",
+            "augustus",
+            "september",
+            "oktober",
+            "november",
+            "december",
+        ],
+        "de" => [
+            "Januar",
+            "Februar",
+            "März",
+            "April",
+            "Mai",
+            "Juni",
+            "Juli",
+            "August",
+            "September",
+            "Oktober",
+            "November",
+            "Dezember",
+        ],
+        "fr" => [
+            "janvier",
+            "février",
+            "mars",
+            "avril",
+            "mai",
+            "juin",
+            "juillet",
+            "août",
+            "septembre",
+            "octobre",
+            "novembre",
+            "décembre",
+        ],
+        "nb" | "no" | "nn" => [
+            "januar",
+            "februar",
+            "mars",
+            "april",
+            "mai",
+            "juni",
+            "juli",
+            "august",
+            "september",
+            "oktober",
+            "november",
+            "desember",
+        ],
+        "es" => [
+            "enero",
+            "febrero",
+            "marzo",
+            "abril",
+            "mayo",
+            "junio",
+            "julio",
+            "agosto",
+            "septiembre",
+            "octubre",
+            "noviembre",
+            "diciembre",
+        ],
+        _ => return None,
+    })
+}
+
+/// A calendar date without time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Date of a unix timestamp in seconds (UTC)
+    pub fn from_timestamp(seconds: i64) -> Self {
+        // Civil from days algorithm by Howard Hinnant
+        let days = seconds.div_euclid(60 * 60 * 24) + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        Date { year, month, day }
+    }
+
+    /// Days since 1970-01-01
+    pub fn days_since_epoch(&self) -> i64 {
+        // Days from civil algorithm by Howard Hinnant
+        let year = self.year - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let mp = (i64::from(self.month) + 9) % 12;
+        let day_of_year = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    /// Day of the week, where 0 is Sunday
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        (self.days_since_epoch() + 4).rem_euclid(7) as u32
+    }
+
+    /// Parse the date part of an ISO 8601 string, e.g. `2024-03-01` or `2024-03-01T12:00:00Z`
+    pub fn parse_iso(value: &str) -> Option<Self> {
+        let date = value.get(..10)?;
+        let mut parts = date.split('-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        ((1..=12).contains(&month) && (1..=31).contains(&day)).then_some(Date { year, month, day })
+    }
+
+    /// Format the date for a locale.
+    /// `long` writes the month name, otherwise the numeric form of the locale is used.
+    /// Unknown locales use ISO 8601.
+    /// ```
+    /// use unchained_web::templates::i18n::Date;
+    /// let date = Date { year: 2002, month: 6, day: 19 };
+    /// assert_eq!(date.format("en", true), "June 19, 2002");
+    /// assert_eq!(date.format("nl", true), "19 juni 2002");
+    /// assert_eq!(date.format("de", false), "19.06.2002");
+    /// ```
+    pub fn format(&self, locale: &str, long: bool) -> String {
+        let Date { year, month, day } = *self;
+        let lang = language(locale).to_lowercase();
+        if long {
+            let months = month_names(&lang).zip((month as usize).checked_sub(1));
+            let name = months.and_then(|(months, index)| months.get(index).copied());
+            let Some(name) = name else {
+                return format!("{year:04}-{month:02}-{day:02}");
+            };
+            return match lang.as_str() {
+                "en" => format!("{name} {day}, {year}"),
+                "de" | "nb" | "no" | "nn" => format!("{day}. {name} {year}"),
+                "es" => format!("{day} de {name} de {year}"),
+                _ => format!("{day} {name} {year}"),
+            };
+        }
+        match lang.as_str() {
+            "en" if locale.eq_ignore_ascii_case("en-gb") => format!("{day:02}/{month:02}/{year}"),
+            "en" => format!("{month}/{day}/{year}"),
+            "nl" => format!("{day:02}-{month:02}-{year}"),
+            "de" | "nb" | "no" | "nn" | "ru" | "pl" | "cs" => {
+                format!("{day:02}.{month:02}.{year}")
+            }
+            "fr" | "es" | "it" | "pt" => format!("{day:02}/{month:02}/{year}"),
+            _ => format!("{year:04}-{month:02}-{day:02}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NL_PO: &str = r#"
+# Dutch translations
+msgid ""
+msgstr ""
+"Language: nl\n"
+"Plural-Forms: nplurals=2; plural=(n != 1);\n"
+
+#: templates/base.html
+msgid "nav.about"
+msgstr "Over mij"
+
+msgid "courses.count"
+msgid_plural "courses.count"
+msgstr[0] "{count} vak"
+msgstr[1] "{count} vakken"
+
+msgid "long"
+msgstr ""
+"Een regel "
+"en nog een"
+
+msgid "untranslated"
+msgstr ""
+"#;
+
+    #[test]
+    fn test_parse_po_catalog() {
+        let catalog = Catalog::from_po("nl", NL_PO).unwrap();
+        assert_eq!(catalog.get("nav.about", None), Some("Over mij"));
+        assert_eq!(catalog.get("long", None), Some("Een regel en nog een"));
+        assert_eq!(catalog.get("courses.count", Some(1)), Some("{count} vak"));
+        assert_eq!(
+            catalog.get("courses.count", Some(4)),
+            Some("{count} vakken")
+        );
+        assert!(catalog.get("untranslated", None).is_none());
+        assert!(catalog.get("", None).is_none());
+    }
+
+    #[test]
+    fn test_translate_falls_back() {
+        let mut translations = Translations::new("en");
+        translations.add_catalog(Catalog::from_po("nl", NL_PO).unwrap());
+        translations.add_catalog(
+            Catalog::from_po("en", "msgid \"nav.skills\"\nmsgstr \"Skills\"").unwrap(),
+        );
+        assert_eq!(
+            translations.translate("nl-BE", "nav.about", None),
+            "Over mij"
+        );
+        assert_eq!(translations.translate("nl", "nav.skills", None), "Skills");
+        assert_eq!(translations.translate("nl", "missing", None), "missing");
+    }
+
+    #[test]
+    fn test_plural_rules() {
+        assert_eq!(plural_index("en", 1), 0);
+        assert_eq!(plural_index("en", 0), 1);
+        assert_eq!(plural_index("fr", 0), 0);
+        assert_eq!(plural_index("ja", 5), 0);
+        let russian = [(1, 0), (3, 1), (5, 2), (11, 2), (21, 0), (22, 1), (112, 2)];
+        for (n, form) in russian {
+            assert_eq!(plural_index("ru", n), form, "n = {n}");
+        }
+        assert_eq!(plural_index("pl", 22), 1);
+        assert_eq!(plural_index("pl", 21), 2);
+        assert_eq!(plural_index("pt-BR", 0), 0);
+        assert_eq!(plural_index("pt_BR", 2), 1);
+        assert_eq!(plural_index("pt", 0), 1);
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let values = HashMap::from([("name".to_string(), "Carl".to_string())]);
+        assert_eq!(
+            interpolate("Hi {name}, {other} {", &values),
+            "Hi Carl, {other} {"
+        );
+    }
+
+    #[test]
+    fn test_negotiate_locale() {
+        let available = ["en", "nl", "de-AT"];
+        assert_eq!(
+            negotiate_locale("de;q=0.5, nl;q=0.8", &available),
+            Some("nl".into())
+        );
+        assert_eq!(negotiate_locale("de-de", &available), Some("de-AT".into()));
+        assert_eq!(
+            negotiate_locale("fr, *;q=0.1", &available),
+            Some("en".into())
+        );
+        assert_eq!(negotiate_locale("fr, nl;q=0", &available), None);
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(
+            Date::from_timestamp(1_024_444_800),
+            Date {
+                year: 2002,
+                month: 6,
+                day: 19
+            }
+        );
+        assert_eq!(
+            Date::from_timestamp(0),
+            Date {
+                year: 1970,
+                month: 1,
+                day: 1
+            }
+        );
+        assert_eq!(
+            Date::parse_iso("2024-03-01T10:00:00Z"),
+            Some(Date {
+                year: 2024,
+                month: 3,
+                day: 1
+            })
+        );
+        assert!(Date::parse_iso("2024-13-01").is_none());
+        let birthday = Date {
+            year: 2002,
+            month: 6,
+            day: 19,
+        };
+        assert_eq!(birthday.days_since_epoch() * 60 * 60 * 24, 1_024_444_800);
+        assert_eq!(birthday.weekday(), 3);
+        let date = Date {
+            year: 2024,
+            month: 3,
+            day: 1,
+        };
+        assert_eq!(date.format("fr", true), "1 mars 2024");
+        assert_eq!(date.format("en", false), "3/1/2024");
+        assert_eq!(date.format("xx", true), "2024-03-01");
+        let invalid = Date {
+            year: 2024,
+            month: 13,
+            day: 1,
+        };
+        assert_eq!(invalid.format("en", true), "2024-13-01");
+        assert_eq!(
+            Date {
+                month: 0,
+                ..invalid
+            }
+            .format("nl", true),
+            "2024-00-01"
+        );
+    }
+}
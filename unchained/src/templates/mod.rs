@@ -1,4 +1,5 @@
 pub mod context;
+pub mod i18n;
 pub mod operations;
 pub mod render;
 pub mod text_parse;
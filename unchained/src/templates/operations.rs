@@ -1,10 +1,11 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write, path::Path};
 
 use crate::error::{Error, WebResult};
 
 use super::{
     context::{ContextMap, ContextTree as Ctx, Primitive::*},
-    render::{load_template, render_html, RenderOptions},
+    i18n::{format_number, interpolate, Date},
+    render::{include_to, load_template, render_content_to, RenderOptions},
     text_parse::{between_connected_patterns, Match},
 };
 
@@ -59,7 +60,8 @@ pub type TemplateOperation =
 /// Get the operation function from a template operation name
 /// If not found as a default name, it uses a custom operation from template options.
 /// Current reserved operation names:
-/// `"get"`, `"for"`, `"if`, `"component`, `"slot`, `"comment`, `"dbg`
+/// `"get"`, `"for"`, `"if`, `"component`, `"slot`, `"comment`, `"dbg`,
+/// `"t"`, `"trans"`, `"num"`, `"date"`, `"url"`
 ///
 /// Example template operation
 /// ```html
@@ -80,6 +82,10 @@ pub fn get_template_operation(
         "slot" => Some(slot),
         "comment" => Some(comment_operation),
         "dbg" => Some(dbg_operation),
+        "t" | "trans" => Some(translate_operation),
+        "num" => Some(number_operation),
+        "date" => Some(date_operation),
+        "url" => Some(url_operation),
         s => custom_operations.get(s).copied(),
     }
 }
@@ -204,8 +210,21 @@ fn if_operation(
 fn for_loop_operation(
     call: TemplateOperationCall,
     context: &ContextMap,
-    _options: &RenderOptions,
+    options: &RenderOptions,
 ) -> WebResult<String> {
+    let mut output = Vec::new();
+    for_loop_to(call, context, options, &mut output)?;
+    String::from_utf8(output)
+        .map_err(|_| Error::ParseTemplate("Rendered loop is not valid UTF-8".to_string()))
+}
+
+/// Render a [for loop](for_loop_operation) straight to a writer, one iteration at a time
+pub(crate) fn for_loop_to(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+    writer: &mut dyn Write,
+) -> WebResult<()> {
     let param_slice = unwrap_n_params::<3>(&call.parameters)?;
     let (element, range_key) = match param_slice {
         [element, "in", range] => (element, range),
@@ -234,20 +253,12 @@ fn for_loop_operation(
         }
     };
     let mut new_context = context.clone();
-    let mut iterated_content = String::new();
     for item in range.iter() {
+        options.count_loop_iteration()?;
         new_context.insert(element.to_string(), (*item).clone());
-        iterated_content.push_str(
-            render_html(
-                children.clone(),
-                Some(new_context.to_owned()),
-                &RenderOptions::empty(),
-            )
-            .unwrap()
-            .as_str(),
-        );
+        render_content_to(&children, &new_context, options, writer)?;
     }
-    Ok(iterated_content)
+    Ok(())
 }
 
 /// Loads an html file to include in a template.
@@ -258,6 +269,10 @@ fn for_loop_operation(
 /// Context can be given to the component. If it is,
 /// other context data is removed.
 ///
+/// The file path is resolved with [RenderOptions::resolve_template],
+/// so `./` and `../` paths are relative to the including template.
+/// Only files with [allowed extensions](RenderOptions::allowed_extensions) can be included.
+///
 /// ```html
 /// <!-- page.html -->
 /// {* component file.html data=object.attribute {
@@ -279,33 +294,62 @@ fn component_operation(
     context: &ContextMap,
     options: &RenderOptions,
 ) -> WebResult<String> {
+    let (file_path, new_context) = component_file_and_context(call, context, options)?;
+    load_template(&file_path, Some(new_context), options)
+}
+
+/// Render a [component operation](component_operation) straight to a writer
+pub(crate) fn component_to(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+    writer: &mut dyn Write,
+) -> WebResult<()> {
+    let (file_path, new_context) = component_file_and_context(call, context, options)?;
+    include_to(&file_path, &new_context, options, writer)
+}
+
+/// Validates the component file path and creates the context
+/// the component is rendered with, including its slots.
+fn component_file_and_context(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+) -> WebResult<(String, ContextMap)> {
     let parameters = call.parameters;
     let file_path = parameters.first();
     let file_path = match file_path {
         Some(file_path) => file_path,
         None => return Err(Error::InvalidParams("File path not specified".to_string())),
     };
-    if !file_path.ends_with(".html")
-        && !file_path.ends_with(".svg")
-        && !file_path.ends_with(".js")
-        && !file_path.ends_with(".css")
-    {
-        return Err(Error::InvalidParams("Invalid file path".to_string()));
+    let extension = Path::new(file_path).extension().and_then(|e| e.to_str());
+    if !extension.is_some_and(|e| options.allowed_extensions.iter().any(|a| a == e)) {
+        return Err(Error::InvalidParams(format!(
+            "Invalid file path {}. Allowed extensions are {:?}",
+            file_path, options.allowed_extensions
+        )));
     }
     let mut new_context = context.clone();
     if parameters.len() > 1 {
         new_context.clear();
-        parameters
-            .iter()
-            .skip(1) // Skip file path
-            .map(|p| p.split('=').collect::<Vec<_>>())
-            .for_each(|p| {
-                if p.len() != 2 {
-                    return;
+        // Skip file path
+        for parameter in parameters.iter().skip(1) {
+            let (name, attribute) = match parameter.split_once('=') {
+                Some((name, attribute))
+                    if !name.is_empty() && !attribute.is_empty() && !attribute.contains('=') =>
+                {
+                    (name, attribute)
                 }
-                let item = attribute_from_context(p[1], context);
-                new_context.insert(p[0].to_string(), item.unwrap());
-            });
+                _ => {
+                    return Err(Error::InvalidParams(format!(
+                        "Invalid component parameter '{}'. Expected name=attribute",
+                        parameter
+                    )))
+                }
+            };
+            let item = attribute_from_context(attribute, context)?;
+            new_context.insert(name.to_string(), item);
+        }
     }
 
     if let Some(children) = call.children {
@@ -330,9 +374,7 @@ fn component_operation(
         }
     }
 
-    let rendered = load_template(file_path, Some(new_context), options)?;
-
-    Ok(rendered)
+    Ok((file_path.to_string(), new_context))
 }
 
 /// Retrives html to include in context
@@ -415,3 +457,159 @@ fn dbg_operation(
     dbg.push_str(" }");
     Ok(dbg)
 }
+
+/// Value of a `key=value` parameter.
+/// The value is looked up in the context, or used as a number literal.
+fn param_value(value: &str, context: &ContextMap) -> WebResult<Ctx> {
+    match attribute_from_context(value, context) {
+        Ok(attribute) => Ok(attribute),
+        Err(e) => value.parse::<isize>().map(Ctx::from).map_err(|_| e),
+    }
+}
+
+/// Translates a key with the catalogs in the render options.
+/// The key can be given directly or as an attribute in the context.
+/// Other parameters are interpolated into `{name}` placeholders.
+/// The `count` parameter selects the plural form.
+/// ```html
+/// {* t nav.about *}
+/// {* trans courses.count count=courses.length *}
+/// {* t greeting name=user.name *}
+/// ```
+fn translate_operation(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+) -> WebResult<String> {
+    let translations = options.translations.as_ref().ok_or_else(|| {
+        Error::InvalidParams("No translations given in render options".to_string())
+    })?;
+    let key = call
+        .parameters
+        .first()
+        .ok_or_else(|| Error::InvalidParams("Translation key not specified".to_string()))?;
+    let key = match attribute_from_context(key, context) {
+        Ok(Ctx::Leaf(Str(key_in_context))) => key_in_context,
+        _ => key.to_string(),
+    };
+
+    let mut values = HashMap::new();
+    let mut count = None;
+    for param in call.parameters.iter().skip(1) {
+        let Some((name, value)) = param.split_once('=') else {
+            return Err(Error::InvalidParams(format!(
+                "Expected name=value, but got '{}'",
+                param
+            )));
+        };
+        let value = match (name, param_value(value, context)?) {
+            ("count", Ctx::Leaf(Num(n))) => {
+                count = Some(n);
+                n.to_string()
+            }
+            ("count", Ctx::Array(arr)) => {
+                count = Some(arr.len() as isize);
+                arr.len().to_string()
+            }
+            ("count", res) => {
+                return Err(Error::InvalidParams(format!(
+                    "Count has to be a number or an array. Got {:?}",
+                    res
+                )))
+            }
+            (_, value) => value.to_string(),
+        };
+        values.insert(name.to_string(), value);
+    }
+
+    let message = translations.translate(options.current_locale(), &key, count);
+    Ok(interpolate(message, &values))
+}
+
+/// Formats a number with the digit grouping of the current locale
+/// ```html
+/// {* num stats.visitors *} <!-- 12,345 in english and 12.345 in dutch -->
+/// ```
+fn number_operation(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+) -> WebResult<String> {
+    let attribute = unwrap_n_params::<1>(&call.parameters)?[0];
+    match param_value(attribute, context)? {
+        Ctx::Leaf(Num(n)) => Ok(format_number(n, options.current_locale())),
+        res => Err(Error::InvalidParams(format!(
+            "Expected a number, but found {:?}",
+            res
+        ))),
+    }
+}
+
+/// Formats a date for the current locale.
+/// The date is a unix timestamp or an ISO 8601 string.
+/// Add `short` to use the numeric form.
+/// ```html
+/// {* date post.published *} <!-- June 19, 2002 -->
+/// {* date post.published short *} <!-- 6/19/2002 -->
+/// ```
+fn date_operation(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+) -> WebResult<String> {
+    let (attribute, long) = match call.parameters.as_slice() {
+        [attribute] => (attribute, true),
+        [attribute, style] if style == "short" => (attribute, false),
+        [attribute, style] if style == "long" => (attribute, true),
+        params => {
+            return Err(Error::InvalidParams(format!(
+                "Expected 'date attribute [short|long]', but got '{:?}'",
+                params
+            )))
+        }
+    };
+    let date = match param_value(attribute, context)? {
+        Ctx::Leaf(Num(timestamp)) => Date::from_timestamp(timestamp as i64),
+        Ctx::Leaf(Str(iso)) => Date::parse_iso(&iso)
+            .ok_or_else(|| Error::InvalidParams(format!("Invalid ISO 8601 date '{}'", iso)))?,
+        res => {
+            return Err(Error::InvalidParams(format!(
+                "Expected a timestamp or ISO 8601 date, but found {:?}",
+                res
+            )))
+        }
+    };
+    Ok(date.format(options.current_locale(), long))
+}
+
+/// Path to a named route from the urls in the render options.
+/// Other parameters fill the parameters of the route.
+/// Fails if the route or one of its parameters is missing.
+/// ```html
+/// {* url course_detail courseid=course.course_id *} <!-- /courses/CS4505 -->
+/// ```
+fn url_operation(
+    call: TemplateOperationCall,
+    context: &ContextMap,
+    options: &RenderOptions,
+) -> WebResult<String> {
+    let urls = options
+        .urls
+        .as_ref()
+        .ok_or_else(|| Error::InvalidParams("No urls given in render options".to_string()))?;
+    let name = call
+        .parameters
+        .first()
+        .ok_or_else(|| Error::InvalidParams("Route name not specified".to_string()))?;
+    let mut params = HashMap::new();
+    for param in call.parameters.iter().skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            return Err(Error::InvalidParams(format!(
+                "Expected name=value, but got '{}'",
+                param
+            )));
+        };
+        params.insert(key.to_string(), param_value(value, context)?.to_string());
+    }
+    urls.url_for(name, &params)
+}
@@ -1,66 +1,371 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::error::{Error, WebResult};
+use crate::{
+    error::{Error, WebResult},
+    router::Urls,
+};
 
 use super::{
     context::ContextMap,
+    i18n::Translations,
     operations::{
-        get_template_operation, operation_params_and_children, template_operation,
-        TemplateOperation,
+        component_to, for_loop_to, get_template_operation, operation_params_and_children,
+        template_operation, TemplateOperation,
     },
 };
 
 #[derive(Clone)]
 pub struct RenderOptions<'a> {
     pub custom_operations: HashMap<&'a str, TemplateOperation>,
+    /// Catalogs used by the `t` operation
+    pub translations: Option<Arc<Translations>>,
+    /// Locale used for translations and formatting.
+    /// Falls back to the fallback locale of the translations.
+    pub locale: Option<String>,
+    /// Paths of named routes used by the `url` operation
+    pub urls: Option<Arc<Urls>>,
+    /// Directory template paths are resolved from.
+    /// Templates can not be loaded from outside of the root when it is set.
+    /// Uses the working directory if `None`.
+    pub template_root: Option<PathBuf>,
+    /// File extensions a `component` can include
+    pub allowed_extensions: Vec<String>,
+    /// Limits for rendering untrusted templates
+    pub limits: RenderLimits,
+    /// State of the ongoing render. Managed by the renderer.
+    pub state: RenderState,
 }
 
 impl RenderOptions<'_> {
     pub fn empty() -> Self {
         RenderOptions {
             custom_operations: HashMap::new(),
+            translations: None,
+            locale: None,
+            urls: None,
+            template_root: None,
+            allowed_extensions: ["html", "svg", "js", "css"].map(String::from).to_vec(),
+            limits: RenderLimits::default(),
+            state: RenderState::default(),
+        }
+    }
+
+    /// Options resolving template paths from `root`.
+    /// See [RenderOptions::resolve_template].
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        RenderOptions {
+            template_root: Some(root.into()),
+            ..RenderOptions::empty()
+        }
+    }
+
+    /// Locale to render with. Defaults to `"en"` without translations.
+    pub fn current_locale(&self) -> &str {
+        match (&self.locale, &self.translations) {
+            (Some(locale), _) => locale,
+            (None, Some(translations)) => &translations.fallback,
+            (None, None) => "en",
+        }
+    }
+
+    /// Options with the budget of a render started.
+    /// Renders inside an ongoing render share its budget.
+    fn started(&self) -> Cow<'_, Self> {
+        if self.state.budget.is_some() {
+            return Cow::Borrowed(self);
+        }
+        let mut options = self.clone();
+        options.state.budget = Some(Arc::new(RenderBudget {
+            started: Instant::now(),
+            loop_iterations: AtomicUsize::new(0),
+            output_size: AtomicUsize::new(0),
+        }));
+        Cow::Owned(options)
+    }
+
+    /// Returns [Error::RenderLimit] if the render has run for too long
+    pub(crate) fn check_time(&self) -> WebResult<()> {
+        if let (Some(timeout), Some(budget)) = (self.limits.timeout, &self.state.budget) {
+            if budget.started.elapsed() > timeout {
+                return Err(Error::RenderLimit(format!(
+                    "Rendering took longer than {} ms",
+                    timeout.as_millis()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a loop iteration and check it against the limit
+    pub(crate) fn count_loop_iteration(&self) -> WebResult<()> {
+        self.check_time()?;
+        if let (Some(max), Some(budget)) = (self.limits.max_loop_iterations, &self.state.budget) {
+            if budget.loop_iterations.fetch_add(1, Ordering::Relaxed) >= max {
+                return Err(Error::RenderLimit(format!(
+                    "More than {} loop iterations",
+                    max
+                )));
+            }
         }
+        Ok(())
+    }
+
+    /// Find the file of a template path.
+    /// Paths starting with `./` or `../` are relative to the template being rendered.
+    /// Slot content is rendered in the component it is given to.
+    /// Other paths are relative to the template root, or the working directory
+    /// without a root. With a root, paths that escape it are rejected.
+    pub fn resolve_template(&self, path: &str) -> WebResult<PathBuf> {
+        let relative_to_including = path.starts_with("./") || path.starts_with("../");
+        let including_dir = self.state.includes.last().and_then(|p| p.parent());
+        let resolved = match (relative_to_including, including_dir, &self.template_root) {
+            (true, Some(dir), _) => dir.join(path),
+            (_, _, Some(root)) => root.join(path.trim_start_matches('/')),
+            (_, _, None) => PathBuf::from(path),
+        };
+        let Some(root) = &self.template_root else {
+            return Ok(resolved);
+        };
+
+        let not_found = || Error::LoadFile(format!("Could not read file {}", path));
+        let root = std::fs::canonicalize(root).map_err(|_| not_found())?;
+        let resolved = std::fs::canonicalize(resolved).map_err(|_| not_found())?;
+        if !resolved.starts_with(&root) {
+            return Err(Error::LoadFile(format!(
+                "{} is outside of the template root",
+                path
+            )));
+        }
+        Ok(resolved)
+    }
+
+    /// Check that a template can be read and included.
+    /// Returns the file of the template and the options to render it with.
+    fn enter_template(&self, path: &str) -> WebResult<(PathBuf, Self)> {
+        let limits = &self.limits;
+        let file = self.resolve_template(path)?;
+        let mut options = self.clone();
+        if limits.allowed_dirs.is_empty() && !limits.deny_recursion {
+            options.state.includes.push(file.clone());
+        } else {
+            let canonical = std::fs::canonicalize(&file)
+                .map_err(|_| Error::LoadFile(format!("Could not read file {}", path)))?;
+            let allowed = limits.allowed_dirs.is_empty()
+                || limits.allowed_dirs.iter().any(|dir| {
+                    std::fs::canonicalize(dir).is_ok_and(|dir| canonical.starts_with(dir))
+                });
+            if !allowed {
+                return Err(Error::RenderLimit(format!(
+                    "{} is outside of the allowed directories",
+                    path
+                )));
+            }
+            if limits.deny_recursion && self.state.includes.contains(&canonical) {
+                return Err(Error::RenderLimit(format!("{} includes itself", path)));
+            }
+            options.state.includes.push(canonical);
+        }
+        if let Some(max) = limits.max_include_depth {
+            // The first template is not an include
+            if options.state.includes.len() > max + 1 {
+                return Err(Error::RenderLimit(format!(
+                    "Includes nested deeper than {} levels",
+                    max
+                )));
+            }
+        }
+        Ok((file, options))
+    }
+}
+
+/// Limits for rendering templates that can not be trusted.
+/// No limits are applied by default.
+/// Exceeding a limit gives an [Error::RenderLimit].
+#[derive(Clone, Debug, Default)]
+pub struct RenderLimits {
+    /// How deep components and other templates can be included in each other
+    pub max_include_depth: Option<usize>,
+    /// Fail when a component includes itself, directly or through other components
+    pub deny_recursion: bool,
+    /// Maximum bytes of output of a render.
+    /// Templates rendered by custom operations count towards it as well.
+    pub max_output_size: Option<usize>,
+    /// Maximum iterations of all loops in a render together
+    pub max_loop_iterations: Option<usize>,
+    /// Maximum time a render can take
+    pub timeout: Option<Duration>,
+    /// Directories templates can be loaded from, e.g. by `component`.
+    /// Any directory is allowed if empty.
+    pub allowed_dirs: Vec<PathBuf>,
+}
+
+impl RenderLimits {
+    /// Limits suitable for templates uploaded by users
+    pub fn sandboxed(allowed_dirs: Vec<PathBuf>) -> Self {
+        RenderLimits {
+            max_include_depth: Some(16),
+            deny_recursion: true,
+            max_output_size: Some(10 * 1024 * 1024),
+            max_loop_iterations: Some(100_000),
+            timeout: Some(Duration::from_secs(2)),
+            allowed_dirs,
+        }
+    }
+}
+
+/// Shared state for all templates in a single render
+#[derive(Clone, Debug, Default)]
+pub struct RenderState {
+    budget: Option<Arc<RenderBudget>>,
+    includes: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+struct RenderBudget {
+    started: Instant,
+    loop_iterations: AtomicUsize,
+    /// Bytes written by all templates of the render
+    output_size: AtomicUsize,
+}
+
+/// Writer that fails when the render has written more than `max` bytes
+struct LimitedWriter<'a, W: Write + ?Sized> {
+    inner: &'a mut W,
+    budget: &'a RenderBudget,
+    max: Option<usize>,
+    exceeded: bool,
+}
+
+impl<W: Write + ?Sized> Write for LimitedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self
+            .budget
+            .output_size
+            .fetch_add(buf.len(), Ordering::Relaxed);
+        if self.max.is_some_and(|max| written + buf.len() > max) {
+            self.exceeded = true;
+            return Err(std::io::Error::other("Render output limit exceeded"));
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
 /// Turn string with template operations into html
 /// Context gives template operations access to data
 pub fn render_html(
-    mut content: String,
+    content: String,
     context: Option<ContextMap>,
     options: &RenderOptions,
 ) -> WebResult<String> {
+    let mut output = Vec::with_capacity(content.len());
+    render_to(&content, context, options, &mut output)?;
+    String::from_utf8(output)
+        .map_err(|_| Error::ParseTemplate("Rendered template is not valid UTF-8".to_string()))
+}
+
+/// Render a template and write the output to `writer` as it is produced.
+/// Text before each template operation is written before the operation runs,
+/// and components and loops are streamed as they are rendered.
+/// ```
+/// use unchained_web::templates::render::{render_to, RenderOptions};
+///
+/// let context = [("name".to_string(), "Carl".into())].into();
+/// let mut output = Vec::new();
+/// render_to("<p>{* get name *}</p>", Some(context), &RenderOptions::empty(), &mut output).unwrap();
+/// assert_eq!(output, b"<p>Carl</p>");
+/// ```
+pub fn render_to<W: Write + ?Sized>(
+    content: &str,
+    context: Option<ContextMap>,
+    options: &RenderOptions,
+    writer: &mut W,
+) -> WebResult<()> {
     let context = &context.unwrap_or_default();
-    let mut min_look_index = 0;
+    let options = options.started();
+    let Some(budget) = &options.state.budget else {
+        unreachable!("Started renders have a budget");
+    };
+    let mut limited = LimitedWriter {
+        inner: writer,
+        budget,
+        max: options.limits.max_output_size,
+        exceeded: false,
+    };
+    let result = render_content_to(content, context, &options, &mut limited);
+    if limited.exceeded {
+        return Err(Error::RenderLimit(format!(
+            "Output is larger than {} bytes",
+            limited.max.unwrap_or_default()
+        )));
+    }
+    result
+}
 
-    loop {
-        let result = template_operation(&content[min_look_index..]);
-        if result.is_none() {
-            break;
-        }
-        let mut result = result.unwrap();
-        result.push(min_look_index);
-        min_look_index = result.from;
-
-        if let Some(op_call) = operation_params_and_children(&result.content) {
-            if let Some(operation) =
-                get_template_operation(&op_call.name, options.custom_operations.clone())
-            {
-                let replacement = operation(op_call, context, options)?;
-                content.replace_range(result.from..=result.to, &replacement)
-            } else {
-                return Err(Error::ParseTemplate(format!(
-                    "No template operation specified for {}",
-                    op_call.name
-                )));
-            }
+/// Render a template to a writer that is already limited by an ongoing render
+pub(crate) fn render_content_to(
+    content: &str,
+    context: &ContextMap,
+    options: &RenderOptions,
+    writer: &mut dyn Write,
+) -> WebResult<()> {
+    let mut rest = content;
+    while let Some(result) = template_operation(rest) {
+        writer.write_all(&rest.as_bytes()[..result.from])?;
+        options.check_time()?;
+
+        let op_call = operation_params_and_children(&result.content).ok_or_else(|| {
+            Error::ParseTemplate("Could not create operation from content".to_string())
+        })?;
+        if op_call.name == "component" {
+            component_to(op_call, context, options, writer)?;
+        } else if op_call.name == "for" {
+            for_loop_to(op_call, context, options, writer)?;
+        } else if let Some(operation) =
+            get_template_operation(&op_call.name, options.custom_operations.clone())
+        {
+            // The output of an operation can contain new operations
+            let replacement = operation(op_call, context, options)?;
+            render_content_to(&replacement, context, options, writer)?;
         } else {
-            return Err(Error::ParseTemplate(
-                "Could not create operation from content".to_string(),
-            ));
+            return Err(Error::ParseTemplate(format!(
+                "No template operation specified for {}",
+                op_call.name
+            )));
         }
+        rest = &rest[result.to + 1..];
     }
-    Ok(content)
+    writer.write_all(rest.as_bytes())?;
+    Ok(())
+}
+
+fn read_template(file: &Path) -> WebResult<String> {
+    std::fs::read_to_string(file)
+        .map_err(|_| Error::LoadFile(format!("Could not read file {}", file.display())))
+}
+
+/// Render an included template to the writer of an ongoing render
+pub(crate) fn include_to(
+    path: &str,
+    context: &ContextMap,
+    options: &RenderOptions,
+    writer: &mut dyn Write,
+) -> WebResult<()> {
+    let (file, options) = options.enter_template(path)?;
+    render_content_to(&read_template(&file)?, context, &options, writer)
 }
 
 /// Render an html file from file
@@ -71,10 +376,231 @@ pub fn load_template(
     context: Option<ContextMap>,
     options: &RenderOptions,
 ) -> WebResult<String> {
-    let content = std::fs::read_to_string(path);
-    if content.is_err() {
-        return Err(Error::LoadFile(format!("Could not read file {}", path)));
+    let (file, options) = options.started().enter_template(path)?;
+    render_html(read_template(&file)?, context, &options)
+}
+
+/// Render an html file and stream the output to `writer`.
+/// See [render_to].
+pub fn load_template_to<W: Write + ?Sized>(
+    path: &str,
+    context: Option<ContextMap>,
+    options: &RenderOptions,
+    writer: &mut W,
+) -> WebResult<()> {
+    let (file, options) = options.started().enter_template(path)?;
+    render_to(&read_template(&file)?, context, &options, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write templates to a new temporary directory
+    fn template_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("unchained-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    fn options_with(limits: RenderLimits) -> RenderOptions<'static> {
+        let mut options = RenderOptions::empty();
+        options.limits = limits;
+        options
+    }
+
+    #[test]
+    fn test_component_including_itself() {
+        let dir = template_dir("recursion", &[]);
+        let path = dir.join("self.html");
+        let content = format!("<div>{{* component {} *}}</div>", path.display());
+        std::fs::write(&path, content).unwrap();
+
+        let options = options_with(RenderLimits::sandboxed(vec![dir]));
+        let res = load_template(path.to_str().unwrap(), None, &options);
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
+    }
+
+    #[test]
+    fn test_include_depth() {
+        let dir = template_dir("depth", &[("c.html", "c")]);
+        let include = |file: &str| format!("{{* component {} *}}", dir.join(file).display());
+        std::fs::write(dir.join("b.html"), include("c.html")).unwrap();
+        std::fs::write(dir.join("a.html"), include("b.html")).unwrap();
+        let a = dir.join("a.html");
+
+        let mut limits = RenderLimits {
+            max_include_depth: Some(2),
+            ..Default::default()
+        };
+        let res = load_template(a.to_str().unwrap(), None, &options_with(limits.clone()));
+        assert_eq!(res.unwrap(), "c");
+
+        limits.max_include_depth = Some(1);
+        let res = load_template(a.to_str().unwrap(), None, &options_with(limits));
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
+    }
+
+    #[test]
+    fn test_allowed_dirs() {
+        let allowed = template_dir("allowed", &[]);
+        let other = template_dir("not-allowed", &[("secret.html", "secret")]);
+        let template = format!("{{* component {} *}}", other.join("secret.html").display());
+
+        let options = options_with(RenderLimits::sandboxed(vec![allowed]));
+        let res = render_html(template, None, &options);
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
+    }
+
+    #[test]
+    fn test_output_and_loop_limits() {
+        let items: Vec<isize> = (0..100).collect();
+        let context: ContextMap = [("items".to_string(), items.into())].into();
+        let template = "{* for item in items { <p>{* get item *}</p> } *}".to_string();
+
+        let limits = RenderLimits {
+            max_loop_iterations: Some(50),
+            ..Default::default()
+        };
+        let res = render_html(
+            template.clone(),
+            Some(context.clone()),
+            &options_with(limits),
+        );
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
+
+        let limits = RenderLimits {
+            max_output_size: Some(100),
+            ..Default::default()
+        };
+        let res = render_html(
+            template.clone(),
+            Some(context.clone()),
+            &options_with(limits),
+        );
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
+
+        let res = render_html(template, Some(context), &RenderOptions::empty());
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_loops_are_streamed_within_the_output_limit() {
+        let items: Vec<isize> = (0..1000).collect();
+        let context: ContextMap = [("items".to_string(), items.into())].into();
+        let template = "{* for item in items { <p>{* get item *}</p> } *}";
+        let limits = RenderLimits {
+            max_output_size: Some(100),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let res = render_to(template, Some(context), &options_with(limits), &mut output);
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
+        // Iterations before the limit were written, the rest never rendered
+        assert!(output.starts_with(b" <p>0</p> ") && output.len() <= 100);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let items: Vec<isize> = (0..10).collect();
+        let context: ContextMap = [("items".to_string(), items.into())].into();
+        let template = "{* for item in items { {* get item *} } *}".to_string();
+
+        let limits = RenderLimits {
+            timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let res = render_html(template, Some(context), &options_with(limits));
+        assert!(matches!(res, Err(Error::RenderLimit(_))));
     }
 
-    render_html(content.unwrap(), context, options)
+    #[test]
+    fn test_paths_resolved_from_root_and_including_template() {
+        let root = template_dir("root", &[("base.html", "<main>{* slot default *}</main>")]);
+        std::fs::create_dir_all(root.join("pages")).unwrap();
+        std::fs::write(root.join("pages/button.html"), "<button></button>").unwrap();
+        std::fs::write(
+            root.join("pages/index.html"),
+            "{* component ./button.html *}{* component base.html { text } *}",
+        )
+        .unwrap();
+
+        let options = RenderOptions::with_root(&root);
+        let res = load_template("pages/index.html", None, &options);
+        assert_eq!(res.unwrap(), "<button></button><main> text </main>");
+    }
+
+    #[test]
+    fn test_paths_escaping_root_are_rejected() {
+        let root = template_dir("escape-root", &[]);
+        template_dir("escape-outside", &[("secret.html", "secret")]);
+        let options = RenderOptions::with_root(&root);
+
+        let outside = format!(
+            "../unchained-escape-outside-{}/secret.html",
+            std::process::id()
+        );
+        let template = format!("{{* component {} *}}", outside);
+        assert!(render_html(template, None, &options).is_err());
+        assert!(load_template(&outside, None, &options).is_err());
+
+        let template = "{* component ../../../../../../etc/passwd *}".to_string();
+        assert!(render_html(template, None, &options).is_err());
+    }
+
+    #[test]
+    fn test_invalid_component_parameters() {
+        let root = template_dir("parameters", &[("f.html", "{* get x *}")]);
+        let options = RenderOptions::with_root(&root);
+        let context: ContextMap = [("a".to_string(), "value".into())].into();
+        let render =
+            |template: &str| render_html(template.to_string(), Some(context.clone()), &options);
+        assert_eq!(render("{* component f.html x=a *}").unwrap(), "value");
+        assert!(render("{* component f.html x=missing *}").is_err());
+        assert!(render("{* component f.html x *}").is_err());
+        assert!(render("{* component f.html x=a=b *}").is_err());
+        assert!(render("{* component f.html =a *}").is_err());
+    }
+
+    #[test]
+    fn test_allowed_extensions() {
+        let root = template_dir("extensions", &[("data.txt", "text"), ("a.html", "html")]);
+        let mut options = RenderOptions::with_root(&root);
+        let template = "{* component data.txt *}".to_string();
+        assert!(render_html(template.clone(), None, &options).is_err());
+
+        options.allowed_extensions.push("txt".to_string());
+        assert_eq!(render_html(template, None, &options).unwrap(), "text");
+    }
+
+    #[test]
+    fn test_url_operation() {
+        let mut urls = Urls::new();
+        urls.add("home", "/")
+            .and_then(|urls| urls.add("course", "/courses/:courseid"))
+            .unwrap();
+        assert!(urls.add("home", "/home").is_err());
+        let mut options = RenderOptions::empty();
+        let context = HashMap::from([("course".to_string(), [("id", "CS4505")].into())]);
+        let render = |template: &str, options: &RenderOptions| {
+            render_html(template.to_string(), Some(context.clone()), options)
+        };
+        assert!(render("{* url home *}", &options).is_err());
+
+        options.urls = Some(Arc::new(urls));
+        assert_eq!(
+            render(
+                "<a href=\"{* url course courseid=course.id *}#top\">",
+                &options
+            )
+            .unwrap(),
+            "<a href=\"/courses/CS4505#top\">"
+        );
+        assert_eq!(render("{* url home *}", &options).unwrap(), "/");
+        assert!(render("{* url course *}", &options).is_err());
+        assert!(render("{* url missing *}", &options).is_err());
+    }
 }
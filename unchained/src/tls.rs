@@ -0,0 +1,436 @@
+//! HTTPS with [rustls](https://docs.rs/rustls), enabled with the `tls` cargo feature.
+//!
+//! Certificates are loaded from PEM files, chosen by the server name the client sends (SNI),
+//! and loaded again when the files change, so renewed certificates are used without a restart.
+//!
+//! ```no_run
+//! use unchained_web::{
+//!     router::{HTTPVerb::*, ResponseContent, Route},
+//!     server::Server,
+//!     tls::{CertificateFiles, TlsOptions},
+//! };
+//!
+//! let mut tls = TlsOptions::new(CertificateFiles::new("certs/example.pem", "certs/example.key"));
+//! tls.add_certificate(
+//!     "blog.example.com",
+//!     CertificateFiles::new("certs/blog.pem", "certs/blog.key"),
+//! );
+//! let routes = vec![Route::new(GET, "/", ResponseContent::Str("Secure".to_string()))];
+//! let mut server = Server::new(routes);
+//! server
+//!     .set_address("0.0.0.0:443")
+//!     .set_tls(tls)
+//!     .redirect_http_from("0.0.0.0:80");
+//! server.listen().unwrap();
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::TcpStream,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::{
+    error::{Error, WebResult},
+    logging::{Level, Logger},
+};
+
+/// A connection encrypted with TLS
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// A certificate chain and its private key in PEM files
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificateFiles {
+    /// Certificate of the server followed by the intermediate certificates
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+}
+
+impl CertificateFiles {
+    pub fn new(certificate: impl Into<PathBuf>, private_key: impl Into<PathBuf>) -> Self {
+        CertificateFiles {
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+        }
+    }
+
+    /// Modification time of the newest of the two files
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.certificate).max(modified(&self.private_key))
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> WebResult<CertifiedKey> {
+        let read = |path: &PathBuf| {
+            fs::read(path)
+                .map_err(|e| Error::LoadFile(format!("Could not read {}. {}", path.display(), e)))
+        };
+        let invalid = |path: &PathBuf, e: &dyn std::fmt::Debug| {
+            Error::LoadFile(format!("Invalid PEM file {}. {:?}", path.display(), e))
+        };
+        let pem = read(&self.certificate)?;
+        let chain = CertificateDer::pem_slice_iter(&pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(&self.certificate, &e))?;
+        if chain.is_empty() {
+            return Err(invalid(&self.certificate, &"No certificates"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(&read(&self.private_key)?)
+            .map_err(|e| invalid(&self.private_key, &e))?;
+        CertifiedKey::from_der(chain, key, provider).map_err(|e| {
+            Error::LoadFile(format!(
+                "Certificate {} does not match its key. {:?}",
+                self.certificate.display(),
+                e
+            ))
+        })
+    }
+}
+
+/// Certificates of an HTTPS server
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// Certificate for clients that do not send a server name or one without a certificate
+    pub default: CertificateFiles,
+    /// Certificates by server name, e.g. `blog.example.com`
+    pub certificates: HashMap<String, CertificateFiles>,
+    /// How often the files are checked for changes. `None` loads them only at startup.
+    pub reload_interval: Option<Duration>,
+}
+
+impl TlsOptions {
+    pub fn new(default: CertificateFiles) -> Self {
+        TlsOptions {
+            default,
+            certificates: HashMap::new(),
+            reload_interval: Some(Duration::from_secs(60)),
+        }
+    }
+
+    /// Use a certificate for a server name sent by the client
+    pub fn add_certificate(&mut self, server_name: &str, files: CertificateFiles) -> &mut Self {
+        self.certificates
+            .insert(server_name.to_ascii_lowercase(), files);
+        self
+    }
+
+    /// Configuration of TLS connections, offering the ALPN protocols in order of preference.
+    /// Certificates that fail to reload are reported to the logger.
+    pub fn server_config(
+        &self,
+        alpn_protocols: &[&str],
+        logger: Arc<dyn Logger>,
+    ) -> WebResult<Arc<ServerConfig>> {
+        let provider = Arc::new(ring::default_provider());
+        let resolver = Certificates::load(self.clone(), provider.clone(), logger)?;
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::InvalidParams(format!("Invalid TLS versions. {:?}", e)))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect();
+        Ok(Arc::new(config))
+    }
+}
+
+/// Loaded certificate and the modification time of its files
+#[derive(Debug)]
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+/// Chooses the certificate for a connection by server name
+/// and reloads certificates whose files changed
+struct Certificates {
+    options: TlsOptions,
+    provider: Arc<CryptoProvider>,
+    default: RwLock<Loaded>,
+    by_name: RwLock<HashMap<String, Loaded>>,
+    last_check: Mutex<Instant>,
+    logger: Arc<dyn Logger>,
+}
+
+impl std::fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Certificates")
+            .field("options", &self.options)
+            .field("default", &self.default)
+            .field("by_name", &self.by_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Certificates {
+    fn load(
+        options: TlsOptions,
+        provider: Arc<CryptoProvider>,
+        logger: Arc<dyn Logger>,
+    ) -> WebResult<Certificates> {
+        let load = |files: &CertificateFiles| -> WebResult<Loaded> {
+            Ok(Loaded {
+                key: Arc::new(files.load(&provider)?),
+                modified: files.modified(),
+            })
+        };
+        let default = load(&options.default)?;
+        let mut by_name = HashMap::new();
+        for (name, files) in options.certificates.iter() {
+            by_name.insert(name.clone(), load(files)?);
+        }
+        Ok(Certificates {
+            default: RwLock::new(default),
+            by_name: RwLock::new(by_name),
+            last_check: Mutex::new(Instant::now()),
+            provider,
+            options,
+            logger,
+        })
+    }
+
+    /// Load certificates with changed files again if the reload interval passed.
+    /// Certificates that fail to load are kept as they were.
+    fn reload_changed(&self) {
+        let Some(interval) = self.options.reload_interval else {
+            return;
+        };
+        {
+            let mut last_check = self.last_check.lock().unwrap();
+            if last_check.elapsed() < interval {
+                return;
+            }
+            *last_check = Instant::now();
+        }
+        let reload = |files: &CertificateFiles, loaded: &mut Loaded| {
+            let modified = files.modified();
+            if modified == loaded.modified {
+                return;
+            }
+            match files.load(&self.provider) {
+                Ok(key) => {
+                    loaded.key = Arc::new(key);
+                    loaded.modified = modified;
+                }
+                Err(e) => self.logger.log(
+                    Level::Warn,
+                    format_args!("Keeping the previous certificate. {:?}", e),
+                ),
+            }
+        };
+        reload(&self.options.default, &mut self.default.write().unwrap());
+        let mut by_name = self.by_name.write().unwrap();
+        for (name, files) in self.options.certificates.iter() {
+            if let Some(loaded) = by_name.get_mut(name) {
+                reload(files, loaded);
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.reload_changed();
+        let named = client_hello.server_name().and_then(|name| {
+            let by_name = self.by_name.read().unwrap();
+            by_name
+                .get(&name.to_ascii_lowercase())
+                .map(|loaded| loaded.key.clone())
+        });
+        named.or_else(|| Some(self.default.read().unwrap().key.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        path::Path,
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+
+    use super::*;
+    use crate::{
+        router::{HTTPVerb, ResponseContent, Route},
+        server::Server,
+    };
+
+    /// Certificate authority signing the certificates of the tests
+    struct Authority {
+        certificate: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new() -> Authority {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let certificate = params.self_signed(&key).unwrap();
+            Authority { certificate, key }
+        }
+
+        /// Write a certificate for the name to `dir`, returning it in DER
+        fn issue(&self, name: &str, dir: &Path) -> (CertificateFiles, Vec<u8>) {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &self.certificate, &self.key)
+                .unwrap();
+            let files = CertificateFiles::new(
+                dir.join(format!("{}.pem", name)),
+                dir.join(format!("{}.key", name)),
+            );
+            fs::write(&files.certificate, certificate.pem()).unwrap();
+            fs::write(&files.private_key, key.serialize_pem()).unwrap();
+            (files, certificate.der().to_vec())
+        }
+
+        fn client(&self, alpn: &[&str]) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.certificate.der().clone()).unwrap();
+            let mut config =
+                ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .unwrap()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+            config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+            Arc::new(config)
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("unchained-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Send a request over TLS, returning the response and the certificate of the server
+    fn https_get(
+        config: Arc<ClientConfig>,
+        address: std::net::SocketAddr,
+        server_name: &str,
+    ) -> (String, Vec<u8>) {
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(config, name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            server_name
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let certificate = stream.conn.peer_certificates().unwrap()[0].to_vec();
+        (String::from_utf8(response).unwrap(), certificate)
+    }
+
+    #[test]
+    fn test_https_and_sni() {
+        let dir = temp_dir("tls");
+        let authority = Authority::new();
+        let (localhost, localhost_der) = authority.issue("localhost", &dir);
+        let (blog, blog_der) = authority.issue("blog.test", &dir);
+        let mut tls = TlsOptions::new(localhost.clone());
+        tls.add_certificate("Blog.test", blog);
+        tls.reload_interval = Some(Duration::ZERO);
+
+        let routes = vec![Route::new(
+            HTTPVerb::GET,
+            "/",
+            ResponseContent::Str("secure".to_string()),
+        )];
+        let mut server = Server::new(routes);
+        server.set_tls(tls).set_address("127.0.0.1:0");
+        let handle = server.start().unwrap();
+        let address = handle.address().unwrap();
+
+        let client = authority.client(&[]);
+        let (response, certificate) = https_get(client.clone(), address, "localhost");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("secure"));
+        assert_eq!(certificate, localhost_der);
+        let (_, certificate) = https_get(client, address, "blog.test");
+        assert_eq!(certificate, blog_der);
+
+        // HTTP/2 is negotiated with ALPN
+        #[cfg(feature = "http2")]
+        {
+            let name = ServerName::try_from("localhost").unwrap();
+            let client = authority.client(&["h2", "http/1.1"]);
+            let connection = ClientConnection::new(client, name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+            stream
+                .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+                .unwrap();
+            let mut frame_header = [0; 9];
+            stream.read_exact(&mut frame_header).unwrap();
+            assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+            // The first frame is the server's SETTINGS
+            assert_eq!(frame_header[3], 0x4);
+        }
+
+        // A renewed certificate is used without a restart
+        std::thread::sleep(Duration::from_millis(20));
+        let (_, renewed_der) = authority.issue("localhost", &dir);
+        assert_ne!(renewed_der, localhost_der);
+        // A new client, so the session is not resumed with the old certificate
+        let (_, certificate) = https_get(authority.client(&[]), address, "localhost");
+        assert_eq!(certificate, renewed_der);
+
+        // The HTTPS listener stops when its redirect can not listen
+        let free = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut failing = Server::new(vec![]);
+        failing
+            .set_tls(TlsOptions::new(localhost))
+            .set_address(&free.to_string())
+            .redirect_http_from(&address.to_string());
+        assert!(failing.start().is_err());
+        assert!(TcpStream::connect(free).is_err());
+    }
+
+    #[test]
+    fn test_invalid_certificates() {
+        let dir = temp_dir("tls-invalid");
+        let authority = Authority::new();
+        let (files, _) = authority.issue("localhost", &dir);
+        let (other, _) = authority.issue("other", &dir);
+        let logger: Arc<dyn Logger> = Arc::new(crate::logging::ConsoleLogger::default());
+        assert!(TlsOptions::new(files.clone())
+            .server_config(&[], logger.clone())
+            .is_ok());
+        let missing = CertificateFiles::new(dir.join("missing.pem"), &files.private_key);
+        assert!(TlsOptions::new(missing)
+            .server_config(&[], logger.clone())
+            .is_err());
+        let mismatched = CertificateFiles::new(&files.certificate, &other.private_key);
+        assert!(TlsOptions::new(mismatched)
+            .server_config(&[], logger.clone())
+            .is_err());
+        let not_pem = CertificateFiles::new(&files.private_key, &files.private_key);
+        assert!(TlsOptions::new(not_pem)
+            .server_config(&[], logger.clone())
+            .is_err());
+    }
+}
@@ -5,6 +5,8 @@ use std::{
     thread,
 };
 
+use crate::logging::{ConsoleLogger, Level, Logger};
+
 /// A thread pool of workers that can execute tasks.
 /// Use `Workers::new(amount)` to create a new group
 /// of workers. Add new tasks with
@@ -15,8 +17,17 @@ where
 {
     amount: u32,
     threads: Vec<thread::JoinHandle<()>>,
-    condition_variable: Arc<(Mutex<bool>, Condvar)>,
-    tasks: Arc<Mutex<VecDeque<T>>>,
+    condition_variable: Arc<Condvar>,
+    tasks: Arc<Mutex<Tasks<T>>>,
+    /// Receives the errors returned by tasks
+    logger: Arc<dyn Logger>,
+}
+
+/// Tasks waiting for a worker
+struct Tasks<T> {
+    queue: VecDeque<T>,
+    /// Set when the workers are dropped, so the threads stop once the queue is empty
+    closed: bool,
 }
 
 impl<T, U> Workers<T, U>
@@ -34,13 +45,24 @@ where
     ///    println!("Hello from a worker!");
     ///    Ok::<(), String>(())
     /// });
+    /// // Dropping the workers waits for the tasks to finish
+    /// drop(workers);
     /// ```
     pub fn new(amount: u32) -> Self {
+        Workers::with_logger(amount, Arc::new(ConsoleLogger::default()))
+    }
+
+    /// Create a new group of workers logging errors of tasks to the logger
+    pub fn with_logger(amount: u32, logger: Arc<dyn Logger>) -> Self {
         let mut workers = Workers {
             amount,
             threads: Vec::new(),
-            condition_variable: Arc::new((Mutex::new(false), Condvar::new())),
-            tasks: Arc::new(Mutex::new(VecDeque::new())),
+            condition_variable: Arc::new(Condvar::new()),
+            tasks: Arc::new(Mutex::new(Tasks {
+                queue: VecDeque::new(),
+                closed: false,
+            })),
+            logger,
         };
         workers.wait_for_tasks();
         workers
@@ -48,36 +70,30 @@ where
 
     /// Add a new task to the worker pool.
     pub fn post(&self, task: T) {
-        self.tasks.lock().unwrap().push_back(task);
-        let (lock, cvar) = &*self.condition_variable;
-        *lock.lock().unwrap() = true;
-        cvar.notify_all();
+        self.tasks.lock().unwrap().queue.push_back(task);
+        self.condition_variable.notify_one();
     }
 
     pub fn start_thread(&mut self) {
         let tasks = self.tasks.clone();
         let condvar = self.condition_variable.clone();
+        let logger = self.logger.clone();
         self.threads.push(thread::spawn(move || loop {
-            let (lock, cvar) = &*condvar;
-            {
-                let mut ready = lock.lock().unwrap();
-                while !*ready {
-                    ready = cvar.wait(ready).unwrap();
-                }
-            }
-            #[allow(unused_assignments)] // We want the tasks lock out of scope
-            let mut task: Option<T> = None;
-            {
+            let task = {
                 let mut tasks = tasks.lock().unwrap();
-                task = tasks.pop_front();
-            }
-            if let Some(func) = task {
-                let result = func();
-                if let Err(e) = result {
-                    eprintln!("Error in worker: {:?}\nContinuing work ...\n", e);
+                loop {
+                    if let Some(task) = tasks.queue.pop_front() {
+                        break task;
+                    }
+                    if tasks.closed {
+                        return;
+                    }
+                    tasks = condvar.wait(tasks).unwrap();
                 }
+            };
+            if let Err(e) = task() {
+                logger.log(Level::Error, format_args!("Error in worker: {:?}", e));
             }
-            *lock.lock().unwrap() = false
         }));
     }
 
@@ -88,3 +104,47 @@ where
         }
     }
 }
+
+/// Finishes the posted tasks and joins the threads
+impl<T, U> Drop for Workers<T, U>
+where
+    T: FnOnce() -> Result<(), U>,
+{
+    fn drop(&mut self) {
+        self.tasks.lock().unwrap().closed = true;
+        self.condition_variable.notify_all();
+        for thread in self.threads.drain(..) {
+            // A panicking task already printed its message
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_drop_finishes_tasks() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let workers = Workers::new(3);
+        for i in 0..11 {
+            let finished = finished.clone();
+            workers.post(move || {
+                if i == 10 {
+                    return Err("failed");
+                }
+                thread::sleep(Duration::from_millis(10));
+                finished.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+        }
+        drop(workers);
+        assert_eq!(finished.load(Ordering::SeqCst), 10);
+    }
+}